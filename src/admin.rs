@@ -0,0 +1,193 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use matrix_sdk::{
+    reqwest::{self, header::CONTENT_TYPE, Url},
+    ruma::{OwnedRoomId, RoomId, ServerName, UserId},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::http::endpoint;
+
+#[derive(Deserialize)]
+struct AdminError {
+    errcode: Option<String>,
+    error: Option<String>,
+}
+
+/// A file a user uploaded, as the admin API lists it
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct UserMedia {
+    pub media_id: String,
+    pub media_type: Option<String>,
+    pub media_length: Option<u64>,
+    pub upload_name: Option<String>,
+    pub created_ts: u64,
+}
+
+/// Client for the Synapse admin API of a homeserver, authenticated with the token of a
+/// server admin.
+pub struct SynapseAdmin {
+    http: reqwest::Client,
+    homeserver: Url,
+    token: String,
+}
+
+impl SynapseAdmin {
+    pub fn new(http: reqwest::Client, homeserver: Url, token: String) -> Self {
+        Self {
+            http,
+            homeserver,
+            token,
+        }
+    }
+
+    pub fn homeserver(&self) -> &Url {
+        &self.homeserver
+    }
+
+    /// URL of the admin API endpoint made of `segments`, each of them percent-encoded
+    fn url(&self, segments: &[&str]) -> anyhow::Result<Url> {
+        endpoint(
+            &self.homeserver,
+            &[&["_synapse", "admin"], segments].concat(),
+        )
+    }
+
+    async fn send(&self, request: reqwest::RequestBuilder) -> anyhow::Result<Value> {
+        let response = request.bearer_auth(&self.token).send().await?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response.json().await?);
+        }
+
+        match response.json::<AdminError>().await {
+            Ok(AdminError {
+                errcode,
+                error: Some(error),
+            }) => anyhow::bail!("{status} {}: {error}", errcode.unwrap_or_default()),
+            _ => anyhow::bail!("{status}"),
+        }
+    }
+
+    /// Logs in as the local `user_id` without its password and returns the access token, which
+    /// expires after `valid_for`. The token isn't bound to a device, see [`Self::logout`] to
+    /// revoke it earlier.
+    pub async fn login_as(&self, user_id: &UserId, valid_for: Duration) -> anyhow::Result<String> {
+        let url = self.url(&["v1", "users", user_id.as_str(), "login"])?;
+        let valid_until = SystemTime::now() + valid_for;
+        let valid_until_ms = valid_until.duration_since(UNIX_EPOCH)?.as_millis() as u64;
+        let response = self
+            .send(
+                self.http
+                    .post(url)
+                    .json(&json!({ "valid_until_ms": valid_until_ms })),
+            )
+            .await?;
+        match response["access_token"].as_str() {
+            Some(token) => Ok(token.to_owned()),
+            None => anyhow::bail!("The homeserver didn't return an access token"),
+        }
+    }
+
+    /// Revokes the access token `token` of a local user, e.g. one of [`Self::login_as`]
+    pub async fn logout(&self, token: &str) -> anyhow::Result<()> {
+        let url = endpoint(&self.homeserver, &["_matrix", "client", "v3", "logout"])?;
+        self.http
+            .post(url)
+            .bearer_auth(token)
+            .json(&json!({}))
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// Joins the local `user_id` to `room_id` without an invite. Works for rooms the user could
+    /// join on its own and for rooms where a local admin can invite it.
+    pub async fn join(&self, room_id: &RoomId, user_id: &UserId) -> anyhow::Result<()> {
+        let url = self.url(&["v1", "join", room_id.as_str()])?;
+        self.send(self.http.post(url).json(&json!({ "user_id": user_id })))
+            .await?;
+        Ok(())
+    }
+
+    /// The rooms the local `user_id` is joined to, without syncing its account
+    pub async fn joined_rooms(&self, user_id: &UserId) -> anyhow::Result<Vec<OwnedRoomId>> {
+        let url = self.url(&["v1", "users", user_id.as_str(), "joined_rooms"])?;
+        let response = self.send(self.http.get(url)).await?;
+        Ok(serde_json::from_value(response["joined_rooms"].clone())?)
+    }
+
+    /// Gives the local `user_id` the highest power level in `room_id`, through a local member
+    /// of the room that is its admin
+    pub async fn make_room_admin(&self, room_id: &RoomId, user_id: &UserId) -> anyhow::Result<()> {
+        let url = self.url(&["v1", "rooms", room_id.as_str(), "make_room_admin"])?;
+        self.send(self.http.post(url).json(&json!({ "user_id": user_id })))
+            .await?;
+        Ok(())
+    }
+
+    /// Deactivates the local `user_id`, removing it from all its rooms, and with `erase` also
+    /// hides its messages from users joining later on
+    pub async fn deactivate(&self, user_id: &UserId, erase: bool) -> anyhow::Result<()> {
+        let url = self.url(&["v1", "deactivate", user_id.as_str()])?;
+        self.send(self.http.post(url).json(&json!({ "erase": erase })))
+            .await?;
+        Ok(())
+    }
+
+    /// The files the local `user_id` uploaded, newest first
+    pub async fn user_media(&self, user_id: &UserId) -> anyhow::Result<Vec<UserMedia>> {
+        let mut media = Vec::new();
+        let mut from = 0;
+        loop {
+            let mut url = self.url(&["v1", "users", user_id.as_str(), "media"])?;
+            url.query_pairs_mut()
+                .append_pair("from", &from.to_string())
+                .append_pair("limit", "100");
+            let response = self.send(self.http.get(url)).await?;
+            media.extend(serde_json::from_value::<Vec<UserMedia>>(
+                response["media"].clone(),
+            )?);
+            match response["next_token"].as_u64() {
+                Some(next) => from = next,
+                None => return Ok(media),
+            }
+        }
+    }
+
+    /// Downloads the file `media_id` of `server` with the token of the admin. Returns the file
+    /// and its content type.
+    pub async fn download(
+        &self,
+        server: &ServerName,
+        media_id: &str,
+    ) -> anyhow::Result<(Vec<u8>, Option<String>)> {
+        let url = endpoint(
+            &self.homeserver,
+            &[
+                "_matrix",
+                "client",
+                "v1",
+                "media",
+                "download",
+                server.as_str(),
+                media_id,
+            ],
+        )?;
+        let response = self
+            .http
+            .get(url)
+            .bearer_auth(&self.token)
+            .send()
+            .await?
+            .error_for_status()?;
+        let content_type = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(ToOwned::to_owned);
+        Ok((response.bytes().await?.to_vec(), content_type))
+    }
+}
@@ -0,0 +1,253 @@
+use std::process::ExitCode;
+
+use matrix_sdk::{
+    ruma::{
+        api::client::state::get_state_events_for_key,
+        events::{
+            room::{
+                member::{MembershipState, RoomMemberEventContent},
+                power_levels::RoomPowerLevelsEventContent,
+            },
+            MessageLikeEventType, StateEventType,
+        },
+        OwnedRoomId, RoomId,
+    },
+    Client, Room, RoomState,
+};
+use tracing::{info, warn};
+
+use crate::{
+    plan::Plan,
+    report::{self, EXIT_FAILED},
+    sync,
+};
+
+/// Outcome of checking the migration of a single room
+pub struct RoomAudit {
+    pub room_id: OwnedRoomId,
+    pub name: Option<String>,
+    /// Everything not matching the old account, empty if the room passed
+    pub problems: Vec<String>,
+}
+
+impl RoomAudit {
+    pub fn passed(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Checks for every room of `rooms` that the new account is joined with at least the power level
+/// the old account has, or the plan asks for.
+pub async fn audit_rooms(
+    from_c: &Client,
+    to_c: &Client,
+    rooms: &[OwnedRoomId],
+    plan: &Plan,
+) -> anyhow::Result<Vec<RoomAudit>> {
+    let old_user = from_c.user_id().unwrap().to_owned();
+    let new_user = to_c.user_id().unwrap().to_owned();
+    let mut audits = Vec::new();
+
+    for room_id in rooms {
+        let name = match from_c.get_room(room_id) {
+            Some(room) => room.display_name().await.ok().map(|n| n.to_string()),
+            None => None,
+        };
+        let mut problems = Vec::new();
+
+        match to_c.get_room(room_id) {
+            None => problems.push(format!("{new_user} isn't member")),
+            Some(joined) if joined.state() != RoomState::Joined => {
+                problems.push(format!("{new_user} hasn't joined"))
+            }
+            Some(joined) => match (
+                sync::member(&joined, &old_user).await?,
+                sync::member(&joined, &new_user).await?,
+            ) {
+                (Some(me), Some(new_acc)) => {
+                    let target = plan.target_power_level(room_id, me.power_level());
+                    if target > new_acc.power_level() {
+                        problems.push(format!(
+                            "{new_user} has power level {} instead of {target}",
+                            new_acc.power_level()
+                        ));
+                    }
+                    let gaps = ability_gaps(&joined, target, new_acc.power_level()).await?;
+                    if !gaps.is_empty() {
+                        problems.push(format!("{new_user} can't {}", gaps.join(", ")));
+                    }
+                }
+                _ => problems.push(format!(
+                    "couldn't compare power levels of {old_user} and {new_user}"
+                )),
+            },
+        }
+
+        for problem in &problems {
+            warn!("{room_id}: {problem}.");
+        }
+        audits.push(RoomAudit {
+            room_id: room_id.to_owned(),
+            name,
+            problems,
+        });
+    }
+
+    Ok(audits)
+}
+
+/// Everything the power levels of `room` restrict, with the level needed for it
+async fn abilities(room: &Room) -> anyhow::Result<Vec<(String, i64)>> {
+    let Some(power_levels) = room
+        .get_state_event_static::<RoomPowerLevelsEventContent>()
+        .await?
+    else {
+        return Ok(Vec::new());
+    };
+    let power_levels = power_levels.deserialize()?.power_levels();
+    let mut abilities = vec![
+        ("invite".to_owned(), power_levels.invite.into()),
+        ("kick".to_owned(), power_levels.kick.into()),
+        ("ban".to_owned(), power_levels.ban.into()),
+        ("redact".to_owned(), power_levels.redact.into()),
+        (
+            "notify @room".to_owned(),
+            power_levels.notifications.room.into(),
+        ),
+        (
+            "send other state events".to_owned(),
+            power_levels.state_default.into(),
+        ),
+        (
+            "send other events".to_owned(),
+            power_levels.events_default.into(),
+        ),
+    ];
+    abilities.extend(
+        power_levels
+            .events
+            .iter()
+            .map(|(event_type, level)| (format!("send {event_type}"), (*level).into())),
+    );
+    Ok(abilities)
+}
+
+/// What a member of `room` with power level `power_level` can't do that one with `expected`
+/// can, e.g. sending state events the `events` of the power levels need a higher level for
+pub async fn ability_gaps(
+    room: &Room,
+    expected: i64,
+    power_level: i64,
+) -> anyhow::Result<Vec<String>> {
+    Ok(abilities(room)
+        .await?
+        .into_iter()
+        .filter(|(_, needed)| expected >= *needed && power_level < *needed)
+        .map(|(ability, needed)| format!("{ability} (needs {needed})"))
+        .collect())
+}
+
+/// Checks that the new account can actually use `room_id` before the old account leaves it:
+/// the old homeserver sees it joined, it can read the state of the room, and in encrypted rooms
+/// its device is set up to send messages. Returns what's missing, if anything.
+pub async fn unusable(
+    from_c: &Client,
+    to_c: &Client,
+    room_id: &RoomId,
+) -> anyhow::Result<Option<String>> {
+    let new_user = to_c.user_id().unwrap();
+    let Some(joined) = to_c.get_room(room_id) else {
+        return Ok(Some(format!("{new_user} isn't member")));
+    };
+
+    // the join has to have reached the old homeserver, or the room is lost once it's left
+    let request = get_state_events_for_key::v3::Request::new(
+        room_id.to_owned(),
+        StateEventType::RoomMember,
+        new_user.to_string(),
+    );
+    let federated = match from_c.send(request, None).await {
+        Ok(response) => response
+            .content
+            .deserialize_as::<RoomMemberEventContent>()
+            .is_ok_and(|m| m.membership == MembershipState::Join),
+        Err(_) => false,
+    };
+    if !federated {
+        return Ok(Some(format!(
+            "the old homeserver doesn't see {new_user} joined yet"
+        )));
+    }
+
+    for event_type in [StateEventType::RoomCreate, StateEventType::RoomPowerLevels] {
+        let request = get_state_events_for_key::v3::Request::new(
+            room_id.to_owned(),
+            event_type.clone(),
+            "".to_owned(),
+        );
+        if let Err(e) = to_c.send(request, None).await {
+            return Ok(Some(format!("{new_user} can't read {event_type}: {e}")));
+        }
+    }
+
+    if joined.is_encrypted().await? {
+        if !joined
+            .can_user_send_message(new_user, MessageLikeEventType::RoomEncrypted)
+            .await?
+        {
+            return Ok(Some(format!(
+                "{new_user} isn't allowed to send encrypted messages"
+            )));
+        }
+        if to_c.encryption().get_own_device().await?.is_none() {
+            return Ok(Some(format!(
+                "the device of {new_user} has no encryption keys to send messages with"
+            )));
+        }
+    }
+
+    Ok(None)
+}
+
+/// The audit as a table with one line per room
+pub fn audit_table(audits: &[RoomAudit]) -> String {
+    let rows = audits
+        .iter()
+        .map(|a| {
+            let name = a.name.clone().unwrap_or_default();
+            let result = if a.passed() {
+                "pass".to_owned()
+            } else {
+                format!("FAIL: {}", a.problems.join("; "))
+            };
+            (report::truncate(&name, 40), a.room_id.to_string(), result)
+        })
+        .collect::<Vec<_>>();
+
+    let name_width = rows.iter().map(|r| r.0.chars().count()).max().unwrap_or(0);
+    let id_width = rows.iter().map(|r| r.1.len()).max().unwrap_or(0);
+    let name_width = name_width.max("Room".len());
+    let id_width = id_width.max("ID".len());
+
+    let mut table = format!("{:name_width$}  {:id_width$}  Audit\n", "Room", "ID");
+    table += &format!("{}\n", "-".repeat(name_width + id_width + 11));
+    for (name, id, result) in rows {
+        table += &format!("{name:name_width$}  {id:id_width$}  {result}\n");
+    }
+    table
+}
+
+/// Logs how many rooms passed and returns the exit code of the audit
+pub fn log_summary(audits: &[RoomAudit]) -> ExitCode {
+    let failed = audits.iter().filter(|a| !a.passed()).count();
+    if failed == 0 {
+        info!("--- Audit passed for all {} rooms", audits.len());
+        ExitCode::SUCCESS
+    } else {
+        warn!(
+            "--- Audit failed for {failed} of {} rooms. See above for the reasons why",
+            audits.len()
+        );
+        ExitCode::from(EXIT_FAILED)
+    }
+}
@@ -0,0 +1,550 @@
+use std::{
+    io::IsTerminal,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use dialoguer::Password;
+use matrix_sdk::{
+    config::RequestConfig,
+    matrix_auth::{MatrixSession, MatrixSessionTokens},
+    oidc::{
+        types::{
+            client_credentials::ClientCredentials,
+            iana::oauth::OAuthClientAuthenticationMethod,
+            oidc::ApplicationType,
+            registration::{ClientMetadata, Localized},
+            requests::GrantType,
+        },
+        AuthorizationResponse, OidcSession, UserSession,
+    },
+    reqwest::Url,
+    ruma::{DeviceId, OwnedDeviceId, OwnedServerName, OwnedUserId},
+    AuthApi, Client, SessionChange, SessionMeta,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+use tracing::{info, warn};
+
+use crate::http::endpoint;
+
+/// How to log in one of the two accounts
+#[derive(Debug, Clone)]
+pub struct Login {
+    pub homeserver: Option<OwnedServerName>,
+    pub user: Option<OwnedUserId>,
+    pub password: Option<String>,
+    pub password_file: Option<PathBuf>,
+    pub token: Option<String>,
+    /// Appservice token to log in as `user` with
+    pub as_token: Option<String>,
+    /// Session exported from a logged-in client, used like an access token
+    pub session_file: Option<PathBuf>,
+    pub sso: bool,
+    pub oidc: bool,
+    /// HTTP or SOCKS proxy to reach the homeserver through
+    pub proxy: Option<String>,
+    /// PEM files with additional certificate authorities to trust
+    pub ca_certs: Vec<PathBuf>,
+    /// Accept any certificate, e.g. self-signed ones during testing
+    pub insecure_tls: bool,
+    /// Display name of the device created when logging in
+    pub device_name: String,
+    /// Directory of the encrypted store keeping the session between runs
+    pub store: Option<PathBuf>,
+    pub passphrase: Option<String>,
+    /// Keep the session at the end instead of logging out
+    pub keep_session: bool,
+    /// How long to wait for a response before giving up on a request
+    pub http_timeout: Duration,
+}
+
+#[derive(Deserialize)]
+struct WhoAmI {
+    user_id: OwnedUserId,
+    device_id: Option<OwnedDeviceId>,
+}
+
+#[derive(Deserialize)]
+struct LoginResponse {
+    user_id: OwnedUserId,
+    device_id: OwnedDeviceId,
+    access_token: String,
+}
+
+/// A session copied out of a logged-in client. Accepts both plain field names and the keys
+/// Element keeps in its local storage.
+#[derive(Deserialize)]
+struct ExportedSession {
+    #[serde(alias = "mx_hs_url")]
+    homeserver: Option<Url>,
+    #[serde(alias = "mx_user_id")]
+    user_id: OwnedUserId,
+    #[serde(alias = "mx_device_id")]
+    device_id: OwnedDeviceId,
+    #[serde(alias = "mx_access_token")]
+    access_token: String,
+}
+
+/// Key of the session in the store's custom values
+const SESSION_KEY: &[u8] = b"matrix-migrate-session";
+
+#[derive(Serialize, Deserialize)]
+enum StoredSession {
+    Matrix(MatrixSession),
+    Oidc {
+        client_id: String,
+        /// The registered `VerifiedClientMetadata`, which only serializes one way
+        metadata: serde_json::Value,
+        user: Box<UserSession>,
+    },
+}
+
+impl Login {
+    /// Whether the session is created by us and should be logged out at the end. Sessions of
+    /// existing access tokens and exported sessions belong to the user, stored sessions are kept
+    /// for the next run.
+    pub fn owns_session(&self) -> bool {
+        self.token.is_none()
+            && self.session_file.is_none()
+            && self.store.is_none()
+            && !self.keep_session
+    }
+
+    /// Logs out the session if we own it, otherwise tells how to get rid of it later.
+    pub async fn end_session(&self, c: &Client) -> anyhow::Result<()> {
+        if self.owns_session() {
+            return logout(c).await;
+        }
+        if self.keep_session {
+            if let (Some(user_id), Some(device_id)) = (c.user_id(), c.device_id()) {
+                info!(
+                    "Keeping the session of {user_id} on device {device_id}. Remove it from the \
+                    session list of any client once you don't need it anymore"
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether logging in needs the password, i.e. no other way of logging in was given
+    fn needs_password(&self) -> bool {
+        self.token.is_none()
+            && self.as_token.is_none()
+            && self.session_file.is_none()
+            && !self.sso
+            && !self.oidc
+    }
+
+    /// Reads the password file or prompts for the password if it's needed but missing, failing
+    /// with `missing` if there is no terminal to prompt on.
+    pub fn ensure_password(&mut self, missing: &str) -> anyhow::Result<()> {
+        if !self.needs_password() || self.password.is_some() {
+            return Ok(());
+        }
+        if let Some(path) = &self.password_file {
+            let password = std::fs::read_to_string(path)
+                .map_err(|e| anyhow::anyhow!("Can't read {}: {e}", path.display()))?;
+            self.password = Some(password.trim_end_matches(['\r', '\n']).to_owned());
+            return Ok(());
+        }
+        if !std::io::stdin().is_terminal() {
+            anyhow::bail!("{missing}");
+        }
+        let prompt = match &self.user {
+            Some(user) => format!("Password of {user}"),
+            None => "Password".to_owned(),
+        };
+        self.password = Some(Password::new().with_prompt(prompt).interact()?);
+        Ok(())
+    }
+
+    /// The HTTP client for all requests of this account, including the ones the sdk can't do
+    pub fn http_client(&self) -> anyhow::Result<reqwest::Client> {
+        let mut builder = reqwest::Client::builder()
+            .user_agent("matrix-migrate/1")
+            .timeout(self.http_timeout);
+        if let Some(proxy) = &self.proxy {
+            builder = builder.proxy(reqwest::Proxy::all(proxy)?);
+        }
+        for path in &self.ca_certs {
+            let pem = std::fs::read(path)
+                .map_err(|e| anyhow::anyhow!("Can't read {}: {e}", path.display()))?;
+            for cert in reqwest::Certificate::from_pem_bundle(&pem)? {
+                builder = builder.add_root_certificate(cert);
+            }
+        }
+        if self.insecure_tls {
+            warn!("Not verifying TLS certificates for {:?}", self.user);
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+        Ok(builder.build()?)
+    }
+
+    pub async fn client(&self) -> anyhow::Result<Client> {
+        let http = self.http_client()?;
+        // don't retry inside the sdk, the limiter and syncer of the caller retry with the
+        // configured policy and pause all other requests on rate limits
+        let mut cb = Client::builder()
+            .http_client(http.clone())
+            .request_config(
+                RequestConfig::new()
+                    .timeout(self.http_timeout)
+                    .disable_retry(),
+            )
+            .handle_refresh_tokens();
+        if let Some(store) = &self.store {
+            cb = cb.sqlite_store(store, self.passphrase.as_deref());
+        }
+        let exported = match &self.session_file {
+            Some(path) => Some(read_exported_session(path)?),
+            None => None,
+        };
+
+        let c = if let Some(h) = &self.homeserver {
+            cb.server_name(h).build().await?
+        } else if let Some(user) = &self.user {
+            cb.server_name(user.server_name()).build().await?
+        } else if let Some(url) = exported.as_ref().and_then(|e| e.homeserver.clone()) {
+            cb.homeserver_url(url).build().await?
+        } else if let Some(exported) = &exported {
+            cb.server_name(exported.user_id.server_name())
+                .build()
+                .await?
+        } else {
+            anyhow::bail!("Either the user or the homeserver is required");
+        };
+
+        if let Some(store) = &self.store {
+            if restore_session(&c).await? {
+                let user_id = c.whoami().await?.user_id;
+                if self.user.as_ref().is_some_and(|user| *user != user_id) {
+                    anyhow::bail!("The session store {} belongs to {user_id}", store.display());
+                }
+                info!("Restored session of {user_id}");
+                save_on_refresh(&c);
+                return Ok(c);
+            }
+        }
+
+        if let Some(exported) = exported {
+            info!("Using the exported session of {}", exported.user_id);
+            c.matrix_auth()
+                .restore_session(MatrixSession {
+                    meta: SessionMeta {
+                        user_id: exported.user_id,
+                        device_id: exported.device_id,
+                    },
+                    tokens: MatrixSessionTokens {
+                        access_token: exported.access_token,
+                        refresh_token: None,
+                    },
+                })
+                .await?;
+            return Ok(c);
+        }
+
+        info!("Logging in {:?}", self.user);
+
+        if self.oidc {
+            oidc_login(&c, &self.device_name).await?;
+        } else {
+            self.matrix_login(&c, &http).await?;
+        }
+
+        if self.store.is_some() && self.token.is_none() {
+            save_session(&c).await?;
+            save_on_refresh(&c);
+        }
+        Ok(c)
+    }
+
+    async fn matrix_login(&self, c: &Client, http: &reqwest::Client) -> anyhow::Result<()> {
+        let auth = c.matrix_auth();
+        if !auth.logged_in() {
+            if let Some(token) = &self.token {
+                auth.restore_session(token_session(c, http, token).await?)
+                    .await?;
+            } else if let Some(as_token) = &self.as_token {
+                let Some(user) = &self.user else {
+                    anyhow::bail!("The user is required for appservice login");
+                };
+                auth.restore_session(
+                    appservice_session(c, http, user, as_token, &self.device_name).await?,
+                )
+                .await?;
+            } else if self.sso {
+                auth.login_sso(|sso_url| async move {
+                    println!("{}", sso_url);
+                    Ok(())
+                })
+                .initial_device_display_name(&self.device_name)
+                .send()
+                .await?;
+            } else {
+                let (Some(user), Some(password)) = (&self.user, &self.password) else {
+                    anyhow::bail!("User and password are required for password login");
+                };
+                auth.login_username(user, password)
+                    .initial_device_display_name(&self.device_name)
+                    .send()
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Restores the session kept in the client's store, if there is one
+async fn restore_session(c: &Client) -> anyhow::Result<bool> {
+    let Some(stored) = c.store().get_custom_value(SESSION_KEY).await? else {
+        return Ok(false);
+    };
+
+    match serde_json::from_slice(&stored)? {
+        StoredSession::Matrix(session) => c.matrix_auth().restore_session(session).await?,
+        StoredSession::Oidc {
+            client_id,
+            metadata,
+            user,
+        } => {
+            c.oidc()
+                .restore_session(OidcSession {
+                    credentials: ClientCredentials::None { client_id },
+                    metadata: serde_json::from_value::<ClientMetadata>(metadata)?.validate()?,
+                    user: *user,
+                })
+                .await?
+        }
+    }
+    Ok(true)
+}
+
+async fn save_session(c: &Client) -> anyhow::Result<()> {
+    let session = match c.auth_api() {
+        Some(AuthApi::Matrix(auth)) => {
+            let Some(session) = auth.session() else {
+                anyhow::bail!("Not logged in");
+            };
+            StoredSession::Matrix(session)
+        }
+        Some(AuthApi::Oidc(oidc)) => {
+            let Some(OidcSession {
+                credentials: ClientCredentials::None { client_id },
+                metadata,
+                user,
+            }) = oidc.full_session()
+            else {
+                anyhow::bail!("Not logged in with a public OIDC client");
+            };
+            StoredSession::Oidc {
+                client_id,
+                metadata: serde_json::to_value(metadata)?,
+                user: Box::new(user),
+            }
+        }
+        _ => anyhow::bail!("Not logged in"),
+    };
+
+    c.store()
+        .set_custom_value(SESSION_KEY, serde_json::to_vec(&session)?)
+        .await?;
+    Ok(())
+}
+
+/// Keeps the stored session up to date when the tokens get refreshed
+fn save_on_refresh(c: &Client) {
+    let mut changes = c.subscribe_to_session_changes();
+    let c = c.clone();
+    tokio::spawn(async move {
+        while let Ok(change) = changes.recv().await {
+            if let SessionChange::TokensRefreshed = change {
+                if let Err(e) = save_session(&c).await {
+                    warn!("Couldn't store the refreshed session: {e}");
+                }
+            }
+        }
+    });
+}
+
+/// Logs out the session with whichever API it was created
+async fn logout(c: &Client) -> anyhow::Result<()> {
+    match c.auth_api() {
+        Some(AuthApi::Matrix(auth)) => {
+            auth.logout().await?;
+        }
+        Some(AuthApi::Oidc(oidc)) => {
+            oidc.logout().await?;
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Registers a client with the homeserver's OIDC provider and logs in through the browser,
+/// receiving the redirect on a local port.
+async fn oidc_login(c: &Client, device_name: &str) -> anyhow::Result<()> {
+    let oidc = c.oidc();
+    let Some(issuer_info) = oidc.authentication_server_info().cloned() else {
+        anyhow::bail!("{} doesn't advertise an OIDC provider", c.homeserver());
+    };
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let redirect_uri = Url::parse(&format!(
+        "http://127.0.0.1:{}/",
+        listener.local_addr()?.port()
+    ))?;
+
+    let metadata = ClientMetadata {
+        application_type: Some(ApplicationType::Native),
+        redirect_uris: Some(vec![redirect_uri.clone()]),
+        grant_types: Some(vec![GrantType::AuthorizationCode, GrantType::RefreshToken]),
+        token_endpoint_auth_method: Some(OAuthClientAuthenticationMethod::None),
+        client_name: Some(Localized::new(device_name.to_owned(), [])),
+        client_uri: Some(Localized::new(
+            Url::parse("https://github.com/pajowu/matrix-migrate")?,
+            [],
+        )),
+        ..Default::default()
+    }
+    .validate()?;
+
+    let registration = oidc
+        .register_client(&issuer_info.issuer, metadata.clone(), None)
+        .await?;
+    oidc.restore_registered_client(
+        issuer_info,
+        metadata,
+        ClientCredentials::None {
+            client_id: registration.client_id,
+        },
+    );
+
+    let auth_data = oidc.login(redirect_uri, None)?.build().await?;
+    println!("{}", auth_data.url);
+
+    let code = match AuthorizationResponse::parse_query(&wait_for_redirect(&listener).await?)? {
+        AuthorizationResponse::Success(code) => code,
+        AuthorizationResponse::Error(e) => {
+            anyhow::bail!("OIDC authorization failed: {:?}", e.error)
+        }
+    };
+    oidc.finish_authorization(code).await?;
+    oidc.finish_login().await?;
+    Ok(())
+}
+
+/// Waits for the browser to be redirected to `listener` and returns the query of the request.
+async fn wait_for_redirect(listener: &TcpListener) -> anyhow::Result<String> {
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+        let mut buf = vec![0; 8192];
+        let n = stream.read(&mut buf).await?;
+        let request = String::from_utf8_lossy(&buf[..n]);
+
+        // GET /?code=...&state=... HTTP/1.1
+        let query = request
+            .split_whitespace()
+            .nth(1)
+            .and_then(|path| path.split_once('?'))
+            .map(|(_, query)| query.to_owned());
+        let Some(query) = query.filter(|q| q.contains("state=")) else {
+            stream
+                .write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n")
+                .await?;
+            continue;
+        };
+
+        stream
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\n\r\nDone, you can close this window.\n")
+            .await?;
+        return Ok(query);
+    }
+}
+
+/// Logs in as `user` with the `m.login.application_service` login type, which the homeserver
+/// only allows for users in the namespace of the appservice owning `as_token`.
+async fn appservice_session(
+    c: &Client,
+    http: &reqwest::Client,
+    user: &OwnedUserId,
+    as_token: &str,
+    device_name: &str,
+) -> anyhow::Result<MatrixSession> {
+    let login: LoginResponse = http
+        .post(endpoint(
+            &c.homeserver(),
+            &["_matrix", "client", "v3", "login"],
+        )?)
+        .bearer_auth(as_token)
+        .json(&json!({
+            "type": "m.login.application_service",
+            "identifier": { "type": "m.id.user", "user": user },
+            "initial_device_display_name": device_name,
+        }))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    Ok(MatrixSession {
+        meta: SessionMeta {
+            user_id: login.user_id,
+            device_id: login.device_id,
+        },
+        tokens: MatrixSessionTokens {
+            access_token: login.access_token,
+            refresh_token: None,
+        },
+    })
+}
+
+fn read_exported_session(path: &Path) -> anyhow::Result<ExportedSession> {
+    serde_json::from_str(&std::fs::read_to_string(path)?)
+        .map_err(|e| anyhow::anyhow!("Invalid session export {}: {e}", path.display()))
+}
+
+/// Looks up who an existing access token belongs to.
+async fn token_session(
+    c: &Client,
+    http: &reqwest::Client,
+    token: &str,
+) -> anyhow::Result<MatrixSession> {
+    let whoami: WhoAmI = http
+        .get(endpoint(
+            &c.homeserver(),
+            &["_matrix", "client", "v3", "account", "whoami"],
+        )?)
+        .bearer_auth(token)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    // tokens an admin created for the user, like those of `bulk`, have no device
+    let device_id = whoami.device_id.unwrap_or_else(|| {
+        warn!(
+            "The access token of {} isn't bound to a device, encrypted rooms can't be read or \
+            written to",
+            whoami.user_id
+        );
+        DeviceId::new()
+    });
+
+    Ok(MatrixSession {
+        meta: SessionMeta {
+            user_id: whoami.user_id,
+            device_id,
+        },
+        tokens: MatrixSessionTokens {
+            access_token: token.to_owned(),
+            refresh_token: None,
+        },
+    })
+}
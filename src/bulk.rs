@@ -0,0 +1,389 @@
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    time::{Duration, SystemTime},
+};
+
+use futures::{stream, StreamExt};
+use matrix_sdk::{
+    ruma::{OwnedUserId, ServerName},
+    Client,
+};
+use serde::Serialize;
+use serde_json::Value;
+use tracing::{info, warn};
+
+use crate::{
+    admin::SynapseAdmin,
+    auth::Login,
+    config,
+    report::{EXIT_FAILED, EXIT_SKIPPED},
+    Args,
+};
+
+/// How long the tokens created through the admin API stay valid, if revoking them at the end
+/// fails
+const TOKEN_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Outcome of migrating one user of a bulk migration
+#[derive(Serialize)]
+struct UserReport {
+    from: OwnedUserId,
+    to: OwnedUserId,
+    /// Exit code of the migration, see the usage notes
+    exit_code: Option<i32>,
+    report_file: PathBuf,
+    /// Counters of the user's report, if the migration got that far
+    counters: Option<Value>,
+    error: Option<String>,
+}
+
+/// Reads the `old_user,new_user` pairs of the CSV at `path`. A header line, empty lines and
+/// lines starting with `#` are skipped.
+fn read_users(path: &Path) -> anyhow::Result<Vec<(OwnedUserId, OwnedUserId)>> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("Can't read {}: {e}", path.display()))?;
+    let mut users = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields = line.split(',').map(str::trim).collect::<Vec<_>>();
+        let pair = match fields[..] {
+            [from, to] => OwnedUserId::try_from(from).and_then(|from| Ok((from, to.try_into()?))),
+            _ => anyhow::bail!(
+                "{} line {}: expected `old_user,new_user`",
+                path.display(),
+                i + 1
+            ),
+        };
+        match pair {
+            Ok(pair) => users.push(pair),
+            Err(_) if users.is_empty() && i == 0 => {}
+            Err(e) => anyhow::bail!("{} line {}: {e}", path.display(), i + 1),
+        }
+    }
+    Ok(users)
+}
+
+/// The admin API of the homeserver of `login`, or else of `server`, if there is an admin token
+/// for it
+pub async fn admin(
+    login: &Login,
+    server: &ServerName,
+    token: Option<&String>,
+) -> anyhow::Result<Option<SynapseAdmin>> {
+    let Some(token) = token else {
+        return Ok(None);
+    };
+    let c = Client::builder()
+        .server_name(login.homeserver.as_deref().unwrap_or(server))
+        .build()
+        .await?;
+    Ok(Some(SynapseAdmin::new(
+        login.http_client()?,
+        c.homeserver(),
+        token.clone(),
+    )))
+}
+
+/// Options only this process acts on. The processes of the users would fight over the ports
+/// and the terminal, and get the options of the config from this process instead.
+const PARENT_OPTIONS: [&str; 4] = ["--metrics-listen", "--web-ui", "--config", "--profile"];
+const PARENT_FLAGS: [&str; 1] = ["--tui"];
+/// Environment variables setting the options only this process acts on
+const PARENT_ENV: [&str; 5] = ["METRICS_LISTEN", "WEB_UI", "TUI", "CONFIG", "PROFILE"];
+
+/// `args` without the `options` taking a value and the `flags`
+fn without(
+    args: impl IntoIterator<Item = OsString>,
+    options: &[&str],
+    flags: &[&str],
+) -> Vec<OsString> {
+    let mut kept = Vec::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let s = arg.to_string_lossy();
+        let name = s.split_once('=').map_or(&*s, |(name, _)| name);
+        if options.contains(&name) {
+            if !s.contains('=') {
+                args.next();
+            }
+        } else if !flags.contains(&name) {
+            kept.push(arg);
+        }
+    }
+    kept
+}
+
+/// The command line of the process migrating a single user from `argv`, the one of this
+/// process: `apply` in place of `bulk` and its own options, followed by the options of the
+/// config the command line doesn't give, without the options only this process acts on. The
+/// options of the migration have to come after `apply`, the ones shared by all subcommands may.
+fn child_args(args: &Args, argv: Vec<OsString>) -> anyhow::Result<Vec<OsString>> {
+    let mut child = argv;
+    if let Some(i) = child.iter().position(|arg| arg == "bulk") {
+        let bulk_options = without(child.split_off(i + 1), &["--users", "--parallel"], &[]);
+        child.pop();
+        child.push("apply".into());
+        child.extend(bulk_options);
+    }
+    if let Some(path) = &args.config {
+        let cmd = Args::cli_command();
+        let cli = cmd
+            .clone()
+            .ignore_errors(true)
+            .try_get_matches_from(std::iter::once(OsString::new()).chain(child.clone()))?;
+        child.extend(config::args(&cmd, path, args.profile.as_deref(), &cli)?.0);
+    }
+    Ok(without(child, &PARENT_OPTIONS, &PARENT_FLAGS))
+}
+
+/// Migrates every `old_user,new_user` pair of the CSV at `users`, each in a process of its own
+/// with the options of this one, at most `parallel` at once. The users are logged in through
+/// the admin API with `--from-admin-token` / `--to-admin-token`, or through the appservice of
+/// `--from-as-token` / `--to-as-token`. Writes a report per user next to `--report-file` and a
+/// summary of all users to it.
+pub async fn bulk(args: &Args, users: &Path, parallel: usize) -> anyhow::Result<ExitCode> {
+    let pairs = read_users(users)?;
+    let Some((first_from, first_to)) = pairs.first() else {
+        anyhow::bail!("{} lists no users to migrate", users.display());
+    };
+    info!("--- Migrating {} users, {parallel} at once", pairs.len());
+
+    let from_admin = admin(
+        &args.connection.source_login(),
+        first_from.server_name(),
+        args.connection.from_admin_token.as_ref(),
+    )
+    .await?;
+    let to_admin = admin(
+        &args.connection.target_login(),
+        first_to.server_name(),
+        args.connection.to_admin_token.as_ref(),
+    )
+    .await?;
+    let exe = std::env::current_exe()?;
+    let base_args = child_args(args, std::env::args_os().skip(1).collect())?;
+    let stem = |path: &Path| {
+        path.file_stem()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned()
+    };
+    let (report_stem, state_stem) = (stem(&args.migrate().report_file), stem(&args.state_file));
+
+    let reports = stream::iter(pairs)
+        .map(|(from, to)| {
+            let report_file = args
+                .migrate()
+                .report_file
+                .with_file_name(format!("{report_stem}-{}.json", from.localpart()));
+            let state_file = args
+                .state_file
+                .with_file_name(format!("{state_stem}-{}.json", from.localpart()));
+            let mut command = std::process::Command::new(&exe);
+            for var in PARENT_ENV {
+                command.env_remove(var);
+            }
+            command
+                .args(&base_args)
+                .arg("--from")
+                .arg(from.as_str())
+                .arg("--to")
+                .arg(to.as_str())
+                .arg("--report-file")
+                .arg(&report_file)
+                .arg("--state-file")
+                .arg(&state_file);
+            let (from_admin, to_admin) = (&from_admin, &to_admin);
+            async move {
+                let mut user = UserReport {
+                    from: from.clone(),
+                    to: to.clone(),
+                    exit_code: None,
+                    report_file: report_file.clone(),
+                    counters: None,
+                    error: None,
+                };
+                // tokens go through the environment, so they don't show up in the process list
+                let mut tokens = Vec::new();
+                for (admin, user_id, var) in [
+                    (from_admin, &from, "FROM_TOKEN"),
+                    (to_admin, &to, "TO_TOKEN"),
+                ] {
+                    let Some(admin) = admin else {
+                        continue;
+                    };
+                    match admin.login_as(user_id, TOKEN_LIFETIME).await {
+                        Ok(token) => {
+                            command.env(var, &token);
+                            tokens.push((admin, token));
+                        }
+                        Err(e) => {
+                            warn!("Couldn't log in as {user_id}: {e}");
+                            user.error = Some(format!("logging in as {user_id} failed: {e}"));
+                            break;
+                        }
+                    }
+                }
+
+                let started = SystemTime::now();
+                if user.error.is_none() {
+                    info!("Migrating {from} to {to}");
+                    match tokio::task::spawn_blocking(move || command.status()).await {
+                        Ok(Ok(status)) => user.exit_code = status.code(),
+                        Ok(Err(e)) => user.error = Some(e.to_string()),
+                        Err(e) => user.error = Some(e.to_string()),
+                    }
+                }
+                // the sessions of tokens aren't logged out by the migration itself
+                for (admin, token) in tokens {
+                    if let Err(e) = admin.logout(&token).await {
+                        warn!("Couldn't revoke the token created for {from} or {to}: {e}");
+                    }
+                }
+                if user.error.is_some() {
+                    return user;
+                }
+                // a report older than the run is left over from an earlier one
+                let written = std::fs::metadata(&report_file)
+                    .and_then(|m| m.modified())
+                    .is_ok_and(|modified| modified >= started);
+                if !written {
+                    // e.g. clap's usage errors, whose exit code is the one of skipped rooms
+                    warn!(
+                        "Migrating {from} to {to} failed without writing a report, exit code {:?}",
+                        user.exit_code
+                    );
+                    user.error = Some(format!(
+                        "exited with {:?} before writing a report",
+                        user.exit_code
+                    ));
+                    return user;
+                }
+                user.counters = std::fs::read(&report_file)
+                    .ok()
+                    .and_then(|content| serde_json::from_slice::<Value>(&content).ok())
+                    .and_then(|mut report| report.get_mut("counters").map(Value::take));
+                if user.exit_code != Some(0) {
+                    warn!(
+                        "Migrating {from} to {to} didn't go through, exit code {:?}",
+                        user.exit_code
+                    );
+                }
+                user
+            }
+        })
+        .buffer_unordered(parallel.max(1))
+        .collect::<Vec<_>>()
+        .await;
+
+    std::fs::write(
+        &args.migrate().report_file,
+        serde_json::to_vec_pretty(&reports)?,
+    )
+    .map_err(|e| {
+        anyhow::anyhow!(
+            "Couldn't write the report {}: {e}",
+            args.migrate().report_file.display()
+        )
+    })?;
+
+    let migrated = reports
+        .iter()
+        .filter(|r| r.error.is_none() && r.exit_code == Some(0))
+        .count();
+    let skipped = reports
+        .iter()
+        .filter(|r| r.error.is_none() && r.exit_code == Some(EXIT_SKIPPED.into()))
+        .count();
+    info!(
+        "--- Migrated {migrated} of {} users, {skipped} with skipped rooms. See {} for the report \
+        of every user",
+        reports.len(),
+        args.migrate().report_file.display()
+    );
+    Ok(if migrated + skipped < reports.len() {
+        ExitCode::from(EXIT_FAILED)
+    } else if skipped > 0 {
+        ExitCode::from(EXIT_SKIPPED)
+    } else {
+        ExitCode::SUCCESS
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Command;
+
+    fn argv(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    /// The arguments a process migrating a user gets, parsed, for the command line `parent`
+    fn child(parent: &[&str]) -> Args {
+        let cmd = Args::cli_command();
+        let args =
+            Args::try_from_argv(&cmd, argv(&[&["matrix-migrate"], parent].concat())).unwrap();
+        let mut child = argv(&["matrix-migrate"]);
+        child.extend(child_args(&args, argv(parent)).unwrap());
+        child.extend(argv(&[
+            "--from",
+            "@old:example.org",
+            "--to",
+            "@new:example.org",
+            "--report-file",
+            "report-old.json",
+            "--state-file",
+            "state-old.json",
+        ]));
+        Args::try_from_argv(&cmd, child).unwrap()
+    }
+
+    #[test]
+    fn migrate_options_come_after_apply() {
+        let args = child(&[
+            "--from-pw",
+            "x",
+            "--to-pw",
+            "y",
+            "bulk",
+            "--users",
+            "users.csv",
+            "--parallel",
+            "2",
+            "--leave-rooms",
+            "--tui",
+        ]);
+        assert!(matches!(args.command, Some(Command::Apply(_))));
+        assert!(args.migrate().leave_rooms);
+        assert!(!args.migrate().tui);
+        assert_eq!(args.state_file, PathBuf::from("state-old.json"));
+    }
+
+    #[test]
+    fn config_options_come_after_apply() {
+        let config = std::env::temp_dir().join(format!("bulk-config-{}.toml", std::process::id()));
+        std::fs::write(
+            &config,
+            "leave-rooms = true\nmetrics-listen = \"127.0.0.1:9000\"\n",
+        )
+        .unwrap();
+        let args = child(&[
+            "--config",
+            config.to_str().unwrap(),
+            "bulk",
+            "--users",
+            "users.csv",
+        ]);
+        std::fs::remove_file(&config).unwrap();
+        assert!(matches!(args.command, Some(Command::Apply(_))));
+        assert!(args.migrate().leave_rooms);
+        assert_eq!(args.migrate().metrics_listen, None);
+        assert_eq!(args.config, None);
+    }
+}
@@ -0,0 +1,168 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use matrix_sdk::{
+    ruma::{events::room::member::MembershipState, OwnedRoomId},
+    Client,
+};
+use serde::Serialize;
+
+use crate::{filter, plan::Plan, Args};
+
+/// How long a request takes when the rate isn't limited, to estimate the duration of a run
+const UNLIMITED_REQUEST_TIME: Duration = Duration::from_millis(500);
+
+/// What a migration would involve, found without changing anything
+#[derive(Serialize)]
+pub struct CheckReport {
+    pub rooms: usize,
+    pub already_shared: usize,
+    pub to_invite: usize,
+    pub power_levels: usize,
+    pub to_leave: usize,
+    pub encrypted: usize,
+    pub dms: usize,
+    /// Skipped unless `--include-bridged` is given
+    pub bridged: Vec<OwnedRoomId>,
+    /// Rooms where inviting the new account is likely to fail, and why
+    pub likely_failures: BTreeMap<OwnedRoomId, String>,
+    pub estimated_seconds: u64,
+}
+
+/// Looks at the selected rooms of both accounts to tell what migrating them would involve
+pub async fn check(
+    args: &Args,
+    plan: &Plan,
+    from_c: &Client,
+    to_c: &Client,
+) -> anyhow::Result<CheckReport> {
+    let old_user = from_c.user_id().unwrap();
+    let new_user = to_c.user_id().unwrap();
+
+    let mut rooms = args.migrate().filters.select(from_c.joined_rooms()).await?;
+    rooms.retain(|r| !plan.skips(r));
+    let bridged = crate::bridged_rooms(from_c, &rooms).await?;
+    if !args.migrate().include_bridged {
+        rooms.retain(|r| !bridged.contains(r));
+    }
+
+    let mut report = CheckReport {
+        rooms: rooms.len(),
+        already_shared: 0,
+        to_invite: 0,
+        power_levels: 0,
+        to_leave: 0,
+        encrypted: 0,
+        dms: 0,
+        bridged,
+        likely_failures: BTreeMap::new(),
+        estimated_seconds: 0,
+    };
+
+    let mut shared = Vec::new();
+    for room_id in &rooms {
+        let Some(room) = from_c.get_room(room_id) else {
+            continue;
+        };
+        if room.is_encrypted().await? {
+            report.encrypted += 1;
+        }
+        if filter::is_dm(&room).await? {
+            report.dms += 1;
+        }
+        if plan.leaves(room_id, args.migrate().leave_rooms) {
+            report.to_leave += 1;
+        }
+        if to_c.get_room(room_id).is_some() {
+            report.already_shared += 1;
+            shared.push(room_id);
+            continue;
+        }
+
+        report.to_invite += 1;
+        // the new account starts out without any power level
+        let own_power_level = room
+            .get_member_no_sync(old_user)
+            .await?
+            .map_or(0, |m| m.power_level());
+        if plan.target_power_level(room_id, own_power_level) > 0 {
+            report.power_levels += 1;
+        }
+        let banned = room
+            .get_member_no_sync(new_user)
+            .await?
+            .is_some_and(|m| *m.membership() == MembershipState::Ban);
+        let failure = if banned && !(args.migrate().unban && room.can_user_ban(old_user).await?) {
+            Some("the new account is banned".to_owned())
+        } else if !crate::acl_allows(&room, new_user.server_name()).await? {
+            Some(format!("the server ACL denies {}", new_user.server_name()))
+        } else if !room.can_user_invite(old_user).await? {
+            Some("the old account isn't allowed to invite".to_owned())
+        } else {
+            None
+        };
+        if let Some(failure) = failure {
+            report.likely_failures.insert(room_id.clone(), failure);
+        }
+    }
+
+    report.power_levels += crate::power_level_gaps(from_c, old_user, new_user, &shared, plan)
+        .await?
+        .len();
+
+    // invites, power levels and leaves go to the old homeserver, accepting to the new one
+    let from_requests = report.to_invite + report.power_levels + report.to_leave;
+    let estimate = |requests: usize, rate: f64| {
+        if rate > 0.0 {
+            Duration::from_secs_f64(requests as f64 / rate)
+        } else {
+            UNLIMITED_REQUEST_TIME.saturating_mul(requests as u32)
+                / args.connection.concurrency.max(1) as u32
+        }
+    };
+    let from_rate = args
+        .connection
+        .from_requests_per_second
+        .unwrap_or(args.connection.requests_per_second);
+    let to_rate = args
+        .connection
+        .to_requests_per_second
+        .unwrap_or(args.connection.requests_per_second);
+    report.estimated_seconds = estimate(from_requests, from_rate)
+        .max(estimate(report.to_invite, to_rate))
+        .as_secs();
+
+    Ok(report)
+}
+
+impl CheckReport {
+    /// The report as a human readable summary
+    pub fn summary(&self) -> String {
+        let estimate = Duration::from_secs(self.estimated_seconds);
+        let rows = [
+            ("Rooms selected:", self.rooms.to_string()),
+            ("Already shared:", self.already_shared.to_string()),
+            ("To invite:", self.to_invite.to_string()),
+            ("Power levels to adjust:", self.power_levels.to_string()),
+            ("To leave:", self.to_leave.to_string()),
+            ("Encrypted:", self.encrypted.to_string()),
+            ("DMs:", self.dms.to_string()),
+            ("Bridged:", self.bridged.len().to_string()),
+            (
+                "Estimated duration:",
+                humantime::format_duration(estimate).to_string(),
+            ),
+            (
+                "Invites likely to fail:",
+                self.likely_failures.len().to_string(),
+            ),
+        ];
+        let mut summary = rows
+            .iter()
+            .map(|(label, value)| format!("{label:<26}{value}\n"))
+            .collect::<String>();
+        for (room_id, failure) in &self.likely_failures {
+            summary += &format!("  {room_id}: {failure}\n");
+        }
+        summary
+    }
+}
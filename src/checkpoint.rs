@@ -0,0 +1,144 @@
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use matrix_sdk::ruma::{OwnedRoomId, OwnedUserId, RoomId, UserId};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+/// A step of the migration of a single room
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Step {
+    Invited,
+    Accepted,
+    PowerLevel,
+    Left,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct Progress {
+    from: Option<OwnedUserId>,
+    to: Option<OwnedUserId>,
+    invited: BTreeSet<OwnedRoomId>,
+    accepted: BTreeSet<OwnedRoomId>,
+    power_level: BTreeSet<OwnedRoomId>,
+    left: BTreeSet<OwnedRoomId>,
+}
+
+impl Progress {
+    fn rooms(&mut self, step: Step) -> &mut BTreeSet<OwnedRoomId> {
+        match step {
+            Step::Invited => &mut self.invited,
+            Step::Accepted => &mut self.accepted,
+            Step::PowerLevel => &mut self.power_level,
+            Step::Left => &mut self.left,
+        }
+    }
+}
+
+/// Progress of the migration, written to a state file after every action so an interrupted
+/// run can be resumed.
+pub struct Checkpoint {
+    path: Option<PathBuf>,
+    progress: Mutex<Progress>,
+}
+
+impl Checkpoint {
+    /// A checkpoint that doesn't write anything, for dry runs
+    pub fn disabled() -> Self {
+        Self {
+            path: None,
+            progress: Mutex::default(),
+        }
+    }
+
+    /// Starts recording to `path`. With `resume`, the progress of the previous run of the same
+    /// accounts is picked up.
+    pub fn open(path: &Path, resume: bool, from: &UserId, to: &UserId) -> anyhow::Result<Self> {
+        let mut progress = Progress::default();
+        if resume {
+            match std::fs::read_to_string(path) {
+                Ok(content) => {
+                    progress = serde_json::from_str(&content).map_err(|e| {
+                        anyhow::anyhow!("Invalid state file {}: {e}", path.display())
+                    })?;
+                    if progress.from.as_deref() != Some(from) || progress.to.as_deref() != Some(to)
+                    {
+                        anyhow::bail!(
+                            "The state file {} belongs to a migration of {:?} to {:?}",
+                            path.display(),
+                            progress.from,
+                            progress.to
+                        );
+                    }
+                    info!(
+                        "Resuming: {} rooms invited, {} accepted, {} power levels adjusted, {} left",
+                        progress.invited.len(),
+                        progress.accepted.len(),
+                        progress.power_level.len(),
+                        progress.left.len()
+                    );
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    warn!("No state file at {}, starting over", path.display());
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        progress.from = Some(from.to_owned());
+        progress.to = Some(to.to_owned());
+
+        Ok(Self {
+            path: Some(path.to_owned()),
+            progress: Mutex::new(progress),
+        })
+    }
+
+    /// Reads the state file at `path` without recording to it
+    pub fn read(path: &Path) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("Can't read the state file {}: {e}", path.display()))?;
+        let progress = serde_json::from_str(&content)
+            .map_err(|e| anyhow::anyhow!("Invalid state file {}: {e}", path.display()))?;
+        Ok(Self {
+            path: None,
+            progress: Mutex::new(progress),
+        })
+    }
+
+    /// The accounts of the migration, unless nothing was recorded yet
+    pub fn accounts(&self) -> Option<(OwnedUserId, OwnedUserId)> {
+        let progress = self.progress.lock().unwrap();
+        progress.from.clone().zip(progress.to.clone())
+    }
+
+    /// How many rooms `step` is done for
+    pub fn count(&self, step: Step) -> usize {
+        self.progress.lock().unwrap().rooms(step).len()
+    }
+
+    pub fn done(&self, step: Step, room_id: &RoomId) -> bool {
+        self.progress.lock().unwrap().rooms(step).contains(room_id)
+    }
+
+    /// Marks `step` as done for `room_id` and writes the state file
+    pub fn record(&self, step: Step, room_id: &RoomId) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let mut progress = self.progress.lock().unwrap();
+        progress.rooms(step).insert(room_id.to_owned());
+
+        // write to a temporary file first, so a crash never leaves a truncated state file
+        let tmp = path.with_extension("tmp");
+        let written = serde_json::to_vec_pretty(&*progress)
+            .map_err(std::io::Error::from)
+            .and_then(|content| std::fs::write(&tmp, content))
+            .and_then(|_| std::fs::rename(&tmp, path));
+        if let Err(e) = written {
+            warn!("Couldn't write the state file {}: {e}", path.display());
+        }
+    }
+}
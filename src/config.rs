@@ -0,0 +1,306 @@
+use std::{ffi::OsString, path::Path};
+
+use clap::{builder::BoolishValueParser, parser::ValueSource, ArgAction, ArgMatches, Command};
+
+/// Turns the options of a TOML config file into command line arguments, so they go through the
+/// same parsing and validation as the ones given directly. Keys are the long option names, and
+/// a `[profile.NAME]` table overrides them when selected with `--profile`:
+///
+/// ```toml
+/// from = "@me:old.example.org"
+/// from-pw-file = "/run/secrets/old-password"
+/// to = "@me:new.example.org"
+/// leave-rooms = true
+///
+/// [profile.work]
+/// space = "#work:old.example.org"
+/// rooms-excluded = ["#random:old.example.org"]
+/// ```
+///
+/// Only the options of the subcommand `cli` was parsed with, if any, are set, and the ones given
+/// on its command line are left out, so they take precedence. `cmd` has to be built.
+///
+/// Several accounts can be merged into the new one by listing them as `[[source]]` tables with
+/// their `from` options. Their arguments are returned separately, one list per account.
+pub fn args(
+    cmd: &Command,
+    path: &Path,
+    profile: Option<&str>,
+    cli: &ArgMatches,
+) -> anyhow::Result<(Vec<OsString>, Vec<Vec<OsString>>)> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("Can't read the config {}: {e}", path.display()))?;
+    let mut options: toml::Table = toml::from_str(&content)
+        .map_err(|e| anyhow::anyhow!("Invalid config {}: {e}", path.display()))?;
+
+    let profiles = match options.remove("profile") {
+        Some(toml::Value::Table(profiles)) => profiles,
+        Some(_) => anyhow::bail!(
+            "Invalid config {}: `profile` has to be a table",
+            path.display()
+        ),
+        None => toml::Table::new(),
+    };
+    if let Some(name) = profile {
+        let Some(toml::Value::Table(overrides)) = profiles.get(name) else {
+            anyhow::bail!(
+                "The config {} has no profile {name}, only {:?}",
+                path.display(),
+                profiles.keys().collect::<Vec<_>>()
+            );
+        };
+        options.extend(overrides.clone());
+    }
+
+    // options of the other subcommands don't apply, the same config serves all of them
+    let (sub, cli) = match cli.subcommand() {
+        Some((name, sub_matches)) => (cmd.find_subcommand(name).unwrap(), sub_matches),
+        None => (cmd, cli),
+    };
+    options.retain(|key, _| {
+        let long = key.replace('_', "-");
+        !has_long(cmd, &long) || sub.get_arguments().any(|a| a.get_long() == Some(&long))
+    });
+
+    let sources = match options.remove("source") {
+        Some(toml::Value::Array(sources)) => sources
+            .into_iter()
+            .map(|source| match source {
+                toml::Value::Table(source) => options_args(sub, source, path, None),
+                _ => anyhow::bail!(
+                    "Invalid config {}: `source` has to be an array of tables",
+                    path.display()
+                ),
+            })
+            .collect::<anyhow::Result<_>>()?,
+        Some(_) => anyhow::bail!(
+            "Invalid config {}: `source` has to be an array of tables",
+            path.display()
+        ),
+        None => Vec::new(),
+    };
+    Ok((options_args(sub, options, path, Some(cli))?, sources))
+}
+
+/// The command line arguments setting `options`, except the ones already given on the command
+/// line `cli` was parsed from
+fn options_args(
+    cmd: &Command,
+    options: toml::Table,
+    path: &Path,
+    cli: Option<&ArgMatches>,
+) -> anyhow::Result<Vec<OsString>> {
+    let mut args = Vec::new();
+    for (key, value) in options {
+        let long = key.replace('_', "-");
+        let Some(arg) = cmd
+            .get_arguments()
+            .find(|a| a.get_long() == Some(long.as_str()))
+            .filter(|a| !matches!(a.get_id().as_str(), "config" | "profile"))
+        else {
+            anyhow::bail!("Invalid config {}: unknown option `{key}`", path.display());
+        };
+        if cli.is_some_and(|m| {
+            m.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine)
+        }) {
+            continue;
+        }
+        let flag = OsString::from(format!("--{long}"));
+        match (arg.get_action(), value) {
+            (ArgAction::SetTrue, toml::Value::Boolean(set)) => {
+                if set {
+                    args.push(flag);
+                }
+            }
+            (ArgAction::Count, toml::Value::Integer(count)) => {
+                for _ in 0..count {
+                    args.push(flag.clone());
+                }
+            }
+            // options with an optional value, like `leave-message = true` for the default one
+            (_, toml::Value::Boolean(set))
+                if arg.get_num_args().is_some_and(|n| n.min_values() == 0) =>
+            {
+                if set {
+                    args.push(flag);
+                }
+            }
+            (ArgAction::Set | ArgAction::Append, toml::Value::Array(values)) => {
+                for value in values {
+                    args.push(flag.clone());
+                    args.push(plain(&key, value, path)?);
+                }
+            }
+            (ArgAction::Set | ArgAction::Append, value) => {
+                args.push(flag);
+                args.push(plain(&key, value, path)?);
+            }
+            (_, value) => anyhow::bail!(
+                "Invalid config {}: unexpected value {value} for `{key}`",
+                path.display()
+            ),
+        }
+    }
+    Ok(args)
+}
+
+/// Whether `cmd` or one of its subcommands has the option `long`
+fn has_long(cmd: &Command, long: &str) -> bool {
+    cmd.get_arguments().any(|a| a.get_long() == Some(long))
+        || cmd.get_subcommands().any(|s| has_long(s, long))
+}
+
+/// `cmd` with its flags and the ones of its subcommands taking an optional value, e.g.
+/// `--leave-rooms=false`, so the command line can turn off the flags the config sets
+pub fn negatable_flags(cmd: Command) -> Command {
+    let subcommands = cmd
+        .get_subcommands()
+        .map(|s| s.get_name().to_owned())
+        .collect::<Vec<_>>();
+    let mut cmd = cmd.mut_args(|a| match a.get_action() {
+        ArgAction::SetTrue => a
+            .action(ArgAction::Set)
+            .num_args(0..=1)
+            .require_equals(true)
+            .default_missing_value("true")
+            .default_value("false")
+            .value_parser(BoolishValueParser::new())
+            .hide_default_value(true)
+            .hide_possible_values(true),
+        _ => a,
+    });
+    for name in subcommands {
+        cmd = cmd.mut_subcommand(name, negatable_flags);
+    }
+    cmd
+}
+
+/// The value of a single option as given on the command line
+fn plain(key: &str, value: toml::Value, path: &Path) -> anyhow::Result<OsString> {
+    Ok(match value {
+        toml::Value::String(s) => s.into(),
+        toml::Value::Integer(i) => i.to_string().into(),
+        toml::Value::Float(f) => f.to_string().into(),
+        toml::Value::Boolean(b) => b.to_string().into(),
+        value => anyhow::bail!(
+            "Invalid config {}: unexpected value {value} for `{key}`",
+            path.display()
+        ),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Arg;
+
+    use super::*;
+
+    fn cmd() -> Command {
+        Command::new("matrix-migrate")
+            .arg(Arg::new("config").long("config"))
+            .arg(Arg::new("state_file").long("state-file"))
+            .arg(Arg::new("rooms").long("rooms").action(ArgAction::Append))
+            .arg(
+                Arg::new("leave_rooms")
+                    .long("leave-rooms")
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(Arg::new("verbose").long("verbose").action(ArgAction::Count))
+            .arg(
+                Arg::new("leave_message")
+                    .long("leave-message")
+                    .num_args(0..=1)
+                    .default_missing_value("moved"),
+            )
+    }
+
+    fn options(toml: &str) -> toml::Table {
+        toml::from_str(toml).unwrap()
+    }
+
+    fn args(cmd: &Command, toml: &str, cli: &[&str]) -> Vec<String> {
+        let matches = cmd
+            .clone()
+            .try_get_matches_from(std::iter::once("matrix-migrate").chain(cli.iter().copied()))
+            .unwrap();
+        options_args(cmd, options(toml), Path::new("test.toml"), Some(&matches))
+            .unwrap()
+            .into_iter()
+            .map(|a| a.into_string().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn turns_options_into_args() {
+        let args = args(
+            &cmd(),
+            r#"
+            state_file = "state.json"
+            rooms = ["!a:example.org", "!b:example.org"]
+            leave-rooms = true
+            verbose = 2
+            leave-message = true
+            "#,
+            &[],
+        );
+        assert_eq!(
+            args,
+            [
+                "--leave-message",
+                "--leave-rooms",
+                "--rooms",
+                "!a:example.org",
+                "--rooms",
+                "!b:example.org",
+                "--state-file",
+                "state.json",
+                "--verbose",
+                "--verbose",
+            ]
+        );
+    }
+
+    #[test]
+    fn leaves_out_unset_flags() {
+        let args = args(&cmd(), "leave-rooms = false\nleave-message = false", &[]);
+        assert!(args.is_empty());
+    }
+
+    #[test]
+    fn command_line_takes_precedence() {
+        let args = args(
+            &cmd(),
+            r#"
+            state-file = "state.json"
+            rooms = ["!a:example.org"]
+            leave-rooms = true
+            "#,
+            &["--rooms", "!b:example.org", "--state-file", "other.json"],
+        );
+        assert_eq!(args, ["--leave-rooms"]);
+    }
+
+    #[test]
+    fn command_line_turns_off_flags() {
+        let cmd = negatable_flags(cmd());
+        assert!(args(&cmd, "leave-rooms = true", &["--leave-rooms=false"]).is_empty());
+
+        let matches = cmd
+            .clone()
+            .try_get_matches_from(["matrix-migrate", "--leave-rooms=false"])
+            .unwrap();
+        assert_eq!(matches.get_one::<bool>("leave_rooms"), Some(&false));
+        let matches = cmd
+            .try_get_matches_from(["matrix-migrate", "--leave-rooms"])
+            .unwrap();
+        assert_eq!(matches.get_one::<bool>("leave_rooms"), Some(&true));
+    }
+
+    #[test]
+    fn rejects_unknown_options() {
+        let path = Path::new("test.toml");
+        assert!(options_args(&cmd(), options("leave = true"), path, None).is_err());
+        assert!(options_args(&cmd(), options("config = \"other.toml\""), path, None).is_err());
+        assert!(options_args(&cmd(), options("rooms = { a = 1 }"), path, None).is_err());
+    }
+}
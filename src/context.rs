@@ -0,0 +1,66 @@
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use tokio::sync::Notify;
+
+use crate::{hook::Hook, journal::Journal, report, tui::Dashboard, webhook::Webhook};
+
+tokio::task_local! {
+    static CURRENT: Arc<Context>;
+}
+
+/// The state of a migration run: its report as the steps fill it in, and where its actions and
+/// events go. Every [`crate::Migrator`] owns one, shared with the accounts merged into the same
+/// new account, so runs in the same process don't see each other's rooms and settings.
+#[derive(Default)]
+pub struct Context {
+    pub report: report::State,
+    pub hook: Mutex<Option<Arc<Hook>>>,
+    pub journal: Mutex<Option<Journal>>,
+    pub webhook: Mutex<Option<Webhook>>,
+    pub dashboard: Mutex<Option<Dashboard>>,
+    aborted: AtomicBool,
+    abort: Notify,
+}
+
+impl Context {
+    /// Asks the run to stop, e.g. from the dashboard. It stops between two requests, and still
+    /// writes its report and logs out.
+    pub fn abort(&self) {
+        self.aborted.store(true, Ordering::Relaxed);
+        self.abort.notify_waiters();
+    }
+
+    pub fn is_aborted(&self) -> bool {
+        self.aborted.load(Ordering::Relaxed)
+    }
+
+    /// Waits until the run is asked to stop
+    pub async fn aborted(&self) {
+        loop {
+            let notified = self.abort.notified();
+            if self.is_aborted() {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+/// Runs `f` with `context` as the context of the steps it calls
+pub async fn scope<T>(
+    context: Arc<Context>,
+    f: impl Future<Output = anyhow::Result<T>>,
+) -> anyhow::Result<T> {
+    CURRENT.scope(context, f).await
+}
+
+/// Calls `f` with the context of the run the caller is part of, `None` outside of a run
+pub fn with<T>(f: impl FnOnce(&Context) -> T) -> Option<T> {
+    CURRENT.try_with(|context| f(context)).ok()
+}
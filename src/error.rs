@@ -0,0 +1,121 @@
+use std::{fmt::Display, time::Duration};
+
+use matrix_sdk::ruma::api::client::error::{ErrorBody, ErrorKind};
+
+use crate::report::Reason;
+
+/// A failed request to a homeserver, sorted by what the run does about it
+#[derive(Debug)]
+pub enum MatrixError {
+    /// `M_LIMIT_EXCEEDED`
+    RateLimited {
+        retry_after: Option<Duration>,
+    },
+    /// `M_FORBIDDEN`
+    Forbidden(String),
+    /// `M_UNKNOWN_TOKEN`, the session was logged out or expired
+    UnknownToken {
+        soft_logout: bool,
+    },
+    /// `M_CONSENT_NOT_GIVEN`, the account has to accept the homeserver's terms first
+    ConsentNotGiven(String),
+    /// The homeserver couldn't reach the servers of the room
+    Federation(String),
+    Other(String),
+}
+
+/// What the run does about a failed request
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Handling {
+    /// Pause the requests to the homeserver and try again
+    Wait,
+    /// Give up on the room and go on with the others
+    Skip,
+    /// Stop, the account has to be logged in again
+    ReAuth,
+    /// Stop, nothing else is going to work either
+    Abort,
+}
+
+impl MatrixError {
+    pub fn of(e: &matrix_sdk::Error) -> Self {
+        let Some(api_error) = e.as_client_api_error() else {
+            return Self::Other(e.to_string());
+        };
+        let (kind, message) = match &api_error.body {
+            ErrorBody::Standard { kind, message } => (Some(kind), message.clone()),
+            _ => (None, e.to_string()),
+        };
+        match kind {
+            Some(ErrorKind::LimitExceeded { retry_after_ms }) => Self::RateLimited {
+                retry_after: *retry_after_ms,
+            },
+            Some(ErrorKind::Forbidden) => Self::Forbidden(message),
+            Some(ErrorKind::UnknownToken { soft_logout }) => Self::UnknownToken {
+                soft_logout: *soft_logout,
+            },
+            Some(kind) if kind.as_ref() == "M_CONSENT_NOT_GIVEN" => Self::ConsentNotGiven(message),
+            _ if api_error.status_code.is_server_error() => Self::Federation(message),
+            _ if message.to_lowercase().contains("federat") => Self::Federation(message),
+            _ => Self::Other(message),
+        }
+    }
+
+    pub fn handling(&self) -> Handling {
+        match self {
+            Self::RateLimited { .. } => Handling::Wait,
+            Self::Forbidden(_) | Self::Federation(_) | Self::Other(_) => Handling::Skip,
+            Self::UnknownToken { .. } => Handling::ReAuth,
+            Self::ConsentNotGiven(_) => Handling::Abort,
+        }
+    }
+
+    /// The reason failures of this kind are grouped under in the report
+    pub fn reason(&self) -> Reason {
+        match self {
+            Self::RateLimited { .. } => Reason::RateLimited,
+            Self::Forbidden(_) => Reason::NoPermission,
+            Self::Federation(_) => Reason::Federation,
+            Self::UnknownToken { .. } | Self::ConsentNotGiven(_) | Self::Other(_) => Reason::Other,
+        }
+    }
+}
+
+impl Display for MatrixError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::RateLimited {
+                retry_after: Some(retry_after),
+            } => write!(
+                f,
+                "rate limited for {}",
+                humantime::format_duration(*retry_after)
+            ),
+            Self::RateLimited { retry_after: None } => f.write_str("rate limited"),
+            Self::Forbidden(message) => write!(f, "forbidden: {message}"),
+            Self::UnknownToken { soft_logout: true } => {
+                f.write_str("the session expired, log in again")
+            }
+            Self::UnknownToken { soft_logout: false } => f.write_str(
+                "the session was logged out, log in again (with --fresh when using --session-store)",
+            ),
+            Self::ConsentNotGiven(message) => {
+                write!(f, "the homeserver's terms have to be accepted first: {message}")
+            }
+            Self::Federation(message) => write!(f, "federation error: {message}"),
+            Self::Other(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for MatrixError {}
+
+/// Fails with the error if it ends the whole run instead of just the room it happened in.
+/// Resuming with `--resume` picks the run up again once the cause is fixed.
+pub fn abort_if_fatal(e: &matrix_sdk::Error) -> anyhow::Result<()> {
+    let error = MatrixError::of(e);
+    match error.handling() {
+        Handling::ReAuth | Handling::Abort => Err(error.into()),
+        Handling::Wait | Handling::Skip => Ok(()),
+    }
+}
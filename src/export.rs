@@ -0,0 +1,116 @@
+use std::{
+    path::Path,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use matrix_sdk::{room::MessagesOptions, ruma::OwnedRoomId, Client, Room};
+use serde_json::Value;
+use tracing::{info, warn};
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// The raw events, one JSON array per room
+    Json,
+    /// A readable table of messages, one HTML page per room
+    Html,
+}
+
+pub async fn export_rooms(
+    c: &Client,
+    rooms: &Vec<OwnedRoomId>,
+    dir: &Path,
+    format: ExportFormat,
+) -> anyhow::Result<()> {
+    std::fs::create_dir_all(dir)?;
+
+    for room_id in rooms {
+        let Some(room) = c.get_room(room_id) else {
+            warn!("Can't export {room_id}: not a member");
+            continue;
+        };
+        let display_name = room.display_name().await?.to_string();
+        info!("Exporting {display_name}({room_id})");
+        let events = history(&room).await?;
+
+        let file_name = room_id
+            .as_str()
+            .replace(|c: char| !c.is_alphanumeric(), "_");
+        let (path, content) = match format {
+            ExportFormat::Json => (
+                dir.join(format!("{file_name}.json")),
+                serde_json::to_string_pretty(&events)?,
+            ),
+            ExportFormat::Html => (
+                dir.join(format!("{file_name}.html")),
+                render_html(&display_name, room_id, &events),
+            ),
+        };
+        std::fs::write(&path, content)?;
+        info!("Wrote {} events to {}", events.len(), path.display());
+    }
+
+    Ok(())
+}
+
+/// The events of the visible history of `room`, oldest first
+pub async fn history(room: &Room) -> anyhow::Result<Vec<Value>> {
+    // paginate backwards until the start of the visible history
+    let mut events = Vec::new();
+    let mut options = MessagesOptions::backward();
+    loop {
+        let messages = room.messages(options).await?;
+        let exhausted = messages.chunk.is_empty();
+        events.extend(
+            messages
+                .chunk
+                .into_iter()
+                .filter_map(|e| e.event.deserialize_as::<Value>().ok()),
+        );
+        match messages.end {
+            Some(end) if !exhausted => options = MessagesOptions::backward().from(end.as_str()),
+            _ => break,
+        }
+    }
+    events.reverse();
+    Ok(events)
+}
+
+fn render_html(display_name: &str, room_id: &OwnedRoomId, events: &[Value]) -> String {
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>{0}</title></head>\n<body>\n<h1>{0}</h1>\n<p>{1}</p>\n<table>\n",
+        escape(display_name),
+        escape(room_id.as_str())
+    );
+
+    for event in events {
+        let time = event["origin_server_ts"]
+            .as_u64()
+            .map(|ts| {
+                humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_millis(ts))
+                    .to_string()
+            })
+            .unwrap_or_default();
+        let sender = event["sender"].as_str().unwrap_or_default();
+        let body = match event["content"]["body"].as_str() {
+            Some(body) => body.to_owned(),
+            None => format!("[{}]", event["type"].as_str().unwrap_or_default()),
+        };
+        html.push_str(&format!(
+            "<tr><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+            time,
+            escape(sender),
+            escape(&body).replace('\n', "<br>")
+        ));
+    }
+
+    html.push_str("</table>\n</body>\n</html>\n");
+    html
+}
+
+/// Escapes `text` for HTML
+pub fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
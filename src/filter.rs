@@ -0,0 +1,548 @@
+use std::{io::Read, path::PathBuf, time::SystemTime};
+
+use dialoguer::MultiSelect;
+use matrix_sdk::{
+    room::MessagesOptions,
+    ruma::{
+        api::client::space::get_hierarchy, events::room::join_rules::JoinRule,
+        MilliSecondsSinceUnixEpoch, OwnedRoomId, OwnedRoomOrAliasId, OwnedServerName, RoomId, UInt,
+    },
+    Client, Room,
+};
+use regex::Regex;
+use tracing::info;
+
+use crate::sync;
+
+/// Options selecting which rooms to act on
+#[derive(clap::Args, Clone, Debug)]
+pub struct FilterArgs {
+    /// Rooms to migrate, by ID or alias (Default: all)
+    #[arg(long = "rooms")]
+    pub rooms: Vec<OwnedRoomOrAliasId>,
+
+    /// Rooms to skip, by ID or alias
+    #[arg(long = "rooms-excluded")]
+    pub rooms_excluded: Vec<OwnedRoomOrAliasId>,
+
+    /// Migrate this space and all rooms below it, by ID or alias
+    #[arg(long = "space")]
+    pub space: Vec<OwnedRoomOrAliasId>,
+
+    /// Migrate rooms whose display name matches this glob pattern, e.g. "Work *"
+    #[arg(long = "rooms-name", value_parser = parse_glob)]
+    pub rooms_name: Vec<Regex>,
+
+    /// Skip rooms whose display name matches this glob pattern
+    #[arg(long = "rooms-excluded-name", value_parser = parse_glob)]
+    pub rooms_excluded_name: Vec<Regex>,
+
+    /// File with rooms to migrate, one ID or alias per line. A `#` on its own starts a comment
+    /// up to the end of the line, also after a room, e.g. `#team:example.org # the old one`.
+    /// `-` reads from stdin
+    #[arg(long = "rooms-file")]
+    pub rooms_file: Option<PathBuf>,
+
+    /// File with rooms to skip, in the same format as `--rooms-file`
+    #[arg(long = "rooms-excluded-file")]
+    pub rooms_excluded_file: Option<PathBuf>,
+
+    /// Migrate rooms whose ID, canonical alias or display name matches this regex
+    #[arg(long = "rooms-regex", value_parser = Regex::new)]
+    pub rooms_regex: Vec<Regex>,
+
+    /// Skip rooms whose ID, canonical alias or display name matches this regex
+    #[arg(long = "rooms-excluded-regex", value_parser = Regex::new)]
+    pub rooms_excluded_regex: Vec<Regex>,
+
+    /// Pick the rooms to act on from an interactive list after filtering
+    #[arg(long = "pick-rooms")]
+    pub pick_rooms: bool,
+
+    /// Only migrate direct messages
+    #[arg(long = "dms-only", conflicts_with_all = ["skip_dms", "spaces_only"])]
+    pub dms_only: bool,
+
+    /// Skip direct messages
+    #[arg(long = "skip-dms")]
+    pub skip_dms: bool,
+
+    /// Only migrate spaces
+    #[arg(long = "spaces-only", conflicts_with = "skip_spaces")]
+    pub spaces_only: bool,
+
+    /// Skip spaces
+    #[arg(long = "skip-spaces")]
+    pub skip_spaces: bool,
+
+    /// Only migrate rooms living on this server, per the room ID or the servers used to route to it
+    #[arg(long = "rooms-on-server")]
+    pub rooms_on_server: Vec<OwnedServerName>,
+
+    /// Skip rooms living on this server, per the room ID or the servers used to route to it
+    #[arg(long = "rooms-not-on-server")]
+    pub rooms_not_on_server: Vec<OwnedServerName>,
+
+    /// Only migrate rooms anyone can join
+    #[arg(long = "public-only", conflicts_with = "private_only")]
+    pub public_only: bool,
+
+    /// Only migrate rooms that aren't public (invite-only, knock, restricted)
+    #[arg(long = "private-only")]
+    pub private_only: bool,
+
+    /// Only migrate end-to-end encrypted rooms
+    #[arg(long = "encrypted-only", conflicts_with = "unencrypted_only")]
+    pub encrypted_only: bool,
+
+    /// Only migrate rooms without end-to-end encryption
+    #[arg(long = "unencrypted-only")]
+    pub unencrypted_only: bool,
+
+    /// Only migrate rooms where the old account has at least this power level
+    #[arg(long = "min-own-power-level", allow_hyphen_values = true)]
+    pub min_own_power_level: Option<i64>,
+
+    /// Only migrate rooms where the old account is admin (power level 100 or more)
+    #[arg(long = "admin-rooms-only", conflicts_with = "min_own_power_level")]
+    pub admin_rooms_only: bool,
+
+    /// Skip rooms with fewer joined members
+    #[arg(long = "min-members")]
+    pub min_members: Option<u64>,
+
+    /// Skip rooms with more joined members
+    #[arg(long = "max-members")]
+    pub max_members: Option<u64>,
+
+    /// Skip rooms without any activity since this duration ago (e.g. `90days`) or date (e.g.
+    /// `2024-01-31`)
+    #[arg(long = "active-since", value_parser = parse_cutoff)]
+    pub active_since: Option<SystemTime>,
+
+    /// `rooms` with aliases resolved to room IDs
+    #[arg(skip)]
+    room_ids: Vec<OwnedRoomId>,
+
+    /// `rooms_excluded` with aliases resolved to room IDs
+    #[arg(skip)]
+    excluded_room_ids: Vec<OwnedRoomId>,
+
+    /// All rooms in the hierarchy of `space`
+    #[arg(skip)]
+    space_room_ids: Vec<OwnedRoomId>,
+}
+
+impl FilterArgs {
+    /// Fails on filters that can't select any room together
+    pub fn check(&self) -> anyhow::Result<()> {
+        if let (Some(min), Some(max)) = (self.min_members, self.max_members) {
+            if min > max {
+                anyhow::bail!("--min-members {min} is above --max-members {max}");
+            }
+        }
+        if let Some(server) = self
+            .rooms_on_server
+            .iter()
+            .find(|s| self.rooms_not_on_server.contains(s))
+        {
+            anyhow::bail!("{server} is given to both --rooms-on-server and --rooms-not-on-server");
+        }
+        if self.active_since.is_some_and(|t| t > SystemTime::now()) {
+            anyhow::bail!("--active-since is in the future, no room can be active since then");
+        }
+        Ok(())
+    }
+
+    /// Reads the room list files and resolves all aliases via the room directory. Has to be
+    /// called before any filtering.
+    pub async fn prepare(&mut self, c: &Client) -> anyhow::Result<()> {
+        if let Some(path) = &self.rooms_file {
+            self.rooms.extend(read_room_list(path)?);
+        }
+        if let Some(path) = &self.rooms_excluded_file {
+            self.rooms_excluded.extend(read_room_list(path)?);
+        }
+
+        self.room_ids = resolve(c, &self.rooms).await?;
+        self.excluded_room_ids = resolve(c, &self.rooms_excluded).await?;
+        if let Some(room_id) = self
+            .room_ids
+            .iter()
+            .find(|r| self.excluded_room_ids.contains(r))
+        {
+            anyhow::bail!("{room_id} is both selected and excluded by the room lists");
+        }
+        for space_id in resolve(c, &self.space).await? {
+            self.space_room_ids
+                .extend(space_hierarchy(c, space_id).await?);
+        }
+        Ok(())
+    }
+
+    /// Whether a room only known by its ID passes the filters.
+    pub fn is_selected(&self, room_id: &RoomId) -> bool {
+        self.matches(room_id, &[room_id.to_string()], None)
+    }
+
+    /// Whether `room_id` may pass the filters, as far as its ID tells. The rooms it rules out
+    /// don't need to be synced.
+    pub fn may_select(&self, room_id: &RoomId) -> bool {
+        if self.excluded_room_ids.iter().any(|r| r == room_id) {
+            return false;
+        }
+        if !self.rooms_regex.is_empty()
+            || !self.rooms_name.is_empty()
+            || (self.rooms.is_empty() && self.space.is_empty())
+        {
+            return true;
+        }
+        self.room_ids.iter().any(|r| r == room_id)
+            || self.space_room_ids.iter().any(|r| r == room_id)
+    }
+
+    /// Whether `room` passes the filters, also matching against its alias and name.
+    pub async fn selects(&self, room: &Room) -> anyhow::Result<bool> {
+        let display_name = room.display_name().await?.to_string();
+        let mut names = vec![room.room_id().to_string(), display_name.clone()];
+        names.extend(room.canonical_alias().map(|a| a.to_string()));
+        if !self.matches(room.room_id(), &names, Some(&display_name)) {
+            return Ok(false);
+        }
+
+        let is_space = room.is_space();
+        if (self.spaces_only && !is_space) || (self.skip_spaces && is_space) {
+            return Ok(false);
+        }
+
+        if !self.rooms_on_server.is_empty() || !self.rooms_not_on_server.is_empty() {
+            let servers = room
+                .room_id()
+                .server_name()
+                .map(ToOwned::to_owned)
+                .into_iter()
+                .chain(room.route().await?)
+                .collect::<Vec<_>>();
+            if (!self.rooms_on_server.is_empty()
+                && !servers.iter().any(|s| self.rooms_on_server.contains(s)))
+                || servers.iter().any(|s| self.rooms_not_on_server.contains(s))
+            {
+                return Ok(false);
+            }
+        }
+
+        let is_public = room.join_rule() == JoinRule::Public;
+        if (self.public_only && !is_public) || (self.private_only && is_public) {
+            return Ok(false);
+        }
+
+        if self.encrypted_only || self.unencrypted_only {
+            let encrypted = room.is_encrypted().await?;
+            if (self.encrypted_only && !encrypted) || (self.unencrypted_only && encrypted) {
+                return Ok(false);
+            }
+        }
+
+        let members = room.joined_members_count();
+        if self.min_members.is_some_and(|min| members < min)
+            || self.max_members.is_some_and(|max| members > max)
+        {
+            return Ok(false);
+        }
+
+        let min_own_power_level = if self.admin_rooms_only {
+            Some(100)
+        } else {
+            self.min_own_power_level
+        };
+        if let Some(min) = min_own_power_level {
+            let own_power_level = sync::member(room, room.own_user_id())
+                .await?
+                .map_or(0, |m| m.power_level());
+            if own_power_level < min {
+                return Ok(false);
+            }
+        }
+
+        if let Some(cutoff) = self.active_since {
+            if last_activity(room).await?.is_none_or(|t| t < cutoff) {
+                return Ok(false);
+            }
+        }
+
+        if self.dms_only || self.skip_dms {
+            let is_dm = is_dm(room).await?;
+            if (self.dms_only && !is_dm) || (self.skip_dms && is_dm) {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// The IDs of the `rooms` passing the filters.
+    pub async fn select(&self, rooms: Vec<Room>) -> anyhow::Result<Vec<OwnedRoomId>> {
+        let mut selected = Vec::new();
+        for room in rooms {
+            if self.selects(&room).await? {
+                selected.push(room);
+            }
+        }
+
+        if self.pick_rooms && !selected.is_empty() {
+            selected = pick(selected).await?;
+        }
+
+        Ok(selected.iter().map(|r| r.room_id().to_owned()).collect())
+    }
+
+    fn matches(&self, room_id: &RoomId, names: &[String], display_name: Option<&str>) -> bool {
+        let matches_any = |regexes: &[Regex]| {
+            regexes
+                .iter()
+                .any(|re| names.iter().any(|n| re.is_match(n)))
+        };
+        let name_matches_any =
+            |globs: &[Regex]| display_name.is_some_and(|n| globs.iter().any(|g| g.is_match(n)));
+
+        if self.excluded_room_ids.iter().any(|r| r == room_id)
+            || matches_any(&self.rooms_excluded_regex)
+            || name_matches_any(&self.rooms_excluded_name)
+        {
+            return false;
+        }
+
+        if self.rooms.is_empty()
+            && self.rooms_regex.is_empty()
+            && self.rooms_name.is_empty()
+            && self.space.is_empty()
+        {
+            return true;
+        }
+
+        self.room_ids.iter().any(|r| r == room_id)
+            || self.space_room_ids.iter().any(|r| r == room_id)
+            || matches_any(&self.rooms_regex)
+            || name_matches_any(&self.rooms_name)
+    }
+}
+
+/// Lets the user tick the rooms to keep.
+async fn pick(rooms: Vec<Room>) -> anyhow::Result<Vec<Room>> {
+    let mut labels = Vec::new();
+    for room in &rooms {
+        let mut label = format!(
+            "{} ({}, {} members)",
+            room.display_name().await?,
+            room.room_id(),
+            room.joined_members_count()
+        );
+        if room.is_space() {
+            label.push_str(" [Space]");
+        } else if is_dm(room).await? {
+            label.push_str(" [DM]");
+        }
+        labels.push(label);
+    }
+
+    let picked = MultiSelect::new()
+        .with_prompt("Rooms to act on (space to toggle, enter to confirm)")
+        .items(&labels)
+        .defaults(&vec![true; labels.len()])
+        .interact()?;
+
+    Ok(rooms
+        .into_iter()
+        .enumerate()
+        .filter(|(i, _)| picked.contains(i))
+        .map(|(_, r)| r)
+        .collect())
+}
+
+/// Whether `room` is marked as DM in `m.direct` or looks like one: an unnamed room with at most
+/// one other member.
+pub async fn is_dm(room: &Room) -> anyhow::Result<bool> {
+    if room.is_direct().await? {
+        return Ok(true);
+    }
+    Ok(!room.is_space() && room.name().is_none() && room.active_members_count() <= 2)
+}
+
+/// Time of the latest event in `room`'s timeline.
+async fn last_activity(room: &Room) -> anyhow::Result<Option<SystemTime>> {
+    let mut options = MessagesOptions::backward();
+    options.limit = UInt::from(1u32);
+    let messages = room.messages(options).await?;
+
+    let Some(latest) = messages.chunk.first() else {
+        return Ok(None);
+    };
+    Ok(latest
+        .event
+        .get_field::<MilliSecondsSinceUnixEpoch>("origin_server_ts")?
+        .and_then(|ts| ts.to_system_time()))
+}
+
+/// Parses either a duration into the past or a date.
+fn parse_cutoff(value: &str) -> Result<SystemTime, String> {
+    if let Ok(duration) = humantime::parse_duration(value) {
+        return SystemTime::now()
+            .checked_sub(duration)
+            .ok_or_else(|| format!("{value} reaches too far into the past"));
+    }
+
+    let timestamp = if value.len() == "YYYY-MM-DD".len() {
+        format!("{value} 00:00:00")
+    } else {
+        value.to_owned()
+    };
+    humantime::parse_rfc3339_weak(&timestamp)
+        .map_err(|e| format!("{value} is neither a duration nor a date: {e}"))
+}
+
+/// Turns a glob pattern with `*` and `?` wildcards into an anchored regex.
+fn parse_glob(glob: &str) -> Result<Regex, regex::Error> {
+    let mut pattern = String::from("^");
+    for c in glob.chars() {
+        match c {
+            '*' => pattern.push_str(".*"),
+            '?' => pattern.push('.'),
+            c => pattern.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    pattern.push('$');
+    Regex::new(&pattern)
+}
+
+fn read_room_list(path: &PathBuf) -> anyhow::Result<Vec<OwnedRoomOrAliasId>> {
+    let mut content = String::new();
+    if path.as_os_str() == "-" {
+        std::io::stdin().read_to_string(&mut content)?;
+    } else {
+        content = std::fs::read_to_string(path)?;
+    }
+
+    let mut rooms = Vec::new();
+    for line in content.lines() {
+        let line = strip_comment(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+        rooms.push(
+            OwnedRoomOrAliasId::try_from(line)
+                .map_err(|e| anyhow::anyhow!("{}: invalid room {line}: {e}", path.display()))?,
+        );
+    }
+    Ok(rooms)
+}
+
+/// Aliases start with `#` too, so only a standalone `#` starts a comment.
+fn strip_comment(line: &str) -> &str {
+    let mut previous = ' ';
+    for (i, c) in line.char_indices() {
+        let next = line[i + c.len_utf8()..].chars().next();
+        if c == '#' && previous.is_whitespace() && next.is_none_or(char::is_whitespace) {
+            return &line[..i];
+        }
+        previous = c;
+    }
+    line
+}
+
+/// The space itself and all rooms below it, as far as the server knows them.
+async fn space_hierarchy(c: &Client, space_id: OwnedRoomId) -> anyhow::Result<Vec<OwnedRoomId>> {
+    let mut rooms = vec![space_id.clone()];
+    let mut from = None;
+    loop {
+        let mut request = get_hierarchy::v1::Request::new(space_id.clone());
+        request.from = from;
+        let response = c.send(request, None).await?;
+
+        rooms.extend(response.rooms.into_iter().map(|r| r.room_id));
+        from = response.next_batch;
+        if from.is_none() {
+            break;
+        }
+    }
+
+    rooms.sort();
+    rooms.dedup();
+    info!("Space {space_id} contains {} rooms", rooms.len());
+    Ok(rooms)
+}
+
+async fn resolve(c: &Client, rooms: &[OwnedRoomOrAliasId]) -> anyhow::Result<Vec<OwnedRoomId>> {
+    let mut room_ids = Vec::new();
+    for room in rooms {
+        match OwnedRoomId::try_from(room.clone()) {
+            Ok(room_id) => room_ids.push(room_id),
+            Err(alias) => {
+                let room_id = c.resolve_room_alias(&alias).await?.room_id;
+                info!("Resolved {alias} to {room_id}");
+                room_ids.push(room_id);
+            }
+        }
+    }
+    Ok(room_ids)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn globs_match_the_whole_name() {
+        let glob = parse_glob("Work *").unwrap();
+        assert!(glob.is_match("Work chat"));
+        assert!(glob.is_match("Work "));
+        assert!(!glob.is_match("Homework chat"));
+        assert!(!glob.is_match("work chat"));
+
+        let glob = parse_glob("team-?").unwrap();
+        assert!(glob.is_match("team-a"));
+        assert!(!glob.is_match("team-ab"));
+    }
+
+    #[test]
+    fn globs_escape_regex_characters() {
+        let glob = parse_glob("C++ (old).*").unwrap();
+        assert!(glob.is_match("C++ (old).txt"));
+        assert!(!glob.is_match("CC (old)xtxt"));
+    }
+
+    #[test]
+    fn cutoffs_are_durations_or_dates() {
+        let cutoff = parse_cutoff("30d").unwrap();
+        let ago = SystemTime::now().duration_since(cutoff).unwrap();
+        assert!(ago >= Duration::from_secs(30 * 24 * 60 * 60));
+        assert!(ago < Duration::from_secs(30 * 24 * 60 * 60 + 60));
+
+        assert_eq!(
+            parse_cutoff("2024-01-01").unwrap(),
+            humantime::parse_rfc3339("2024-01-01T00:00:00Z").unwrap()
+        );
+        assert_eq!(
+            parse_cutoff("2024-01-01 12:30:00").unwrap(),
+            humantime::parse_rfc3339("2024-01-01T12:30:00Z").unwrap()
+        );
+        assert!(parse_cutoff("last week").is_err());
+    }
+
+    #[test]
+    fn comments_start_at_a_standalone_hash() {
+        assert_eq!(strip_comment("# just a comment"), "");
+        assert_eq!(strip_comment("#"), "");
+        assert_eq!(
+            strip_comment("!abc:example.org # the old one"),
+            "!abc:example.org "
+        );
+        assert_eq!(strip_comment("#team:example.org"), "#team:example.org");
+        assert_eq!(
+            strip_comment("#team:example.org #not-a-comment"),
+            "#team:example.org #not-a-comment"
+        );
+        assert_eq!(strip_comment("#team:example.org #"), "#team:example.org ");
+    }
+}
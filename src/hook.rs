@@ -0,0 +1,161 @@
+use std::{
+    io::Write,
+    path::PathBuf,
+    process::{Command, Stdio},
+    sync::Arc,
+};
+
+use matrix_sdk::{
+    ruma::{OwnedRoomId, OwnedUserId, RoomId},
+    Client,
+};
+use serde::Serialize;
+use serde_json::Value;
+use tracing::{info, warn};
+
+use crate::{
+    context,
+    report::{self, RoomReport},
+};
+
+/// The script called at the boundaries of rooms and phases of a run
+pub struct Hook {
+    path: PathBuf,
+    from: OwnedUserId,
+    to: OwnedUserId,
+    dry_run: bool,
+}
+
+/// Events the hook is called with, as JSON on its stdin along with `from`, `to` and `dry_run`
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    PhaseStarted {
+        phase: &'a str,
+        rooms: u64,
+    },
+    PhaseFinished {
+        phase: &'a str,
+        rooms: u64,
+    },
+    /// Before anything is done in the room. A non-zero exit code skips it.
+    RoomStarted {
+        room_id: &'a RoomId,
+        name: Option<&'a str>,
+    },
+    /// At the end of the run, with what was done in the room
+    RoomFinished {
+        room: &'a RoomReport,
+    },
+}
+
+/// Calls the script at `path` for every event of the current run from now on
+pub fn init(
+    path: PathBuf,
+    from: OwnedUserId,
+    to: OwnedUserId,
+    dry_run: bool,
+) -> anyhow::Result<()> {
+    if !path.is_file() {
+        anyhow::bail!("The hook {} doesn't exist", path.display());
+    }
+    let hook = Arc::new(Hook {
+        path,
+        from,
+        to,
+        dry_run,
+    });
+    context::with(|c| *c.hook.lock().unwrap() = Some(hook));
+    Ok(())
+}
+
+/// Stops calling a hook for the events of the current run, e.g. for a merged account without one
+pub fn clear() {
+    context::with(|c| *c.hook.lock().unwrap() = None);
+}
+
+/// The hook of the current run, if one is configured
+fn current() -> Option<Arc<Hook>> {
+    context::with(|c| c.hook.lock().unwrap().clone()).flatten()
+}
+
+/// Runs the hook of the current run with `event` and waits for it to exit. Its exit code, `None`
+/// without a hook.
+pub fn call(event: Event<'_>) -> Option<i32> {
+    current()?.call(event)
+}
+
+impl Hook {
+    fn call(&self, event: Event<'_>) -> Option<i32> {
+        let mut input = match serde_json::to_value(event) {
+            Ok(input) => input,
+            Err(e) => {
+                warn!("Couldn't serialize the hook event: {e}");
+                return None;
+            }
+        };
+        if let Value::Object(fields) = &mut input {
+            fields.insert("from".to_owned(), self.from.as_str().into());
+            fields.insert("to".to_owned(), self.to.as_str().into());
+            fields.insert("dry_run".to_owned(), self.dry_run.into());
+        }
+
+        let run = || {
+            let mut child = Command::new(&self.path).stdin(Stdio::piped()).spawn()?;
+            let stdin = child.stdin.take();
+            if let Some(mut stdin) = stdin {
+                // a hook not reading its input is fine
+                let _ = stdin.write_all(input.to_string().as_bytes());
+            }
+            child.wait()
+        };
+        match run() {
+            Ok(status) => Some(status.code().unwrap_or(-1)),
+            Err(e) => {
+                warn!("Running the hook {} failed: {e}", self.path.display());
+                Some(-1)
+            }
+        }
+    }
+}
+
+/// Calls the hook before each of `rooms`, and skips the ones it exits with a non-zero code for
+pub async fn start_rooms(c: &Client, rooms: &mut Vec<OwnedRoomId>) {
+    let Some(hook) = current() else {
+        return;
+    };
+    let mut started = Vec::with_capacity(rooms.len());
+    for room_id in rooms.drain(..) {
+        let name = c.get_room(&room_id).and_then(|r| r.name());
+        let id = room_id.clone();
+        let hook = hook.clone();
+        let code = tokio::task::spawn_blocking(move || {
+            hook.call(Event::RoomStarted {
+                room_id: &id,
+                name: name.as_deref(),
+            })
+        })
+        .await
+        .unwrap_or(Some(-1));
+        match code {
+            Some(0) | None => started.push(room_id),
+            Some(code) => {
+                info!("Skipping {room_id}, the hook exited with {code}");
+                report::skip(&room_id, format!("skipped by the hook (exit code {code})"));
+            }
+        }
+    }
+    *rooms = started;
+}
+
+/// Calls the hook after each of `rooms`, with what was done in it
+pub async fn finish_rooms(rooms: &[RoomReport]) {
+    let Some(hook) = current() else {
+        return;
+    };
+    for room in rooms {
+        let (hook, room) = (hook.clone(), room.clone());
+        let _ = tokio::task::spawn_blocking(move || hook.call(Event::RoomFinished { room: &room }))
+            .await;
+    }
+}
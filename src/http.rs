@@ -0,0 +1,62 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use matrix_sdk::reqwest::Url;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+use tracing::warn;
+
+/// URL of the endpoint made of `segments` below `base`, each of them percent-encoded. Unlike
+/// `Url::join` with an absolute path, this keeps a path `base` is served under.
+pub fn endpoint(base: &Url, segments: &[&str]) -> anyhow::Result<Url> {
+    let mut url = base.clone();
+    url.path_segments_mut()
+        .map_err(|_| anyhow::anyhow!("Invalid homeserver URL {base}"))?
+        .pop_if_empty()
+        .extend(segments);
+    Ok(url)
+}
+
+/// A response: the status line, the content type and the body
+pub type Response = (&'static str, &'static str, Vec<u8>);
+
+/// Answers every request on `addr` with what `handler` returns for its path, for as long as the
+/// process runs. Just enough HTTP for a status page or a scraper, one request per connection.
+pub async fn serve<F>(addr: SocketAddr, handler: F) -> anyhow::Result<()>
+where
+    F: Fn(&str) -> Response + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind(addr).await?;
+    let handler = Arc::new(handler);
+
+    tokio::spawn(async move {
+        loop {
+            let mut stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    warn!("Accepting a connection on {addr} failed: {e}");
+                    continue;
+                }
+            };
+            let handler = handler.clone();
+            tokio::spawn(async move {
+                let mut request = [0; 1024];
+                let Ok(n) = stream.read(&mut request).await else {
+                    return;
+                };
+                // the request line is `GET /path HTTP/1.1`
+                let request = String::from_utf8_lossy(&request[..n]);
+                let path = request.split_whitespace().nth(1).unwrap_or("/");
+                let (status, content_type, body) = handler(path);
+                let header = format!(
+                    "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                );
+                let _ = stream.write_all(header.as_bytes()).await;
+                let _ = stream.write_all(&body).await;
+            });
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,325 @@
+use std::future::IntoFuture;
+
+use matrix_sdk::{
+    reqwest,
+    room::MessagesOptions,
+    ruma::{OwnedEventId, RoomId, RoomVersionId, TransactionId, UInt},
+    Client, Room,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tracing::{info, warn};
+
+use crate::{auth::Login, export, http::endpoint, limit::Limiter};
+
+/// How many messages go into one MSC2716 batch send
+const BATCH_SIZE: usize = 100;
+
+#[derive(Deserialize)]
+struct BatchSendResponse {
+    next_batch_id: String,
+}
+
+/// The room version to re-create rooms in so their history can be imported with MSC2716 batch
+/// sends, if the homeserver of `to_c` has one, e.g. Synapse with `msc2716_enabled`. Only
+/// appservices may send batches, so the new account has to be logged in with `--to-as-token`.
+pub async fn batch_room_version(
+    to_c: &Client,
+    to_login: &Login,
+) -> anyhow::Result<Option<RoomVersionId>> {
+    if to_login.as_token.is_none() {
+        return Ok(None);
+    }
+    let capabilities = to_c.get_capabilities().await?;
+    Ok(capabilities
+        .room_versions
+        .available
+        .into_keys()
+        .filter(|v| v.as_str().contains("msc2716"))
+        .max_by(|a, b| a.as_str().cmp(b.as_str())))
+}
+
+/// Imports the last `limit` messages of `from_room` into `to_room`, a room the new account just
+/// created in its place. With `batch`, if `to_room` was created in the room version of
+/// [`batch_room_version`], they're inserted into its history with MSC2716 batch sends, dated
+/// before the first message of the new room. Otherwise they're quoted: posted as new events
+/// after a notice, keeping their original timestamps only when logged in through an appservice
+/// and the room isn't encrypted. Either way they're sent by the new account with their original
+/// senders in front, and replies and edits lose what they point to. Returns how many messages
+/// were imported.
+pub async fn import_history(
+    from_room: &Room,
+    to_c: &Client,
+    to_room: &Room,
+    to_login: &Login,
+    limit: usize,
+    batch: bool,
+    limiter: &Limiter,
+) -> anyhow::Result<usize> {
+    let messages = last_messages(from_room, limit).await?;
+    if let (true, Some(as_token)) = (batch, &to_login.as_token) {
+        info!(
+            "Importing {} messages of {} into the history of {}",
+            messages.len(),
+            from_room.room_id(),
+            to_room.room_id()
+        );
+        return batch_send(
+            &to_login.http_client()?,
+            as_token,
+            to_c,
+            to_room,
+            &messages,
+            limiter,
+        )
+        .await;
+    }
+    quote(from_room, to_c, to_room, to_login, &messages, limiter).await
+}
+
+/// Inserts `messages` into the history of `room` with MSC2716 batch sends of the appservice
+/// owning `as_token`. The newest batch goes first, every further one before the previous one.
+async fn batch_send(
+    http: &reqwest::Client,
+    as_token: &str,
+    c: &Client,
+    room: &Room,
+    messages: &[Value],
+    limiter: &Limiter,
+) -> anyhow::Result<usize> {
+    let new_user = c.user_id().unwrap();
+    // the history goes after the events of creating the room
+    let mut options = MessagesOptions::backward();
+    options.limit = UInt::from(1u32);
+    let prev_event_id = room
+        .messages(options)
+        .await?
+        .chunk
+        .first()
+        .map(|e| e.event.get_field::<OwnedEventId>("event_id"))
+        .transpose()?
+        .flatten()
+        .ok_or_else(|| anyhow::anyhow!("{} has no event to import after", room.room_id()))?;
+    let url = endpoint(
+        &c.homeserver(),
+        &[
+            "_matrix",
+            "client",
+            "unstable",
+            "org.matrix.msc2716",
+            "rooms",
+            room.room_id().as_str(),
+            "batch_send",
+        ],
+    )?;
+
+    let ts = |event: &Value| event["origin_server_ts"].as_u64().unwrap_or_default();
+    let mut batch_id = None;
+    let mut imported = 0;
+    for batch in messages.rchunks(BATCH_SIZE) {
+        let events = batch
+            .iter()
+            .map(|event| {
+                json!({
+                    "type": "m.room.message",
+                    "sender": new_user,
+                    "origin_server_ts": ts(event),
+                    "content": quoted(event),
+                })
+            })
+            .collect::<Vec<_>>();
+        // the sender has to be a member at the start of the batch
+        let body = json!({
+            "state_events_at_start": [{
+                "type": "m.room.member",
+                "sender": new_user,
+                "state_key": new_user,
+                "origin_server_ts": ts(&batch[0]),
+                "content": { "membership": "join" },
+            }],
+            "events": events,
+        });
+        let mut query = vec![
+            ("prev_event_id", prev_event_id.to_string()),
+            ("user_id", new_user.to_string()),
+        ];
+        query.extend(batch_id.map(|id| ("batch_id", id)));
+
+        let _permit = limiter.acquire(&c.homeserver()).await;
+        let response: BatchSendResponse = http
+            .post(url.clone())
+            .bearer_auth(as_token)
+            .query(&query)
+            .json(&body)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        batch_id = Some(response.next_batch_id);
+        imported += batch.len();
+    }
+    Ok(imported)
+}
+
+/// Posts `messages` in `to_room` quoted, between two notices, where the history can't be
+/// imported
+async fn quote(
+    from_room: &Room,
+    to_c: &Client,
+    to_room: &Room,
+    to_login: &Login,
+    messages: &[Value],
+    limiter: &Limiter,
+) -> anyhow::Result<usize> {
+    info!(
+        "Quoting {} messages of {} in {}",
+        messages.len(),
+        from_room.room_id(),
+        to_room.room_id()
+    );
+
+    // appservices may set the timestamp of the events they send, but can't encrypt them
+    let appservice = match &to_login.as_token {
+        Some(as_token) if !to_room.is_encrypted().await? => {
+            Some((to_login.http_client()?, as_token.as_str()))
+        }
+        _ => None,
+    };
+
+    notice(
+        to_c,
+        to_room,
+        limiter,
+        &format!(
+            "The last {} messages of {}, quoted:",
+            messages.len(),
+            from_room.room_id()
+        ),
+    )
+    .await?;
+    let mut quoted_count = 0;
+    for event in messages {
+        let content = quoted(event);
+        let sent = match &appservice {
+            Some((http, as_token)) => {
+                let ts = event["origin_server_ts"].as_u64().unwrap_or_default();
+                let _permit = limiter.acquire(&to_c.homeserver()).await;
+                send_at(http, as_token, to_c, to_room.room_id(), &content, ts).await
+            }
+            None => limiter
+                .run(to_c, || {
+                    to_room
+                        .send_raw("m.room.message", content.clone())
+                        .into_future()
+                })
+                .await
+                .map(|_| ())
+                .map_err(anyhow::Error::from),
+        };
+        match sent {
+            Ok(()) => quoted_count += 1,
+            Err(e) => warn!(
+                "Couldn't quote {} in {}: {e}",
+                event["event_id"].as_str().unwrap_or_default(),
+                to_room.room_id()
+            ),
+        }
+    }
+    notice(to_c, to_room, limiter, "End of the quoted messages").await?;
+    Ok(quoted_count)
+}
+
+/// The last `limit` messages with a body of the visible history of `room`, oldest first
+async fn last_messages(room: &Room, limit: usize) -> anyhow::Result<Vec<Value>> {
+    let mut messages = Vec::new();
+    let mut options = MessagesOptions::backward();
+    while messages.len() < limit {
+        let response = room.messages(options).await?;
+        let exhausted = response.chunk.is_empty();
+        messages.extend(
+            response
+                .chunk
+                .into_iter()
+                .filter_map(|e| e.event.deserialize_as::<Value>().ok())
+                .filter(|e| e["type"] == "m.room.message" && e["content"]["body"].is_string()),
+        );
+        match response.end {
+            Some(end) if !exhausted => options = MessagesOptions::backward().from(end.as_str()),
+            _ => break,
+        }
+    }
+    messages.truncate(limit);
+    messages.reverse();
+    Ok(messages)
+}
+
+/// The content of `event` with its sender in front of the message
+fn quoted(event: &Value) -> Value {
+    let sender = event["sender"].as_str().unwrap_or_default();
+    let mut content = event["content"].clone();
+    let body = content["body"].as_str().unwrap_or_default();
+    content["body"] = format!("{sender}: {body}").into();
+    if let Some(formatted) = content["formatted_body"].as_str() {
+        content["formatted_body"] =
+            format!("<b>{}</b>: {formatted}", export::escape(sender)).into();
+    }
+    // replies and edits point to events of the old room
+    if let Value::Object(fields) = &mut content {
+        fields.remove("m.relates_to");
+        fields.remove("m.new_content");
+    }
+    content["org.matrix-migrate.imported"] = json!({
+        "event_id": event["event_id"],
+        "sender": sender,
+        "origin_server_ts": event["origin_server_ts"],
+    });
+    content
+}
+
+async fn notice(c: &Client, room: &Room, limiter: &Limiter, body: &str) -> anyhow::Result<()> {
+    let content = json!({ "msgtype": "m.notice", "body": body });
+    limiter
+        .run(c, || {
+            room.send_raw("m.room.message", content.clone())
+                .into_future()
+        })
+        .await?;
+    Ok(())
+}
+
+/// Sends `content` to `room_id` as the user of the appservice owning `as_token`, dated `ts`
+async fn send_at(
+    http: &reqwest::Client,
+    as_token: &str,
+    c: &Client,
+    room_id: &RoomId,
+    content: &Value,
+    ts: u64,
+) -> anyhow::Result<()> {
+    let txn_id = TransactionId::new();
+    let url = endpoint(
+        &c.homeserver(),
+        &[
+            "_matrix",
+            "client",
+            "v3",
+            "rooms",
+            room_id.as_str(),
+            "send",
+            "m.room.message",
+            txn_id.as_str(),
+        ],
+    )?;
+    http.put(url)
+        .bearer_auth(as_token)
+        .query(&[
+            ("user_id", c.user_id().unwrap().to_string()),
+            ("ts", ts.to_string()),
+        ])
+        .json(content)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
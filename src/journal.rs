@@ -0,0 +1,150 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, Write},
+    path::Path,
+    time::SystemTime,
+};
+
+use matrix_sdk::ruma::{OwnedRoomId, OwnedUserId, RoomId, UserId};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::{context, report::Action};
+
+/// The open journal of a run and its accounts
+pub struct Journal {
+    file: File,
+    from: OwnedUserId,
+    to: OwnedUserId,
+}
+
+/// An action taken for a room, one JSON line of the journal
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Entry {
+    pub at: String,
+    pub from: OwnedUserId,
+    pub to: OwnedUserId,
+    pub room_id: OwnedRoomId,
+    pub action: Action,
+    /// The account whose power level changed, when it isn't the new account
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<OwnedUserId>,
+    /// Power level of the new account, or of `user`, before it was raised
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous_power_level: Option<i64>,
+    /// The DM or room the new account created in place of the room
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_room: Option<OwnedRoomId>,
+}
+
+/// Starts appending the actions of the current run, migrating `from` to `to`, to the journal at
+/// `path`
+pub fn open(path: &Path, from: &UserId, to: &UserId) -> anyhow::Result<()> {
+    let file = File::options()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| anyhow::anyhow!("Can't open the journal {}: {e}", path.display()))?;
+    let journal = Journal {
+        file,
+        from: from.to_owned(),
+        to: to.to_owned(),
+    };
+    context::with(|c| *c.journal.lock().unwrap() = Some(journal));
+    Ok(())
+}
+
+fn append(
+    room_id: &RoomId,
+    action: Action,
+    user: Option<OwnedUserId>,
+    previous_power_level: Option<i64>,
+    created_room: Option<OwnedRoomId>,
+) {
+    context::with(|c| {
+        let mut journal = c.journal.lock().unwrap();
+        let Some(journal) = journal.as_mut() else {
+            return;
+        };
+        let entry = Entry {
+            at: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+            from: journal.from.clone(),
+            to: journal.to.clone(),
+            room_id: room_id.to_owned(),
+            action,
+            user,
+            previous_power_level,
+            created_room,
+        };
+        let written = serde_json::to_string(&entry)
+            .map_err(std::io::Error::from)
+            .and_then(|line| writeln!(journal.file, "{line}"));
+        if let Err(e) = written {
+            warn!("Couldn't write to the journal: {e}");
+        }
+    });
+}
+
+/// Records `action` for `room_id`
+pub fn record(room_id: &RoomId, action: Action) {
+    append(room_id, action, None, None, None);
+}
+
+/// Records raising the new account's power level in `room_id` from `previous`
+pub fn record_power_level(room_id: &RoomId, previous: i64) {
+    append(room_id, Action::PowerLevel, None, Some(previous), None);
+}
+
+/// Records changing the power level of `user`, another account than the new one, in `room_id`
+/// from `previous`
+pub fn record_power_level_of(room_id: &RoomId, user: &UserId, previous: i64) {
+    append(
+        room_id,
+        Action::PowerLevel,
+        Some(user.to_owned()),
+        Some(previous),
+        None,
+    );
+}
+
+/// Records the DM `created_room` created in place of `room_id`
+pub fn record_dm(room_id: &RoomId, created_room: &RoomId) {
+    append(
+        room_id,
+        Action::DmRecreated,
+        None,
+        None,
+        Some(created_room.to_owned()),
+    );
+}
+
+/// Records the room `created_room` created in place of `room_id`
+pub fn record_recreated(room_id: &RoomId, created_room: &RoomId) {
+    append(
+        room_id,
+        Action::Recreated,
+        None,
+        None,
+        Some(created_room.to_owned()),
+    );
+}
+
+/// The entries of the journal at `path` belonging to the migration of `from` to `to`
+pub fn read(path: &Path, from: &UserId, to: &UserId) -> anyhow::Result<Vec<Entry>> {
+    let file = File::open(path)
+        .map_err(|e| anyhow::anyhow!("Can't open the journal {}: {e}", path.display()))?;
+    let mut entries = Vec::new();
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry: Entry = serde_json::from_str(&line).map_err(|e| {
+            anyhow::anyhow!("Invalid journal {} line {}: {e}", path.display(), i + 1)
+        })?;
+        if entry.from == from && entry.to == to {
+            entries.push(entry);
+        }
+    }
+    Ok(entries)
+}
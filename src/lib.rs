@@ -0,0 +1,4197 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ffi::OsString,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::Arc,
+    time::Duration,
+};
+
+use clap::{parser::ValueSource, CommandFactory, FromArgMatches, Parser, Subcommand};
+use futures::{
+    future::{join_all, try_join_all},
+    try_join,
+};
+use matrix_sdk::{
+    deserialized_responses::SyncOrStrippedState,
+    ruma::{
+        api::client::{
+            alias::create_alias,
+            filter::{FilterDefinition, RoomEventFilter, RoomFilter},
+            knock::knock_room,
+            room::{
+                aliases,
+                create_room::{self, v3::RoomPreset},
+            },
+            state::{get_state_events_for_key, send_state_event},
+            sync::sync_events,
+        },
+        events::EmptyStateKey,
+        events::{
+            room::{
+                avatar::RoomAvatarEventContent,
+                canonical_alias::RoomCanonicalAliasEventContent,
+                encryption::RoomEncryptionEventContent,
+                join_rules::{AllowRule, JoinRule, RoomJoinRulesEventContent},
+                member::{MembershipState, RoomMemberEventContent},
+                message::RoomMessageEventContent,
+                power_levels::RoomPowerLevelsEventContent,
+                server_acl::RoomServerAclEventContent,
+                tombstone::RoomTombstoneEventContent,
+            },
+            AnyStrippedStateEvent, InitialStateEvent, StateEventType, SyncStateEvent,
+        },
+        int,
+        room::RoomType,
+        serde::Raw,
+        Int, OwnedRoomId, OwnedRoomOrAliasId, OwnedServerName, OwnedUserId, RoomAliasId, RoomId,
+        ServerName, UserId,
+    },
+    Client, Room, RoomMemberships, RoomState,
+};
+use serde_json::json;
+use tracing::{info, warn, Instrument};
+
+use crate::{
+    admin::SynapseAdmin,
+    auth::Login,
+    checkpoint::{Checkpoint, Step},
+    context::Context,
+    error::MatrixError,
+    export::ExportFormat,
+    filter::FilterArgs,
+    http::endpoint,
+    limit::{Limiter, RetryPolicy},
+    plan::Plan,
+    progress::LogFormat,
+    report::{Action, Failure, OnError, OutputFormat, Reason},
+    sync::Syncer,
+    webhook::Event,
+};
+pub use crate::{
+    audit::RoomAudit,
+    check::CheckReport,
+    plan::{Plan as MigrationPlan, RoomPlan},
+    report::{Report as MigrationReport, EXIT_ABORTED},
+};
+
+mod admin;
+mod audit;
+mod auth;
+mod bulk;
+mod check;
+mod checkpoint;
+mod config;
+mod context;
+mod error;
+mod export;
+mod filter;
+mod hook;
+mod http;
+mod import;
+mod journal;
+mod limit;
+mod media;
+mod merge;
+mod metrics;
+mod plan;
+mod preflight;
+mod progress;
+mod pushers;
+mod quirks;
+mod register;
+mod report;
+mod rollback;
+mod spaces;
+mod status;
+mod sync;
+mod tui;
+mod uia;
+mod web;
+mod webhook;
+mod widgets;
+
+/// Fast migration of one matrix account to another
+#[derive(Parser, Debug)]
+#[command(
+    author,
+    version,
+    about,
+    long_about = None,
+    subcommand_negates_reqs = true
+)]
+pub struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// TOML file with options, keyed by their long names. Options given on the command line
+    /// take precedence
+    #[arg(long, env = "CONFIG", global = true)]
+    config: Option<PathBuf>,
+
+    /// Profile of the config file whose options override the others, e.g. `work` for the
+    /// `[profile.work]` table
+    #[arg(long, env = "PROFILE", requires = "config", global = true)]
+    profile: Option<String>,
+
+    /// The accounts of the `[[source]]` tables of the config, merged into the new account
+    #[arg(skip)]
+    sources: Vec<Args>,
+
+    /// Simulate a migration. Logs in and syncs, but does not perform any actual actions
+    #[arg(long = "dry-run", global = true)]
+    dryrun: bool,
+
+    /// How to print the result of the run. `json` prints the per-room report on stdout
+    #[arg(long, env = "OUTPUT", value_enum, default_value_t, global = true)]
+    output: OutputFormat,
+
+    /// File every action of a migration is appended to, for rolling it back later on
+    #[arg(
+        long,
+        env = "JOURNAL",
+        default_value = "matrix-migrate-journal.jsonl",
+        global = true
+    )]
+    journal: PathBuf,
+
+    /// File recording the progress of the migration after every action
+    #[arg(
+        long,
+        env = "STATE_FILE",
+        default_value = "matrix-migrate-state.json",
+        global = true
+    )]
+    state_file: PathBuf,
+
+    /// Custom logging info, overriding `-v` and `-q`
+    #[arg(long, env = "RUST_LOG", global = true)]
+    log: Option<String>,
+
+    /// Log more details, `-vv` for even more
+    #[arg(short, long, action = clap::ArgAction::Count, conflicts_with = "quiet", global = true)]
+    verbose: u8,
+
+    /// Only log warnings and hide the progress bars, `-qq` for errors only
+    #[arg(short, long, action = clap::ArgAction::Count, global = true)]
+    quiet: u8,
+
+    /// Format of the log lines. `json` adds the room and phase as fields
+    #[arg(long, env = "LOG_FORMAT", value_enum, default_value_t, global = true)]
+    log_format: LogFormat,
+
+    #[command(flatten)]
+    connection: ConnectionArgs,
+
+    /// The options of a migration without a subcommand, see [`Args::migrate`]
+    #[command(flatten)]
+    migrate: MigrateArgs,
+}
+
+/// How to log in to both accounts and talk to their homeservers, shared by every subcommand
+#[derive(clap::Args, Debug)]
+struct ConnectionArgs {
+    /// Username of the account to migrate from
+    #[arg(long = "from", env = "FROM_USER", global = true)]
+    from_user: Option<OwnedUserId>,
+
+    /// Password of the account to migrate from. Prompted for if missing on a terminal
+    #[arg(long = "from-pw", env = "FROM_PASSWORD", global = true)]
+    from_user_password: Option<String>,
+
+    /// File containing the password of the account to migrate from
+    #[arg(
+        long = "from-pw-file",
+        env = "FROM_PASSWORD_FILE",
+        conflicts_with = "from_user_password",
+        global = true
+    )]
+    from_password_file: Option<PathBuf>,
+
+    /// Existing access token of the account to migrate from, instead of logging in
+    #[arg(
+        long = "from-token",
+        env = "FROM_TOKEN",
+        conflicts_with = "from_browser",
+        global = true
+    )]
+    from_token: Option<String>,
+
+    /// `as_token` of an appservice whose namespace covers the account to migrate from, to log in
+    /// without its password
+    #[arg(
+        long = "from-as-token",
+        env = "FROM_AS_TOKEN",
+        requires = "from_user",
+        conflicts_with_all = ["from_browser", "from_token"],
+        global = true
+    )]
+    from_as_token: Option<String>,
+
+    /// JSON file with the session of a logged-in client (`user_id`, `device_id`, `access_token`
+    /// and optionally `homeserver`, or Element's `mx_*` local storage keys), instead of logging in
+    #[arg(
+        long = "from-session",
+        env = "FROM_SESSION",
+        conflicts_with_all = ["from_browser", "from_token"],
+        global = true
+    )]
+    from_session: Option<PathBuf>,
+
+    /// Custom homeserver, if not defined discovery is used
+    #[arg(long, env = "FROM_HOMESERVER", global = true)]
+    from_homeserver: Option<OwnedServerName>,
+
+    /// Login via sso instead of username & password
+    #[arg(
+        long = "from-sso",
+        env = "FROM_SSO",
+        group = "from_browser",
+        global = true
+    )]
+    from_sso: bool,
+
+    /// Login via OIDC, for homeservers using matrix-authentication-service
+    #[arg(
+        long = "from-oidc",
+        env = "FROM_OIDC",
+        group = "from_browser",
+        global = true
+    )]
+    from_oidc: bool,
+
+    /// Username of the given account to migrate to
+    #[arg(long = "to", env = "TO_USER", global = true)]
+    to_user: Option<OwnedUserId>,
+
+    /// Password of the account to migrate to. Prompted for if missing on a terminal
+    #[arg(long = "to-pw", env = "TO_PASSWORD", global = true)]
+    to_user_password: Option<String>,
+
+    /// File containing the password of the account to migrate to
+    #[arg(
+        long = "to-pw-file",
+        env = "TO_PASSWORD_FILE",
+        conflicts_with = "to_user_password",
+        global = true
+    )]
+    to_password_file: Option<PathBuf>,
+
+    /// Existing access token of the account to migrate to, instead of logging in
+    #[arg(
+        long = "to-token",
+        env = "TO_TOKEN",
+        conflicts_with = "to_browser",
+        global = true
+    )]
+    to_token: Option<String>,
+
+    /// `as_token` of an appservice whose namespace covers the account to migrate to, to log in
+    /// without its password
+    #[arg(
+        long = "to-as-token",
+        env = "TO_AS_TOKEN",
+        requires = "to_user",
+        conflicts_with_all = ["to_browser", "to_token"],
+        global = true
+    )]
+    to_as_token: Option<String>,
+
+    /// Access token of a Synapse admin on the homeserver to migrate from, to log in as the users
+    /// of `bulk` without their passwords. The rooms of the old account are listed through the
+    /// admin API then, and only the ones the filters may select are synced
+    #[arg(long = "from-admin-token", env = "FROM_ADMIN_TOKEN", global = true)]
+    from_admin_token: Option<String>,
+
+    /// Access token of a Synapse admin on the homeserver to migrate to. The new account is then
+    /// joined to the rooms directly, falling back to inviting it where that isn't possible
+    #[arg(long = "to-admin-token", env = "TO_ADMIN_TOKEN", global = true)]
+    to_admin_token: Option<String>,
+
+    /// Custom homeserver, if not defined discovery is used
+    #[arg(long, env = "TO_HOMESERVER", global = true)]
+    to_homeserver: Option<OwnedServerName>,
+
+    /// Login via sso instead of username & password
+    #[arg(long = "to-sso", env = "TO_SSO", group = "to_browser", global = true)]
+    to_sso: bool,
+
+    /// Login via OIDC, for homeservers using matrix-authentication-service
+    #[arg(long = "to-oidc", env = "TO_OIDC", group = "to_browser", global = true)]
+    to_oidc: bool,
+
+    /// Use sliding sync instead of a full initial sync, which is much faster for large accounts.
+    /// Needs a homeserver or proxy supporting it
+    #[arg(long, env = "SLIDING_SYNC", global = true)]
+    sliding_sync: bool,
+
+    /// Custom timeout for syncing
+    #[arg(long, env = "TIMEOUT", default_value = "60", global = true)]
+    timeout: u64,
+
+    /// How many invites, joins and power level updates to run at once
+    #[arg(long, env = "CONCURRENCY", default_value = "4", global = true)]
+    concurrency: usize,
+
+    /// How many invites, joins and power level updates to start per second and homeserver at
+    /// most. 0 disables the limit
+    #[arg(long, env = "REQUESTS_PER_SECOND", default_value = "2", global = true)]
+    requests_per_second: f64,
+
+    /// Requests per second for the homeserver to migrate from, overriding
+    /// `--requests-per-second`
+    #[arg(long, env = "FROM_REQUESTS_PER_SECOND", global = true)]
+    from_requests_per_second: Option<f64>,
+
+    /// Requests per second for the homeserver to migrate to, overriding `--requests-per-second`
+    #[arg(long, env = "TO_REQUESTS_PER_SECOND", global = true)]
+    to_requests_per_second: Option<f64>,
+
+    /// Don't adapt to the homeserver implementations, e.g. with the lower rate of matrix.org or
+    /// without sliding sync on Conduit
+    #[arg(long, env = "NO_QUIRKS", global = true)]
+    no_quirks: bool,
+
+    /// How many requests to a homeserver may start at once after a quiet period
+    #[arg(long, env = "BURST", default_value = "5", global = true)]
+    burst: usize,
+
+    /// How long to wait for a response before giving up on a request, e.g. `30s`
+    #[arg(long, env = "HTTP_TIMEOUT", default_value = "30s", value_parser = humantime::parse_duration, global = true)]
+    http_timeout: Duration,
+
+    /// How often to retry requests failing with a network or server error
+    #[arg(long, env = "RETRIES", default_value = "3", global = true)]
+    retries: usize,
+
+    /// Pause before the first retry, doubled for every further one
+    #[arg(long, env = "RETRY_BACKOFF", default_value = "1s", value_parser = humantime::parse_duration, global = true)]
+    retry_backoff: Duration,
+
+    /// HTTP or SOCKS proxy for both homeservers, e.g. `socks5h://127.0.0.1:9050` for Tor
+    #[arg(long, env = "PROXY", global = true)]
+    proxy: Option<String>,
+
+    /// Proxy for the homeserver to migrate from, overriding `--proxy`
+    #[arg(long, env = "FROM_PROXY", global = true)]
+    from_proxy: Option<String>,
+
+    /// Proxy for the homeserver to migrate to, overriding `--proxy`
+    #[arg(long, env = "TO_PROXY", global = true)]
+    to_proxy: Option<String>,
+
+    /// PEM file with certificate authorities to trust for the homeserver to migrate from
+    #[arg(long, env = "FROM_CA_CERT", global = true)]
+    from_ca_cert: Vec<PathBuf>,
+
+    /// PEM file with certificate authorities to trust for the homeserver to migrate to
+    #[arg(long, env = "TO_CA_CERT", global = true)]
+    to_ca_cert: Vec<PathBuf>,
+
+    /// Don't verify the TLS certificate of the homeserver to migrate from
+    #[arg(long, env = "FROM_INSECURE_TLS", global = true)]
+    from_insecure_tls: bool,
+
+    /// Don't verify the TLS certificate of the homeserver to migrate to
+    #[arg(long, env = "TO_INSECURE_TLS", global = true)]
+    to_insecure_tls: bool,
+
+    /// Display name of the devices the tool logs in with
+    #[arg(
+        long,
+        env = "DEVICE_NAME",
+        default_value = "matrix-migrate",
+        global = true
+    )]
+    device_name: String,
+
+    /// Keep both sessions instead of logging out at the end
+    #[arg(long, env = "NO_LOGOUT", global = true)]
+    no_logout: bool,
+
+    /// Directory to keep both sessions and their synced state in, so repeated runs reuse the
+    /// same devices instead of logging in again and continue syncing where the last run stopped
+    #[arg(long, env = "SESSION_STORE", global = true)]
+    session_store: Option<PathBuf>,
+
+    /// Wipe the session store before starting, logging in and syncing from scratch
+    #[arg(long, env = "FRESH", requires = "session_store", global = true)]
+    fresh: bool,
+
+    /// Passphrase encrypting the session store
+    #[arg(
+        long,
+        env = "STORE_PASSPHRASE",
+        requires = "session_store",
+        global = true
+    )]
+    store_passphrase: Option<String>,
+}
+
+/// The options of a migration, given to `apply`, `plan`, `verify` and `bulk`, or without a
+/// subcommand
+#[derive(clap::Args, Debug)]
+struct MigrateArgs {
+    /// URL to POST JSON events to when the run starts and finishes, a phase completes or a room
+    /// fails
+    #[arg(long, env = "WEBHOOK_URL")]
+    webhook_url: Option<reqwest::Url>,
+
+    /// Script to run with a JSON event on stdin at the start and end of every phase, before
+    /// every room (a non-zero exit code skips the room) and for every room at the end
+    #[arg(long, env = "HOOK")]
+    hook: Option<PathBuf>,
+
+    /// Also write the report as CSV to this file, one line per room
+    #[arg(long, env = "REPORT_CSV")]
+    report_csv: Option<PathBuf>,
+
+    /// Address to serve Prometheus metrics on, e.g. `0.0.0.0:9090`
+    #[arg(long, env = "METRICS_LISTEN")]
+    metrics_listen: Option<SocketAddr>,
+
+    /// Address to serve a status page of the run on, e.g. `127.0.0.1:8080`, with the rooms,
+    /// progress, failures and the report to download once written
+    #[arg(long, env = "WEB_UI")]
+    web_ui: Option<SocketAddr>,
+
+    /// Post the report to a private "Migration report" room of the new account
+    #[arg(long, env = "REPORT_ROOM")]
+    report_room: bool,
+
+    /// File to write the per-room report of the run to
+    #[arg(long, env = "REPORT_FILE", default_value = "migration-report.json")]
+    report_file: PathBuf,
+
+    /// Register the account to migrate to with its password if it doesn't exist yet
+    #[arg(
+        long,
+        env = "REGISTER_TO",
+        requires = "to_user",
+        conflicts_with_all = ["to_browser", "to_token", "to_as_token"]
+    )]
+    register_to: bool,
+
+    /// Registration token the homeserver to migrate to asks for when registering
+    #[arg(long, env = "REGISTRATION_TOKEN", requires = "register_to")]
+    registration_token: Option<String>,
+
+    /// Email address to verify when the homeserver to migrate to asks for one when registering
+    #[arg(long, env = "REGISTER_EMAIL", requires = "register_to")]
+    register_email: Option<String>,
+
+    /// Pick up an interrupted migration where the state file says it left off
+    #[arg(long, env = "RESUME")]
+    resume: bool,
+
+    /// How often to retry invites failing for a passing reason (rate limits, federation errors)
+    /// later in the run, after the other rooms are done
+    #[arg(long, env = "INVITE_RETRIES", default_value = "3")]
+    invite_retries: u32,
+
+    /// What to do when an action for a room fails: record it and go on with the other rooms, or
+    /// stop the run
+    #[arg(long = "on-error", env = "ON_ERROR", value_enum, default_value_t)]
+    on_error: OnError,
+
+    #[command(flatten)]
+    filters: FilterArgs,
+
+    /// Also migrate rooms managed by a bridge. Inviting a second account usually breaks bridging
+    #[arg(long = "include-bridged")]
+    include_bridged: bool,
+
+    /// Create fresh DMs from the new account instead of inviting it into existing 1:1 DMs
+    #[arg(long = "recreate-dms")]
+    recreate_dms: bool,
+
+    /// Re-create the selected rooms the old account administers from the new account instead of
+    /// inviting it, and point the old rooms to the new ones
+    #[arg(long = "recreate", env = "RECREATE")]
+    recreate: bool,
+
+    /// Import the last messages of re-created rooms into the new rooms, sent by the new account
+    /// with the original senders in front. With `--to-as-token` and a homeserver supporting
+    /// MSC2716 batch sends, they're inserted into the history of the new rooms. Otherwise, and in
+    /// encrypted rooms, they're quoted after the creation of the room
+    #[arg(long = "import-history", env = "IMPORT_HISTORY", requires = "recreate")]
+    import_history: bool,
+
+    /// How many of the last messages of each re-created room `--import-history` imports
+    #[arg(
+        long = "import-history-limit",
+        env = "IMPORT_HISTORY_LIMIT",
+        requires = "import_history",
+        default_value_t = 100
+    )]
+    import_history_limit: usize,
+
+    /// Message to post in each re-created DM. `{old_user}` is replaced with the old account's MXID
+    #[arg(
+        long = "dm-handoff-message",
+        requires = "recreate_dms",
+        num_args = 0..=1,
+        default_missing_value = "Hi! This is my new account, I'm moving here from {old_user}."
+    )]
+    dm_handoff_message: Option<String>,
+
+    /// Unban the new account where it is banned and the old account has the power to do so
+    #[arg(long = "unban")]
+    unban: bool,
+
+    /// TOML file with per-room options overriding the global flags
+    #[arg(long = "plan")]
+    plan: Option<PathBuf>,
+
+    /// Don't ask for confirmation before re-creating or leaving rooms or deactivating the old
+    /// account
+    #[arg(short, long, env = "YES")]
+    yes: bool,
+
+    /// Remove old account from rooms when migration was successful
+    #[arg(long = "leave-rooms")]
+    leave_rooms: bool,
+
+    /// Message the old account posts in each room right before leaving. `{new_user}` is replaced
+    /// with the new account's MXID
+    #[arg(
+        long = "leave-message",
+        requires = "leave_rooms",
+        num_args = 0..=1,
+        default_missing_value = "This account has moved to {new_user}"
+    )]
+    leave_message: Option<String>,
+
+    /// Power level to grant the new account instead of the old account's, e.g. 50 for
+    /// moderator. Capped at the old account's own level, per-room levels of the plan go first
+    #[arg(
+        long,
+        env = "TARGET_POWER_LEVEL",
+        allow_negative_numbers = true,
+        value_parser = clap::value_parser!(i64).range(plan::POWER_LEVELS)
+    )]
+    target_power_level: Option<i64>,
+
+    /// Highest power level to grant the new account, however high the old account's or the
+    /// plan's is
+    #[arg(
+        long,
+        env = "MAX_POWER_LEVEL",
+        allow_negative_numbers = true,
+        value_parser = clap::value_parser!(i64).range(plan::POWER_LEVELS)
+    )]
+    max_power_level: Option<i64>,
+
+    /// Drop the old account's power level to the room's default right before leaving, so it
+    /// doesn't linger in the power levels of the rooms
+    #[arg(long = "demote-old", env = "DEMOTE_OLD")]
+    demote_old: bool,
+
+    /// What to do with rooms where the new account's power level is below the old account's
+    #[arg(
+        long = "leave-policy",
+        env = "LEAVE_POLICY",
+        value_enum,
+        default_value = "skip"
+    )]
+    leave_policy: LeavePolicy,
+
+    /// Copy avatars hosted on the old homeserver to the new one and point the new account and
+    /// rooms at the copies
+    #[arg(long = "reupload-media")]
+    reupload_media: bool,
+
+    /// Copy the old account's emote and sticker packs, re-uploading their images
+    #[arg(long = "migrate-emotes")]
+    migrate_emotes: bool,
+
+    /// Copy the old account's widgets, like its sticker picker, re-uploading their images
+    #[arg(long = "migrate-widgets")]
+    migrate_widgets: bool,
+
+    /// Give the new account the display name the old one uses in a room, where it differs from
+    /// its global display name
+    #[arg(long = "room-display-names")]
+    room_display_names: bool,
+
+    /// Point re-created spaces and the rooms restricted to their members at each other, and
+    /// check the new account can manage every room of the spaces the old one administers
+    #[arg(long = "hand-over-spaces")]
+    hand_over_spaces: bool,
+
+    /// Re-create aliases the old homeserver holds for migrated rooms on the new homeserver
+    #[arg(long = "republish-aliases")]
+    republish_aliases: bool,
+
+    /// Point the canonical alias of migrated rooms at the re-published aliases
+    #[arg(long = "update-canonical-alias", requires = "republish_aliases")]
+    update_canonical_alias: bool,
+
+    /// Forward rooms the old account is only invited to
+    #[arg(long = "forward-invites", value_enum)]
+    forward_invites: Option<InviteForwarding>,
+
+    /// Knock with the new account on rooms the old account has pending knocks in
+    #[arg(long = "migrate-knocks")]
+    migrate_knocks: bool,
+
+    /// Rename the old account to point at the new one. `{name}` is replaced with the old display
+    /// name, `{new_user}` with the new account's MXID
+    #[arg(
+        long = "redirect-profile",
+        num_args = 0..=1,
+        default_missing_value = "{name} (moved to {new_user})"
+    )]
+    redirect_profile: Option<String>,
+
+    /// What to do with the old account's avatar when redirecting its profile
+    #[arg(
+        long = "redirect-avatar",
+        value_enum,
+        default_value = "keep",
+        requires = "redirect_profile"
+    )]
+    redirect_avatar: AvatarRedirect,
+
+    /// Keep running after the migration and mirror rooms the old account joins later onto the
+    /// new one, for migrating gradually. Doesn't go with `--pick-rooms`, which would ask about
+    /// the new rooms of every round
+    #[arg(long, env = "WATCH", conflicts_with_all = ["deactivate_old", "pick_rooms"])]
+    watch: bool,
+
+    /// How often to look for rooms the old account joined with `--watch`, e.g. `5m`
+    #[arg(long, env = "WATCH_INTERVAL", default_value = "1m", value_parser = humantime::parse_duration)]
+    watch_interval: Duration,
+
+    /// Deactivate the old account once every migrated room has been verified. With
+    /// `--from-admin-token`, through the admin API, which removes it from all remaining rooms
+    #[arg(long = "deactivate-old")]
+    deactivate_old: bool,
+
+    /// Ask the old homeserver to also erase the old account's messages when deactivating
+    #[arg(long = "erase", requires = "deactivate_old")]
+    erase: bool,
+
+    /// Show a full-screen dashboard of the migration instead of the progress bars, with the
+    /// state of every room. `p` pauses the requests, `q` aborts
+    #[arg(long, env = "TUI", conflicts_with = "quiet")]
+    tui: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Download the visible history of the old account's rooms instead of migrating
+    Export {
+        /// Directory to write the archive to
+        #[arg(long, default_value = "matrix-export")]
+        dir: PathBuf,
+
+        /// Format of the per-room archive files
+        #[arg(long, value_enum, default_value = "json")]
+        format: ExportFormat,
+
+        #[command(flatten)]
+        filters: FilterArgs,
+    },
+    /// List the media the old account uploaded through the admin API of its homeserver
+    /// (`--from-admin-token`), to download it or re-upload it to the new account's homeserver
+    Media {
+        /// Directory to download the media to, along with an index of it
+        #[arg(long)]
+        download: Option<PathBuf>,
+
+        /// Re-upload the media to the new account's homeserver
+        #[arg(long)]
+        reupload: bool,
+
+        /// Only the media with these IDs
+        #[arg(long = "media-id")]
+        media_ids: Vec<String>,
+
+        /// Only media whose content type starts with this, e.g. `image/`
+        #[arg(long = "media-type")]
+        media_type: Option<String>,
+    },
+    /// Log in and sync both accounts and tell what migrating would involve, without doing anything
+    #[command(alias = "check")]
+    Plan(MigrateArgs),
+    /// Migrate the old account to the new one, the default without a subcommand
+    Apply(MigrateArgs),
+    /// Don't change anything, but check that the new account is joined to every selected room
+    /// with at least the power level of the old account, and print a pass/fail audit
+    Verify(MigrateArgs),
+    /// Reverse the migrations recorded in the journal: the new account leaves the rooms it was
+    /// joined to, pending invites are retracted and raised power levels are lowered again
+    Rollback,
+    /// Tell how far the last migration got, from the state file and journal without logging in
+    Status,
+    /// Migrate many users one after the other, for server admins decommissioning a homeserver.
+    /// They're logged in with `--from-admin-token` / `--to-admin-token` or through an appservice
+    Bulk {
+        /// CSV file with one `old_user,new_user` pair per line
+        #[arg(long)]
+        users: PathBuf,
+
+        /// How many users to migrate at once
+        #[arg(long, default_value = "1")]
+        parallel: usize,
+
+        #[command(flatten)]
+        migrate: MigrateArgs,
+    },
+}
+
+impl Args {
+    /// Parses the command line, with the options of `--config` for the ones it doesn't give.
+    /// Unlike [`Parser::parse`], the options shared by all subcommands are accepted after the
+    /// subcommand as well, e.g. `matrix-migrate status --state-file old.json`
+    pub fn from_cli() -> Self {
+        let mut cmd = Self::cli_command();
+        let mut argv = std::env::args_os().collect::<Vec<_>>();
+        let mut sources = Vec::new();
+        if let Ok(matches) = cmd.clone().ignore_errors(true).try_get_matches_from(&argv) {
+            if let Some(path) = matches.get_one::<PathBuf>("config") {
+                let profile = matches.get_one::<String>("profile").map(String::as_str);
+                match config::args(&cmd, path, profile, &matches) {
+                    Ok((args, source_args)) => {
+                        argv.extend(args);
+                        sources = source_args;
+                    }
+                    Err(e) => cmd.error(clap::error::ErrorKind::InvalidValue, e).exit(),
+                }
+            }
+        }
+        let parse = |argv| Self::try_from_argv(&cmd, argv).unwrap_or_else(|e| e.exit());
+        let mut args = parse(argv.clone());
+        // the options of a source override the shared ones
+        args.sources = sources
+            .into_iter()
+            .map(|source| parse(argv.iter().cloned().chain(source).collect()))
+            .collect();
+        args
+    }
+
+    /// Parses `argv` as it is, without the options of `--config`. The options of a migration
+    /// given before a subcommand are rejected, they would be silently ignored.
+    fn try_from_argv(cmd: &clap::Command, argv: Vec<OsString>) -> Result<Self, clap::Error> {
+        let matches = cmd.clone().try_get_matches_from(argv)?;
+        if let Some((name, _)) = matches.subcommand() {
+            let misplaced = cmd.get_arguments().find(|a| {
+                !a.is_global_set()
+                    && matches.value_source(a.get_id().as_str()) == Some(ValueSource::CommandLine)
+            });
+            if let Some(arg) = misplaced {
+                return Err(cmd.clone().error(
+                    clap::error::ErrorKind::ArgumentConflict,
+                    format!("{arg} has to come after the subcommand '{name}'"),
+                ));
+            }
+        }
+        Self::from_arg_matches(&matches)
+    }
+
+    /// The parser of the command line, built. Flags take `=false` as well, see
+    /// [`config::negatable_flags`]
+    fn cli_command() -> clap::Command {
+        let mut cmd = config::negatable_flags(Self::command().args_override_self(true));
+        cmd.build();
+        cmd
+    }
+
+    /// The options of the migration, given after `apply`, `plan`, `verify` or `bulk`, or
+    /// without a subcommand. Left at their defaults for the other subcommands.
+    fn migrate(&self) -> &MigrateArgs {
+        match &self.command {
+            Some(Command::Plan(migrate) | Command::Apply(migrate) | Command::Verify(migrate))
+            | Some(Command::Bulk { migrate, .. }) => migrate,
+            _ => &self.migrate,
+        }
+    }
+
+    fn migrate_mut(&mut self) -> &mut MigrateArgs {
+        match &mut self.command {
+            Some(Command::Plan(migrate) | Command::Apply(migrate) | Command::Verify(migrate))
+            | Some(Command::Bulk { migrate, .. }) => migrate,
+            _ => &mut self.migrate,
+        }
+    }
+
+    /// The log filters given by `--log`, or else by the verbosity flags
+    fn log_filters(&self) -> String {
+        if let Some(log) = &self.log {
+            return log.clone();
+        }
+        match (self.quiet, self.verbose) {
+            (0, 0) => "matrix_migrate=info",
+            (1, _) => "matrix_migrate=warn",
+            (_, 0) => "matrix_migrate=error",
+            (_, 1) => "matrix_migrate=debug",
+            (_, 2) => "matrix_migrate=trace,matrix_sdk=debug",
+            _ => "trace",
+        }
+        .to_owned()
+    }
+}
+
+impl ConnectionArgs {
+    /// Fails unless the accounts to migrate from and, with `to`, to are given. Only checked for
+    /// the subcommands logging in, so the options can be shared by all of them.
+    fn check_accounts(&self, to: bool) -> anyhow::Result<()> {
+        let from_browser = self.from_sso || self.from_oidc;
+        if self.from_user.is_none()
+            && self.from_token.is_none()
+            && self.from_session.is_none()
+            && !(self.from_homeserver.is_some() && from_browser)
+        {
+            anyhow::bail!(
+                "--from is required, unless logging in with --from-token, --from-session or \
+                --from-homeserver and --from-sso/--from-oidc"
+            );
+        }
+        let to_browser = self.to_sso || self.to_oidc;
+        if to
+            && self.to_user.is_none()
+            && self.to_token.is_none()
+            && !(self.to_homeserver.is_some() && to_browser)
+        {
+            anyhow::bail!(
+                "--to is required, unless logging in with --to-token or --to-homeserver and \
+                --to-sso/--to-oidc"
+            );
+        }
+        Ok(())
+    }
+
+    fn source_login(&self) -> Login {
+        Login {
+            homeserver: self.from_homeserver.clone(),
+            user: self.from_user.clone(),
+            password: self.from_user_password.clone(),
+            password_file: self.from_password_file.clone(),
+            token: self.from_token.clone(),
+            as_token: self.from_as_token.clone(),
+            session_file: self.from_session.clone(),
+            sso: self.from_sso,
+            oidc: self.from_oidc,
+            proxy: self.from_proxy.clone().or_else(|| self.proxy.clone()),
+            ca_certs: self.from_ca_cert.clone(),
+            insecure_tls: self.from_insecure_tls,
+            device_name: self.device_name.clone(),
+            store: self.session_store.as_ref().map(|dir| dir.join("from")),
+            passphrase: self.store_passphrase.clone(),
+            keep_session: self.no_logout,
+            http_timeout: self.http_timeout,
+        }
+    }
+
+    fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            retries: self.retries,
+            backoff: self.retry_backoff,
+        }
+    }
+
+    fn target_login(&self) -> Login {
+        Login {
+            homeserver: self.to_homeserver.clone(),
+            user: self.to_user.clone(),
+            password: self.to_user_password.clone(),
+            password_file: self.to_password_file.clone(),
+            token: self.to_token.clone(),
+            as_token: self.to_as_token.clone(),
+            session_file: None,
+            sso: self.to_sso,
+            oidc: self.to_oidc,
+            proxy: self.to_proxy.clone().or_else(|| self.proxy.clone()),
+            ca_certs: self.to_ca_cert.clone(),
+            insecure_tls: self.to_insecure_tls,
+            device_name: self.device_name.clone(),
+            store: self.session_store.as_ref().map(|dir| dir.join("to")),
+            passphrase: self.store_passphrase.clone(),
+            keep_session: self.no_logout,
+            http_timeout: self.http_timeout,
+        }
+    }
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum InviteForwarding {
+    /// Accept the invites with the old account and migrate the rooms like any other
+    Join,
+    /// Ask the original inviters via DM to invite the new account instead
+    Request,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum LeavePolicy {
+    /// Stay in these rooms and leave the others
+    Skip,
+    /// Fail the run without leaving any room
+    Abort,
+    /// Leave them anyway
+    Force,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum AvatarRedirect {
+    /// Leave the old avatar in place
+    Keep,
+    /// Remove the old avatar
+    Clear,
+    /// Use the new account's avatar
+    New,
+}
+
+/// Pause before the first round of retrying failed invites, doubled for every further one
+const INVITE_RETRY_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Runs the command given by `args` and returns the exit code of the run
+pub async fn run(args: Args) -> anyhow::Result<ExitCode> {
+    progress::init_logging(&args.log_filters(), args.log_format, !args.migrate().tui)?;
+    if args.quiet > 0 {
+        progress::hide();
+    }
+    if let Some(addr) = args.migrate().metrics_listen {
+        metrics::serve(addr).await?;
+    }
+    // the status page shows the run started below
+    let context = Arc::<Context>::default();
+    if let Some(addr) = args.migrate().web_ui {
+        web::serve(addr, args.migrate().report_file.clone(), context.clone()).await?;
+    }
+
+    if args.dryrun {
+        info!("Running in dry mode, not doing any actual changes");
+    }
+
+    if !args.migrate().filters.rooms_excluded.is_empty() {
+        info!("Excluded rooms {:?}", args.migrate().filters.rooms_excluded);
+    }
+    if !args.migrate().filters.rooms.is_empty() {
+        info!(
+            "Only doing actions for rooms {:?}",
+            args.migrate().filters.rooms
+        );
+    }
+
+    match &args.command {
+        Some(Command::Export {
+            dir,
+            format,
+            filters,
+        }) => {
+            export(&args, dir, *format, filters.clone()).await?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Command::Media {
+            download,
+            reupload,
+            media_ids,
+            media_type,
+        }) => {
+            let download = download.clone();
+            let (reupload, media_ids, media_type) =
+                (*reupload, media_ids.clone(), media_type.clone());
+            export_media(args, download, reupload, media_ids, media_type).await?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Command::Status) => {
+            let status = status::status(&args.state_file, &args.journal)?;
+            match args.output {
+                OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&status)?),
+                OutputFormat::Text => print!("{}", status.summary()),
+            }
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Command::Bulk {
+            users, parallel, ..
+        }) => {
+            return bulk::bulk(&args, users, *parallel).await;
+        }
+        Some(Command::Verify(_)) if args.dryrun => {
+            anyhow::bail!("verify doesn't change anything anyway, --dry-run isn't needed")
+        }
+        _ => {}
+    }
+
+    if !args.sources.is_empty() {
+        if !matches!(args.command, None | Some(Command::Apply(_))) {
+            anyhow::bail!("Only apply can migrate the several [[source]] accounts of the config");
+        }
+        let output = args.output;
+        let plan_table = args.dryrun && args.quiet == 0;
+        let report = merge::merge(args.sources, context).await?;
+        match output {
+            OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&report)?),
+            OutputFormat::Text if plan_table => print!("{}", report.plan_table()),
+            OutputFormat::Text => {}
+        }
+        return Ok(report.exit_code());
+    }
+
+    let mut migrator = Migrator::login_in(args, context.clone()).await?;
+
+    match migrator.args.command {
+        Some(Command::Rollback) => {
+            let exit_code = migrator.rollback().await?;
+            migrator.logout().await?;
+            return Ok(exit_code);
+        }
+        Some(Command::Plan(_)) => {
+            let check = migrator.check().await?;
+            match migrator.args.output {
+                OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&check)?),
+                OutputFormat::Text => print!("{}", check.summary()),
+            }
+            migrator.logout().await?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Command::Verify(_)) => {
+            let audits = migrator.verify().await?;
+            if migrator.args.quiet == 0 {
+                print!("{}", audit::audit_table(&audits));
+            }
+            migrator.logout().await?;
+            return Ok(audit::log_summary(&audits));
+        }
+        Some(Command::Apply(_))
+        | Some(Command::Export { .. })
+        | Some(Command::Media { .. })
+        | Some(Command::Status)
+        | Some(Command::Bulk { .. })
+        | None => {}
+    }
+
+    let output = migrator.args.output;
+    let plan_table = migrator.args.dryrun && migrator.args.quiet == 0;
+    if migrator.args.migrate().tui {
+        let from = migrator.from_c.user_id().unwrap().to_owned();
+        let to = migrator.to_c.user_id().unwrap().to_owned();
+        tui::start(from, to, &context)?;
+    }
+    let report = migrator.migrate().await;
+    tui::stop(&context);
+    let report = report?;
+    match output {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&report)?),
+        OutputFormat::Text if plan_table => print!("{}", report.plan_table()),
+        OutputFormat::Text => {}
+    }
+    if context.is_aborted() {
+        return Ok(ExitCode::from(EXIT_ABORTED));
+    }
+    Ok(report.exit_code())
+}
+
+/// Downloads the visible history of the old account's rooms to `output`
+async fn export(
+    args: &Args,
+    output: &Path,
+    format: ExportFormat,
+    mut filters: FilterArgs,
+) -> anyhow::Result<()> {
+    args.connection.check_accounts(false)?;
+    filters.check()?;
+
+    let mut from_login = args.connection.source_login();
+    from_login.ensure_password(
+        "Either --from-pw, --from-token, --from-as-token, --from-session, --from-sso or \
+        --from-oidc is required",
+    )?;
+    let from_c = from_login.client().await?;
+    filters.prepare(&from_c).await?;
+
+    let mut from_sync = Syncer::new(
+        &from_c,
+        args.connection.sliding_sync,
+        from_login.store.is_some(),
+        Duration::from_secs(0),
+        args.connection.retry_policy(),
+    )
+    .await?;
+    info!("Logged in. Syncing...");
+    from_sync.next().await?;
+
+    let rooms = filters.select(from_c.joined_rooms()).await?;
+    export::export_rooms(&from_c, &rooms, output, format).await?;
+
+    from_login.end_session(&from_c).await?;
+    info!("-- All done! -- ");
+    Ok(())
+}
+
+/// Lists, downloads and re-uploads the media of the old account through the admin API
+async fn export_media(
+    args: Args,
+    download: Option<PathBuf>,
+    reupload: bool,
+    media_ids: Vec<String>,
+    media_type: Option<String>,
+) -> anyhow::Result<()> {
+    let Some(from_user) = &args.connection.from_user else {
+        anyhow::bail!("--from is required to list the media of the old account");
+    };
+    let Some(admin) = bulk::admin(
+        &args.connection.source_login(),
+        from_user.server_name(),
+        args.connection.from_admin_token.as_ref(),
+    )
+    .await?
+    else {
+        anyhow::bail!("--from-admin-token is required to list the media of the old account");
+    };
+
+    let mut media = admin.user_media(from_user).await?;
+    media.retain(|m| media_ids.is_empty() || media_ids.contains(&m.media_id));
+    if let Some(media_type) = &media_type {
+        media.retain(|m| {
+            m.media_type
+                .as_deref()
+                .is_some_and(|t| t.starts_with(media_type.as_str()))
+        });
+    }
+    let size = media.iter().filter_map(|m| m.media_length).sum::<u64>();
+    info!("{from_user} uploaded {} files, {size} bytes", media.len());
+
+    let mut to = None;
+    if reupload && !args.dryrun {
+        args.connection.check_accounts(true)?;
+        let mut to_login = args.connection.target_login();
+        to_login.ensure_password(
+            "Either --to-pw, --to-token, --to-as-token, --to-sso or --to-oidc is required",
+        )?;
+        let to_c = to_login.client().await?;
+        to = Some((to_login, to_c));
+    }
+    let download = download.filter(|_| !args.dryrun);
+    let exported = media::export_media(
+        &admin,
+        from_user,
+        media,
+        download.as_deref(),
+        to.as_ref().map(|(_, c)| c),
+    )
+    .await?;
+    if let Some((to_login, to_c)) = &to {
+        to_login.end_session(to_c).await?;
+    }
+
+    match args.output {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&exported)?),
+        OutputFormat::Text if args.quiet == 0 => {
+            for entry in &exported {
+                let name = entry.media.upload_name.as_deref().unwrap_or_default();
+                let media_type = entry.media.media_type.as_deref().unwrap_or_default();
+                match &entry.new_uri {
+                    Some(new_uri) => println!("{} {media_type} {name} -> {new_uri}", entry.uri),
+                    None => println!("{} {media_type} {name}", entry.uri),
+                }
+            }
+        }
+        OutputFormat::Text => {}
+    }
+    info!("-- All done! -- ");
+    Ok(())
+}
+
+/// A migration of one account to another, for embedding it into other tools. It's configured
+/// with the same options as the binary, e.g. `Args::parse_from(["matrix-migrate", "--from", ..])`.
+///
+/// [`Migrator::migrate`] runs all phases one after the other, the same as the binary does. The
+/// phases can be run one by one as well, in the order of the methods below, e.g. to show
+/// progress in between or to stop after inviting. Every migrator keeps its own report, so
+/// several of them can run in the same process.
+pub struct Migrator {
+    args: Args,
+    context: Arc<Context>,
+    plan: MigrationPlan,
+    from_login: Login,
+    to_login: Login,
+    from_c: Client,
+    to_c: Client,
+    from_sync: Syncer,
+    to_sync: Syncer,
+    limiter: Limiter,
+    checkpoint: Checkpoint,
+    /// Rooms of the old account that are migrated, set by `select_rooms`
+    rooms: Vec<OwnedRoomId>,
+    bridged: Vec<OwnedRoomId>,
+    unforwarded_invites: Vec<OwnedRoomId>,
+    manual_knocks: Vec<OwnedRoomId>,
+    failed_dms: Vec<OwnedUserId>,
+    failed_invites: Vec<OwnedRoomId>,
+    deactivated: bool,
+    /// Rooms the filters ruled out before syncing, which the sync doesn't know about
+    prefiltered: usize,
+}
+
+impl Migrator {
+    /// Logs in both accounts given by `args` and checks that one can be migrated to the other
+    pub async fn login(args: Args) -> anyhow::Result<Self> {
+        Self::login_in(args, Arc::default()).await
+    }
+
+    /// Like [`Self::login`], recording the run in `context`
+    async fn login_in(mut args: Args, context: Arc<Context>) -> anyhow::Result<Self> {
+        args.connection.check_accounts(true)?;
+        args.migrate().filters.check()?;
+        context.report.on_error(args.migrate().on_error);
+
+        let mut plan = match &args.migrate().plan {
+            Some(path) => MigrationPlan::load(path)?,
+            None => MigrationPlan::default(),
+        };
+
+        if let Some(dir) = args
+            .connection
+            .session_store
+            .as_ref()
+            .filter(|_| args.connection.fresh)
+        {
+            if dir.exists() {
+                info!("Wiping the session store {}", dir.display());
+                std::fs::remove_dir_all(dir)?;
+            }
+        }
+
+        let mut from_login = args.connection.source_login();
+        from_login.ensure_password(
+            "Either --from-pw, --from-token, --from-as-token, --from-session, --from-sso or \
+            --from-oidc is required",
+        )?;
+        let from_c = from_login.client().await?;
+
+        args.migrate_mut().filters.prepare(&from_c).await?;
+        plan.resolve(&from_c).await?;
+        plan.set_power_levels(
+            args.migrate().target_power_level,
+            args.migrate().max_power_level,
+        );
+
+        let mut to_login = args.connection.target_login();
+        to_login.ensure_password(
+            "Either --to-pw, --to-token, --to-as-token, --to-sso or --to-oidc is required",
+        )?;
+        if args.migrate().register_to {
+            let answers = uia::Answers {
+                registration_token: args.migrate().registration_token.as_deref(),
+                email: args.migrate().register_email.as_deref(),
+            };
+            register::register(&to_login, &answers, args.dryrun).await?;
+        }
+        let to_c = to_login.client().await?;
+        preflight::check(&from_c, &to_c, args.migrate().migrate_knocks).await?;
+
+        let (from_quirks, to_quirks) = if args.connection.no_quirks {
+            (None, None)
+        } else {
+            let from_quirks = quirks::detect(&from_login.http_client()?, &from_c).await;
+            let to_quirks = quirks::detect(&to_login.http_client()?, &to_c).await;
+            info!(
+                "Homeserver profiles: {} for the old account, {} for the new one",
+                from_quirks.name, to_quirks.name
+            );
+            (Some(from_quirks), Some(to_quirks))
+        };
+        for quirks in [from_quirks, to_quirks].iter().flatten() {
+            if args.connection.sliding_sync && !quirks.sliding_sync {
+                info!(
+                    "{} doesn't serve sliding sync, syncing the usual way instead",
+                    quirks.name
+                );
+                args.connection.sliding_sync = false;
+            }
+        }
+        if let Some(quirks) =
+            to_quirks.filter(|q| !q.admin_api && args.connection.to_admin_token.is_some())
+        {
+            warn!(
+                "{} has no Synapse admin API, inviting the new account instead of joining it \
+                with --to-admin-token",
+                quirks.name
+            );
+            args.connection.to_admin_token = None;
+        }
+
+        // the admin API lists the rooms right away, so only the ones the filters may select are
+        // synced. Rooms joined or invited to later on aren't in the list.
+        let mut from_rooms = None;
+        let mut prefiltered = 0;
+        if let Some(token) = &args.connection.from_admin_token {
+            if from_quirks.is_some_and(|q| !q.admin_api) {
+                info!(
+                    "{} has no Synapse admin API, syncing all rooms",
+                    from_c.homeserver()
+                );
+            } else if !args.migrate().watch && args.migrate().forward_invites.is_none() {
+                let admin = SynapseAdmin::new(
+                    from_login.http_client()?,
+                    from_c.homeserver(),
+                    token.clone(),
+                );
+                let from_user = from_c.user_id().unwrap();
+                match admin.joined_rooms(from_user).await {
+                    Ok(mut rooms) => {
+                        let joined = rooms.len();
+                        rooms.retain(|r| args.migrate().filters.may_select(r));
+                        prefiltered = joined - rooms.len();
+                        info!(
+                            "{from_user} is in {joined} rooms, syncing the {} the filters may select",
+                            rooms.len()
+                        );
+                        from_rooms = Some(rooms);
+                    }
+                    Err(e) => warn!(
+                        "Couldn't list the rooms of {from_user} through the admin API, syncing all of them: {e}"
+                    ),
+                }
+            }
+        }
+
+        let mut from_sync = Syncer::new(
+            &from_c,
+            args.connection.sliding_sync && from_rooms.is_none(),
+            from_login.store.is_some(),
+            Duration::from_secs(0),
+            args.connection.retry_policy(),
+        )
+        .await?;
+        if let Some(rooms) = from_rooms {
+            from_sync = from_sync.with_rooms(rooms);
+        }
+        let to_sync = Syncer::new(
+            &to_c,
+            args.connection.sliding_sync,
+            to_login.store.is_some(),
+            Duration::from_secs(args.connection.timeout),
+            args.connection.retry_policy(),
+        )
+        .await?;
+
+        let mut limiter = Limiter::new(
+            args.connection.concurrency,
+            args.connection.requests_per_second,
+            args.connection.burst,
+            args.connection.retry_policy(),
+        );
+        // the homeserver's profile lowers the rate, unless it's given for the homeserver or
+        // `--requests-per-second 0` turned the limit off
+        let quirk_rate = |quirks: Option<quirks::Quirks>| {
+            let rate = quirks?.requests_per_second?;
+            let limit = args.connection.requests_per_second;
+            (limit > 0.0).then(|| rate.min(limit))
+        };
+        if let Some(rate) = args
+            .connection
+            .from_requests_per_second
+            .or(quirk_rate(from_quirks))
+        {
+            limiter = limiter.with_rate(&from_c, rate);
+        }
+        if let Some(rate) = args
+            .connection
+            .to_requests_per_second
+            .or(quirk_rate(to_quirks))
+        {
+            limiter = limiter.with_rate(&to_c, rate);
+        }
+
+        Ok(Self {
+            args,
+            plan,
+            from_login,
+            to_login,
+            from_c,
+            to_c,
+            from_sync,
+            to_sync,
+            limiter,
+            checkpoint: Checkpoint::disabled(),
+            rooms: Vec::new(),
+            bridged: Vec::new(),
+            unforwarded_invites: Vec::new(),
+            manual_knocks: Vec::new(),
+            failed_dms: Vec::new(),
+            failed_invites: Vec::new(),
+            deactivated: false,
+            prefiltered,
+            context,
+        })
+    }
+
+    /// Replaces the plan given by `--plan`
+    pub async fn set_plan(&mut self, mut plan: MigrationPlan) -> anyhow::Result<()> {
+        plan.resolve(&self.from_c).await?;
+        plan.set_power_levels(
+            self.args.migrate().target_power_level,
+            self.args.migrate().max_power_level,
+        );
+        self.plan = plan;
+        Ok(())
+    }
+
+    /// Tells what migrating would involve, without doing anything
+    pub async fn check(&mut self) -> anyhow::Result<CheckReport> {
+        info!("All logged in. Syncing...");
+        try_join!(self.from_sync.next(), self.to_sync.next())?;
+
+        check::check(&self.args, &self.plan, &self.from_c, &self.to_c).await
+    }
+
+    /// Checks that the rooms of the old account are migrated, without doing anything
+    pub async fn verify(&mut self) -> anyhow::Result<Vec<RoomAudit>> {
+        info!("All logged in. Syncing...");
+        try_join!(self.from_sync.next(), self.to_sync.next())?;
+
+        let mut rooms = self
+            .args
+            .migrate()
+            .filters
+            .select(self.from_c.joined_rooms())
+            .await?;
+        rooms.retain(|r| !self.plan.skips(r));
+        audit::audit_rooms(&self.from_c, &self.to_c, &rooms, &self.plan).await
+    }
+
+    /// Reverses the migrations recorded in the journal
+    pub async fn rollback(&mut self) -> anyhow::Result<ExitCode> {
+        let entries = journal::read(
+            &self.args.journal,
+            self.from_c.user_id().unwrap(),
+            self.to_c.user_id().unwrap(),
+        )?;
+        if entries.is_empty() {
+            anyhow::bail!(
+                "The journal {} has no actions of this migration to roll back",
+                self.args.journal.display()
+            );
+        }
+        info!("All logged in. Syncing...");
+        try_join!(self.from_sync.next(), self.to_sync.next())?;
+
+        rollback::rollback(
+            &self.from_c,
+            &self.to_c,
+            &entries,
+            &self.limiter,
+            self.args.dryrun,
+        )
+        .await
+    }
+
+    /// Ends the sessions of both accounts
+    pub async fn logout(self) -> anyhow::Result<()> {
+        self.to_login.end_session(&self.to_c).await?;
+        if !self.deactivated {
+            self.from_login.end_session(&self.from_c).await?;
+        }
+        info!("-- All done! -- ");
+        Ok(())
+    }
+
+    /// Runs all phases of the migration and returns its report. When the run is aborted from
+    /// the dashboard, the phases stop right away and the report tells how far they got.
+    pub async fn migrate(mut self) -> anyhow::Result<MigrationReport> {
+        let context = self.context.clone();
+        tokio::select! {
+            result = self.run_phases() => result?,
+            () = context.aborted() => {
+                warn!("Aborted. Run again with --resume to continue where the migration stopped");
+            }
+        }
+        self.finish().await
+    }
+
+    async fn run_phases(&mut self) -> anyhow::Result<()> {
+        self.start().await?;
+        self.select_rooms().await?;
+        self.invite().await?;
+        self.migrate_extras().await?;
+        self.leave().await?;
+        self.redirect_profile().await?;
+        self.deactivate().await?;
+        if self.args.migrate().watch {
+            self.watch().await?;
+        }
+        Ok(())
+    }
+
+    /// Opens the checkpoint and journal and syncs both accounts. With sliding sync, the rooms
+    /// are already invited to while they're loaded.
+    pub async fn start(&mut self) -> anyhow::Result<()> {
+        context::scope(self.context.clone(), async {
+            let args = &self.args;
+            let from_user = self.from_c.user_id().unwrap();
+            let to_user = self.to_c.user_id().unwrap();
+            if !args.dryrun {
+                self.checkpoint =
+                    Checkpoint::open(&args.state_file, args.migrate().resume, from_user, to_user)?;
+                journal::open(&args.journal, from_user, to_user)?;
+            }
+
+            if let Some(url) = &args.migrate().webhook_url {
+                webhook::init(self.to_login.http_client()?, url.clone());
+            }
+            webhook::send(Event::RunStarted {
+                from: Some(from_user.to_owned()),
+                to: Some(to_user.to_owned()),
+                dry_run: args.dryrun,
+            });
+            report::source(from_user);
+            // merged accounts share the context, each calls the hook with its own account
+            match &args.migrate().hook {
+                Some(path) => hook::init(
+                    path.clone(),
+                    from_user.to_owned(),
+                    to_user.to_owned(),
+                    args.dryrun,
+                )?,
+                None => hook::clear(),
+            }
+
+            info!("All logged in. Syncing...");
+
+            // the hook is asked about every room before it's invited to
+            if args.connection.sliding_sync
+                && !args.dryrun
+                && !args.migrate().filters.pick_rooms
+                && args.migrate().hook.is_none()
+            {
+                self.to_sync.next().await?;
+                invite_while_syncing(
+                    args,
+                    &self.plan,
+                    &mut self.from_sync,
+                    &self.from_c,
+                    &self.to_c,
+                    &self.limiter,
+                    &self.checkpoint,
+                )
+                .await?;
+            } else {
+                try_join!(self.from_sync.next(), self.to_sync.next())?;
+            }
+
+            info!("--- Synced");
+            Ok(())
+        })
+        .await
+    }
+
+    /// Forwards pending invites and knocks, selects the rooms to migrate and re-creates the DMs
+    /// among them
+    pub async fn select_rooms(&mut self) -> anyhow::Result<()> {
+        context::scope(self.context.clone(), async {
+            let args = &self.args;
+            let plan = &self.plan;
+            let from_c = &self.from_c;
+            let to_c = &self.to_c;
+            let filters = &args.migrate().filters;
+
+            // the pending invites and the joined rooms are picked from one list
+            let picked = if filters.pick_rooms {
+                let mut rooms = from_c.joined_rooms();
+                if args.migrate().forward_invites.is_some() {
+                    rooms.extend(from_c.invited_rooms());
+                }
+                Some(filters.select(rooms).await?.into_iter().collect())
+            } else {
+                None
+            };
+
+            if let Some(mode) = args.migrate().forward_invites {
+                let pending_invites =
+                    select_picked(filters, from_c.invited_rooms(), picked.as_ref()).await?;
+
+                let to_user = to_c.user_id().unwrap().to_owned();
+                self.unforwarded_invites =
+                    forward_invites(from_c, &to_user, &pending_invites, mode, args.dryrun).await?;
+
+                if mode == InviteForwarding::Join && !args.dryrun && !pending_invites.is_empty() {
+                    // pull in the state of the freshly joined rooms
+                    self.from_sync.next().await?;
+                }
+            }
+
+            if args.migrate().migrate_knocks {
+                let knocked = knocked_rooms(from_c)
+                    .await?
+                    .into_iter()
+                    .filter(|(r, _)| args.migrate().filters.is_selected(r))
+                    .collect::<Vec<_>>();
+                self.manual_knocks = migrate_knocks(from_c, to_c, knocked, args.dryrun).await?;
+            }
+
+            let joined = from_c.joined_rooms();
+            let candidates = joined.len();
+            let mut all_prev_rooms = select_picked(filters, joined, picked.as_ref()).await?;
+            report::filtered(candidates - all_prev_rooms.len() + self.prefiltered);
+            report::track(&all_prev_rooms);
+            all_prev_rooms.retain(|r| {
+                if plan.skips(r) {
+                    report::skip(r, "skipped by the plan");
+                }
+                !plan.skips(r)
+            });
+
+            let bridged = bridged_rooms(from_c, &all_prev_rooms).await?;
+            if !bridged.is_empty() {
+                if args.migrate().include_bridged {
+                    warn!(
+                        "Migrating bridged rooms {:?}. Inviting a second account usually breaks the bridge",
+                        bridged
+                    );
+                } else {
+                    info!(
+                        "Skipping bridged rooms {:?}. Use --include-bridged to migrate them anyway",
+                        bridged
+                    );
+                    for room_id in &bridged {
+                        report::skip(room_id, "bridged");
+                    }
+                    all_prev_rooms.retain(|r| !bridged.contains(r));
+                }
+            }
+            self.bridged = bridged;
+            hook::start_rooms(from_c, &mut all_prev_rooms).await;
+
+            let dm_candidates = all_prev_rooms
+                .iter()
+                .filter(|r| plan.recreates_dm(r, args.migrate().recreate_dms))
+                .cloned()
+                .collect::<Vec<_>>();
+            if !dm_candidates.is_empty() {
+                let dms = direct_messages(from_c, &dm_candidates).await?;
+                // re-created DMs don't go through the invite flow
+                all_prev_rooms.retain(|r| !dms.iter().any(|(dm, _)| dm == r));
+                self.failed_dms = recreate_dms(
+                    from_c,
+                    to_c,
+                    &dms,
+                    args.migrate().dm_handoff_message.as_deref(),
+                    args.dryrun,
+                )
+                .await?;
+            }
+
+            let recreate_candidates = all_prev_rooms
+                .iter()
+                .filter(|r| plan.recreates(r, args.migrate().recreate))
+                .cloned()
+                .collect::<Vec<_>>();
+            if !recreate_candidates.is_empty() {
+                let mut summary = format!(
+                    "{} rooms are about to be re-created from {}. Each of them gets a tombstone \
+                    pointing its members to the new room, which can't be undone:",
+                    recreate_candidates.len(),
+                    to_c.user_id().unwrap(),
+                );
+                for room_id in &recreate_candidates {
+                    let name = match from_c.get_room(room_id) {
+                        Some(room) => room.display_name().await?.to_string(),
+                        None => String::new(),
+                    };
+                    summary += &format!("\n  {name} ({room_id})");
+                }
+                if args.dryrun
+                    || progress::confirm(&summary, "Re-create and replace these rooms?", args.migrate().yes)?
+                {
+                    // re-created rooms don't go through the invite flow either
+                    let recreated = recreate_rooms(
+                        args,
+                        from_c,
+                        to_c,
+                        &self.to_login,
+                        &recreate_candidates,
+                        &self.limiter,
+                    )
+                    .await?;
+                    all_prev_rooms.retain(|r| !recreated.contains(r));
+                } else {
+                    info!("Not re-creating any rooms, inviting the new account instead");
+                }
+            }
+
+            self.rooms = all_prev_rooms;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Invites the new account to the selected rooms, accepts the invites and raises its power
+    /// levels, retrying invites failing for a passing reason
+    pub async fn invite(&mut self) -> anyhow::Result<()> {
+        context::scope(self.context.clone(), async {
+            let args = &self.args;
+            let plan = &self.plan;
+            let from_c = &self.from_c;
+            let to_c = &self.to_c;
+            let limiter = &self.limiter;
+            let checkpoint = &self.checkpoint;
+            let all_prev_rooms = &self.rooms;
+
+            let all_new_rooms = to_c
+                .joined_rooms()
+                .into_iter()
+                .map(|r| r.room_id().to_owned())
+                .chain(
+                    to_c.invited_rooms()
+                        .into_iter()
+                        .map(|r| r.room_id().to_owned()),
+                )
+                .collect::<Vec<_>>();
+
+            let (already_invited, mut to_invite): (Vec<_>, Vec<_>) = all_prev_rooms
+                .iter()
+                .partition(|r| all_new_rooms.contains(r));
+
+            let to_user = to_c.user_id().unwrap().to_owned();
+            let banned = banned_rooms(from_c, &to_user, &to_invite, args.migrate().unban, args.dryrun).await?;
+            to_invite.retain(|r| !banned.contains(r));
+            let acl_blocked = acl_blocked_rooms(from_c, &to_user, &to_invite).await?;
+            to_invite.retain(|r| !acl_blocked.contains(r));
+
+            let invites_to_accept = to_c
+                .invited_rooms()
+                .into_iter()
+                .filter_map(|r| {
+                    let room_id = r.room_id().to_owned();
+                    if all_prev_rooms.contains(&room_id) {
+                        Some(room_id)
+                    } else {
+                        None
+                    }
+                })
+                .collect::<Vec<_>>();
+
+            // only rooms where a previous run didn't already get the power level right
+            let power_level_gaps = power_level_gaps(
+                from_c,
+                from_c.user_id().unwrap(),
+                &to_user,
+                &already_invited,
+                plan,
+            )
+            .await?;
+            let pending_leaves = if args.migrate().leave_rooms || plan.has_leaves() {
+                all_prev_rooms
+                    .iter()
+                    .filter(|r| plan.leaves(r, args.migrate().leave_rooms))
+                    .count()
+            } else {
+                0
+            };
+
+            info!(
+                "--- Already sharing {}; Power levels to adjust: {}; Rooms to accept: {};  Rooms to invite: {}; Rooms to leave: {}",
+                already_invited.len(),
+                power_level_gaps.len(),
+                invites_to_accept.len(),
+                to_invite.len(),
+                pending_leaves
+            );
+            if power_level_gaps.is_empty()
+                && invites_to_accept.is_empty()
+                && to_invite.is_empty()
+                && pending_leaves == 0
+            {
+                info!(
+                    "--- Nothing to do, all {} rooms are already migrated",
+                    already_invited.len()
+                );
+            }
+
+            // rooms the new account is joined to by the admin API skip the invite and accept steps,
+            // but still get their power level adjusted
+            let mut admins = Admins::default();
+            if let Some(token) = &args.connection.from_admin_token {
+                admins.from = Some(SynapseAdmin::new(
+                    self.from_login.http_client()?,
+                    from_c.homeserver(),
+                    token.clone(),
+                ));
+            }
+            let mut force_joined = Vec::new();
+            if let Some(token) = &args.connection.to_admin_token {
+                let admin = SynapseAdmin::new(
+                    self.to_login.http_client()?,
+                    to_c.homeserver(),
+                    token.clone(),
+                );
+                force_joined = force_join(
+                    &admin,
+                    from_c,
+                    &to_invite,
+                    &to_user,
+                    limiter,
+                    checkpoint,
+                    args.dryrun,
+                )
+                .await;
+                admins.to = Some(admin);
+            }
+            let to_power_level = to_invite.clone();
+            to_invite.retain(|r| !force_joined.contains(r));
+
+            // public rooms and restricted rooms the new account can join as member of an allowed
+            // room don't need an invite
+            let public_joined =
+                join_public(from_c, to_c, &to_invite, limiter, checkpoint, args.dryrun).await?;
+            to_invite.retain(|r| !public_joined.contains(r));
+            let restricted_joined = join_restricted(
+                from_c,
+                to_c,
+                &to_invite,
+                limiter,
+                checkpoint,
+                args.dryrun,
+                false,
+            )
+            .await?;
+            to_invite.retain(|r| !restricted_joined.contains(r));
+
+            let to_accept = invites_to_accept.iter().collect();
+            let ensure_user = to_user.clone();
+
+            let (_, not_yet_accepted, (remaining_invites, failed_invites)) = try_join!(
+                async {
+                    ensure_power_levels(
+                        from_c,
+                        to_c,
+                        ensure_user,
+                        &power_level_gaps,
+                        plan,
+                        &admins,
+                        limiter,
+                        checkpoint,
+                        args.dryrun,
+                    )
+                    .await
+                },
+                async { accept_invites(to_c, &to_accept, limiter, checkpoint, args.dryrun).await },
+                async {
+                    let failed_invites = send_invites(
+                        from_c,
+                        &to_invite,
+                        to_user.clone(),
+                        limiter,
+                        checkpoint,
+                        args.dryrun,
+                    )
+                    .await?;
+                    ensure_power_levels(
+                        from_c,
+                        to_c,
+                        to_user.clone(),
+                        &to_power_level,
+                        plan,
+                        &admins,
+                        limiter,
+                        checkpoint,
+                        args.dryrun,
+                    )
+                    .await?;
+                    Ok((
+                        to_invite
+                            .iter()
+                            .map(|r| (*r).to_owned())
+                            .filter(|r| !failed_invites.iter().any(|f| f.room_id == *r))
+                            .collect::<Vec<_>>(),
+                        failed_invites,
+                    ))
+                },
+            )?;
+
+            let mut invites_awaiting = not_yet_accepted
+                .into_iter()
+                .chain(remaining_invites)
+                .collect::<Vec<_>>();
+            let mut failed_invites = failed_invites;
+            let mut knocks_awaiting =
+                knock_failed_invites(from_c, to_c, &mut failed_invites, limiter, args.dryrun).await?;
+
+            info!("First invitation set done.");
+            while !invites_awaiting.is_empty() && !args.dryrun {
+                info!("Still {} rooms to go. Syncing up", invites_awaiting.len());
+                self.to_sync.next().await?;
+                invites_awaiting = accept_invites(
+                    to_c,
+                    &invites_awaiting.iter().collect(),
+                    limiter,
+                    checkpoint,
+                    args.dryrun,
+                )
+                .await?;
+
+                // knocks let in by a moderator in the meantime come in as invites
+                let (let_in, still_knocking) = knocks_awaiting.into_iter().partition(|r| {
+                    to_c.get_room(r)
+                        .is_some_and(|r| r.state() == RoomState::Invited)
+                });
+                knocks_awaiting = still_knocking;
+                invites_awaiting.extend(let_in);
+            }
+            if !knocks_awaiting.is_empty() && !args.dryrun {
+                info!(
+                    "Knocked on {:?}, a moderator has to let the new account in. Run again to accept \
+                    once they did",
+                    knocks_awaiting
+                );
+            }
+
+            let mut failed_invites = retry_invites(
+                args,
+                from_c,
+                to_c,
+                &mut self.to_sync,
+                failed_invites,
+                limiter,
+                checkpoint,
+            )
+            .await?;
+
+            // the allowed rooms of restricted rooms may have been joined in the meantime
+            if !failed_invites.is_empty() && !args.dryrun {
+                self.to_sync.next().await?;
+                let joined = join_restricted(
+                    from_c,
+                    to_c,
+                    &failed_invites.iter().collect::<Vec<_>>(),
+                    limiter,
+                    checkpoint,
+                    args.dryrun,
+                    true,
+                )
+                .await?;
+                failed_invites.retain(|r| !joined.contains(r));
+            }
+
+            if !failed_invites.is_empty() {
+                warn!(
+                    "Failed to invite to {:?}. See logs above for the reasons why",
+                    failed_invites
+                );
+            }
+
+            if !banned.is_empty() {
+                warn!(
+                    "Skipped {:?}, the new account is banned there. See logs above for details",
+                    banned
+                );
+            }
+
+            if !acl_blocked.is_empty() {
+                warn!(
+                    "Skipped {:?}, their server ACL blocks the new account's homeserver. Ask a moderator to allow it",
+                    acl_blocked
+                );
+            }
+
+            if !self.unforwarded_invites.is_empty() {
+                warn!(
+                    "Couldn't forward the pending invites to {:?}. See logs above for the reasons why",
+                    self.unforwarded_invites
+                );
+            }
+
+            if !self.bridged.is_empty() {
+                warn!(
+                    "Bridged rooms {:?} have to be re-provisioned with their bridge manually",
+                    self.bridged
+                );
+            }
+
+            if !self.failed_dms.is_empty() {
+                warn!(
+                    "Failed to re-create the DMs with {:?}. See logs above for the reasons why",
+                    self.failed_dms
+                );
+            }
+
+            if !self.manual_knocks.is_empty() {
+                warn!(
+                    "Knocks on {:?} have to be redone manually. See logs above for the reasons why",
+                    self.manual_knocks
+                );
+            }
+
+            self.failed_invites = failed_invites;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Re-uploads media and republishes aliases of the migrated rooms, if asked for
+    pub async fn migrate_extras(&mut self) -> anyhow::Result<()> {
+        context::scope(self.context.clone(), async {
+            let migrated_rooms = self
+                .rooms
+                .iter()
+                .filter(|r| self.to_c.get_room(r).is_some())
+                .collect::<Vec<_>>();
+
+            if self.args.migrate().reupload_media {
+                media::reupload_media(&self.from_c, &self.to_c, &migrated_rooms, self.args.dryrun)
+                    .await?;
+            }
+
+            if let Err(e) = pushers::check_pushers(&self.from_c, &self.to_c, self.args.dryrun).await
+            {
+                warn!("Couldn't look at the pushers and addresses of the old account: {e}");
+            }
+
+            if self.args.migrate().migrate_emotes {
+                media::migrate_emotes(&self.from_c, &self.to_c, self.args.dryrun).await?;
+            }
+
+            if self.args.migrate().migrate_widgets {
+                widgets::migrate_widgets(&self.from_c, &self.to_c, self.args.dryrun).await?;
+            }
+
+            if self.args.migrate().room_display_names {
+                copy_room_display_names(
+                    &self.from_c,
+                    &self.to_c,
+                    &migrated_rooms,
+                    &self.limiter,
+                    self.args.dryrun,
+                )
+                .await?;
+            }
+
+            if self.args.migrate().hand_over_spaces {
+                spaces::hand_over_spaces(
+                    &self.from_c,
+                    &self.to_c,
+                    &self.rooms,
+                    &self.limiter,
+                    self.args.dryrun,
+                )
+                .await?;
+            }
+
+            if self.args.migrate().republish_aliases {
+                republish_aliases(
+                    &self.from_c,
+                    &self.to_c,
+                    &migrated_rooms,
+                    self.args.migrate().update_canonical_alias,
+                    self.args.dryrun,
+                )
+                .await?;
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    /// Leaves the migrated rooms with the old account, if asked for
+    pub async fn leave(&mut self) -> anyhow::Result<()> {
+        context::scope(self.context.clone(), async {
+            let args = &self.args;
+            let plan = &self.plan;
+            let from_c = &self.from_c;
+            let to_c = &self.to_c;
+
+            if !args.migrate().leave_rooms && !plan.has_leaves() {
+                info!("Hint: Run again with the --leave-rooms flag to remove the old account from successfully migrated rooms");
+                return Ok(());
+            }
+
+            self.to_sync.next().await?;
+
+            let all_new_rooms = to_c
+                .joined_rooms()
+                .into_iter()
+                .map(|r| r.room_id().to_owned())
+                .collect::<Vec<_>>();
+
+            let to_remove = self
+                .rooms
+                .iter()
+                .filter(|r| all_new_rooms.contains(r) && plan.leaves(r, args.migrate().leave_rooms))
+                .collect::<Vec<_>>();
+
+            if args.migrate().leave_policy == LeavePolicy::Abort {
+                let below = power_level_gaps(
+                    to_c,
+                    from_c.user_id().unwrap(),
+                    to_c.user_id().unwrap(),
+                    &to_remove,
+                    plan,
+                )
+                .await?;
+                if !below.is_empty() {
+                    anyhow::bail!(
+                        "{} has a lower power level than {} in {:?}. Not leaving any room \
+                        (--leave-policy abort)",
+                        to_c.user_id().unwrap(),
+                        from_c.user_id().unwrap(),
+                        below
+                    );
+                }
+            }
+
+            let mut summary = format!(
+                "{} is about to leave {} rooms:",
+                from_c.user_id().unwrap(),
+                to_remove.len()
+            );
+            for room_id in &to_remove {
+                let name = match from_c.get_room(room_id) {
+                    Some(room) => room.display_name().await?.to_string(),
+                    None => String::new(),
+                };
+                summary += &format!("\n  {name} ({room_id})");
+            }
+            if args.migrate().demote_old {
+                summary += "\nIts power level is dropped to the default in each of them first.";
+            }
+            if to_remove.is_empty()
+                || args.dryrun
+                || progress::confirm(&summary, "Leave these rooms?", args.migrate().yes)?
+            {
+                leave_room(args, from_c, to_c, to_remove, plan, &self.checkpoint).await?;
+            } else {
+                info!("Not leaving any rooms");
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    /// Points the old account's profile at the new account, if asked for
+    pub async fn redirect_profile(&mut self) -> anyhow::Result<()> {
+        context::scope(self.context.clone(), async {
+            if let Some(template) = &self.args.migrate().redirect_profile {
+                redirect_profile(
+                    &self.from_c,
+                    &self.to_c,
+                    template,
+                    self.args.migrate().redirect_avatar,
+                    self.args.dryrun,
+                )
+                .await?;
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    /// Deactivates the old account once the migration of every room is verified, if asked for
+    pub async fn deactivate(&mut self) -> anyhow::Result<()> {
+        context::scope(self.context.clone(), async {
+            let args = &self.args;
+            let from_c = &self.from_c;
+            let to_c = &self.to_c;
+            if !args.migrate().deactivate_old {
+                return Ok(());
+            }
+
+            self.to_sync.next().await?;
+
+            let unverified = audit::audit_rooms(from_c, to_c, &self.rooms, &self.plan)
+                .await?
+                .into_iter()
+                .filter(|a| !a.passed())
+                .map(|a| a.room_id)
+                .collect::<Vec<_>>();
+            if !self.failed_invites.is_empty() || !unverified.is_empty() {
+                warn!(
+                    "Not deactivating the old account, migration of {:?} couldn't be verified",
+                    self.failed_invites
+                        .iter()
+                        .chain(unverified.iter())
+                        .collect::<Vec<_>>()
+                );
+            } else if args.dryrun
+                || progress::confirm(
+                    &format!(
+                        "{} is about to be deactivated{}. This can't be undone.",
+                        from_c.user_id().unwrap(),
+                        if args.migrate().erase {
+                            " and its messages erased"
+                        } else {
+                            ""
+                        }
+                    ),
+                    "Deactivate the old account?",
+                    args.migrate().yes,
+                )?
+            {
+                match &args.connection.from_admin_token {
+                    Some(token) => {
+                        let admin = SynapseAdmin::new(
+                            self.from_login.http_client()?,
+                            from_c.homeserver(),
+                            token.clone(),
+                        );
+                        deactivate_as_admin(from_c, &admin, args.migrate().erase, args.dryrun)
+                            .await?;
+                    }
+                    None => {
+                        deactivate_account(
+                            from_c,
+                            &self.from_login.http_client()?,
+                            self.from_login.password.as_deref(),
+                            args.migrate().erase,
+                            args.dryrun,
+                        )
+                        .await?
+                    }
+                }
+                self.deactivated = !args.dryrun;
+            } else {
+                info!("Not deactivating the old account");
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    /// Keeps mirroring the rooms the old account joins onto the new one, every `--watch-interval`.
+    /// Runs until the process is stopped, updating the report file after every round.
+    pub async fn watch(&mut self) -> anyhow::Result<()> {
+        context::scope(self.context.clone(), async {
+            let mut known_rooms = self
+                .from_c
+                .joined_rooms()
+                .into_iter()
+                .map(|r| r.room_id().to_owned())
+                .collect::<BTreeSet<_>>();
+            let mut known_invites = self
+                .from_c
+                .invited_rooms()
+                .into_iter()
+                .map(|r| r.room_id().to_owned())
+                .collect::<BTreeSet<_>>();
+            // the initial run already warned about these
+            self.bridged.clear();
+            self.unforwarded_invites.clear();
+            self.manual_knocks.clear();
+            self.failed_dms.clear();
+
+            info!(
+                "--- Watching {} for new rooms every {}",
+                self.from_c.user_id().unwrap(),
+                humantime::format_duration(self.args.migrate().watch_interval)
+            );
+            loop {
+                report::finish(&self.from_c, &self.to_c, self.args.dryrun)
+                    .await
+                    .write(&self.args.migrate().report_file)?;
+                tokio::time::sleep(self.args.migrate().watch_interval).await;
+                self.from_sync.next().await?;
+
+                let args = &self.args;
+                // only the rooms that are new since the last round go through the filters, so
+                // e.g. `--active-since` doesn't look at the history of every room again
+                let invites = self
+                    .from_c
+                    .invited_rooms()
+                    .into_iter()
+                    .filter(|r| known_invites.insert(r.room_id().to_owned()))
+                    .collect();
+                let new_invites = args.migrate().filters.select(invites).await?;
+                if !new_invites.is_empty() {
+                    match args.migrate().forward_invites {
+                        Some(mode) => {
+                            let to_user = self.to_c.user_id().unwrap().to_owned();
+                            forward_invites(
+                                &self.from_c,
+                                &to_user,
+                                &new_invites,
+                                mode,
+                                args.dryrun,
+                            )
+                            .await?;
+                        }
+                        None => info!(
+                            "The old account was invited to {:?}, they're mirrored once it joins",
+                            new_invites
+                        ),
+                    }
+                }
+
+                let joined = self
+                    .from_c
+                    .joined_rooms()
+                    .into_iter()
+                    .filter(|r| known_rooms.insert(r.room_id().to_owned()))
+                    .collect();
+                let mut new_rooms = args.migrate().filters.select(joined).await?;
+                report::track(&new_rooms);
+                new_rooms.retain(|r| {
+                    if self.plan.skips(r) {
+                        report::skip(r, "skipped by the plan");
+                    }
+                    !self.plan.skips(r)
+                });
+                if !args.migrate().include_bridged {
+                    let bridged = bridged_rooms(&self.from_c, &new_rooms).await?;
+                    for room_id in &bridged {
+                        info!("Not mirroring bridged room {room_id}");
+                        report::skip(room_id, "bridged");
+                    }
+                    new_rooms.retain(|r| !bridged.contains(r));
+                }
+                hook::start_rooms(&self.from_c, &mut new_rooms).await;
+                if new_rooms.is_empty() {
+                    continue;
+                }
+
+                info!(
+                    "The old account joined {:?}, mirroring them onto the new one",
+                    new_rooms
+                );
+                self.rooms = new_rooms;
+                self.to_sync.next().await?;
+                self.invite().await?;
+            }
+        })
+        .await
+    }
+
+    /// Writes, sends and posts the report of the migration and ends the sessions
+    pub async fn finish(self) -> anyhow::Result<MigrationReport> {
+        context::scope(self.context.clone(), async move {
+            let args = &self.args;
+            let report = report::finish(&self.from_c, &self.to_c, args.dryrun).await;
+            report.log_summary();
+            report.write(&args.migrate().report_file)?;
+            if let Some(path) = &args.migrate().report_csv {
+                report.write_csv(path)?;
+            }
+            webhook::send(Event::RunFinished {
+                counters: &report.counters,
+            });
+            hook::finish_rooms(&report.rooms).await;
+            if args.migrate().report_room {
+                if args.dryrun {
+                    info!("Not posting the report of a dry run");
+                } else if let Err(e) = report.post(&self.to_c).await {
+                    warn!("Couldn't post the report to the new account: {e}");
+                }
+            }
+
+            webhook::flush().await;
+            self.logout().await?;
+            Ok(report)
+        })
+        .await
+    }
+}
+
+/// Invites the new account to the rooms of the old one as soon as sliding sync loads them,
+/// instead of waiting for all rooms to be loaded. Rooms needing a closer look (DMs, bridges,
+/// bans) and failed invites are left to the regular flow, which skips the rooms invited here.
+async fn invite_while_syncing(
+    args: &Args,
+    plan: &Plan,
+    from_sync: &mut Syncer,
+    from_c: &Client,
+    to_c: &Client,
+    limiter: &Limiter,
+    checkpoint: &Checkpoint,
+) -> anyhow::Result<()> {
+    let to_user = to_c.user_id().unwrap().to_owned();
+
+    while let Some(batch) = from_sync.next_batch().await? {
+        let mut rooms = Vec::new();
+        for room_id in batch {
+            let Some(room) = from_c.get_room(&room_id) else {
+                continue;
+            };
+            if room.state() != RoomState::Joined
+                || plan.skips(&room_id)
+                || to_c.get_room(&room_id).is_some()
+                || checkpoint.done(Step::Invited, &room_id)
+                || !args.migrate().filters.selects(&room).await?
+            {
+                continue;
+            }
+            if plan.recreates_dm(&room_id, args.migrate().recreate_dms) && room.is_direct().await? {
+                continue;
+            }
+            if plan.recreates(&room_id, args.migrate().recreate) {
+                continue;
+            }
+            if let Some(member) = room.get_member_no_sync(&to_user).await? {
+                if *member.membership() == MembershipState::Ban {
+                    continue;
+                }
+            }
+            if !acl_allows(&room, to_user.server_name()).await? {
+                continue;
+            }
+            rooms.push(room_id);
+        }
+        if !args.migrate().include_bridged {
+            let bridged = bridged_rooms(from_c, &rooms).await?;
+            rooms.retain(|r| !bridged.contains(r));
+        }
+        if rooms.is_empty() {
+            continue;
+        }
+
+        info!("Inviting to {} freshly loaded rooms", rooms.len());
+        join_all(rooms.iter().map(|room_id| {
+            let to_user = &to_user;
+            let span = progress::room_span("invite", from_c, room_id);
+            async move {
+                let Some(room) = from_c.get_room(room_id) else {
+                    return;
+                };
+                match limiter
+                    .run(from_c, || room.invite_user_by_id(to_user))
+                    .await
+                {
+                    Ok(()) => {
+                        checkpoint.record(Step::Invited, room_id);
+                        journal::record(room_id, Action::Invited);
+                        report::action(room_id, Action::Invited);
+                    }
+                    Err(e) => info!("Inviting to {room_id} failed ({e}), retrying later"),
+                }
+            }
+            .instrument(span)
+        }))
+        .await;
+    }
+    Ok(())
+}
+
+/// Synapse admins of the homeservers of both accounts, for what the accounts can't do themselves
+#[derive(Default)]
+struct Admins {
+    from: Option<SynapseAdmin>,
+    to: Option<SynapseAdmin>,
+}
+
+impl Admins {
+    fn is_empty(&self) -> bool {
+        self.from.is_none() && self.to.is_none()
+    }
+}
+
+/// Makes the new account of `to_c` admin of `room` through the admin API, where the old account
+/// can't raise its power level, and leaves it at `target`. An admin of the new homeserver makes
+/// the new account admin right away, which then lowers its own level to `target`. One of the old
+/// homeserver makes the old account admin, which then raises the new account's level to
+/// `target` and lowers its own back to where it was. Both only work through a local admin of the
+/// room.
+async fn make_room_admin(
+    admins: &Admins,
+    room: &Room,
+    to_c: &Client,
+    old_user: &UserId,
+    target: i64,
+    limiter: &Limiter,
+) -> anyhow::Result<()> {
+    let room_id = room.room_id();
+    let new_user = to_c.user_id().unwrap();
+    let target = Int::try_from(target)?;
+    let mut errors = Vec::new();
+    if let Some(admin) = &admins.to {
+        let made_admin = {
+            let _permit = limiter.acquire(admin.homeserver()).await;
+            admin.make_room_admin(room_id, new_user).await
+        };
+        match made_admin {
+            Ok(()) => {
+                info!("Made {new_user} admin of {room_id} through the admin API");
+                let mut power_levels = fresh_power_levels(to_c, room_id).await?;
+                if power_levels.users.get(new_user) != Some(&target) {
+                    power_levels.users.insert(new_user.to_owned(), target);
+                    send_power_levels(to_c, room_id, &power_levels, limiter).await?;
+                }
+                return Ok(());
+            }
+            Err(e) => errors.push(format!("{}: {e}", admin.homeserver())),
+        }
+    }
+    if let Some(admin) = &admins.from {
+        let from_c = room.client();
+        let power_levels = fresh_power_levels(&from_c, room_id).await?;
+        let previous = power_levels
+            .users
+            .get(old_user)
+            .copied()
+            .unwrap_or(power_levels.users_default);
+        let made_admin = {
+            let _permit = limiter.acquire(admin.homeserver()).await;
+            admin.make_room_admin(room_id, old_user).await
+        };
+        match made_admin {
+            Ok(()) => {
+                info!("Made {old_user} admin of {room_id} through the admin API");
+                journal::record_power_level_of(room_id, old_user, previous.into());
+                // the power levels synced so far don't know the old account is admin now
+                let mut power_levels = fresh_power_levels(&from_c, room_id).await?;
+                power_levels.users.insert(new_user.to_owned(), target);
+                power_levels.users.insert(old_user.to_owned(), previous);
+                if let Err(e) = send_power_levels(&from_c, room_id, &power_levels, limiter).await {
+                    report::manual(
+                        format!("{old_user} in {room_id}"),
+                        format!(
+                            "The old account was made admin to raise the new account's power \
+                            level. Lower its power level back to {previous}"
+                        ),
+                    );
+                    return Err(e);
+                }
+                info!("Lowered the power level of {old_user} in {room_id} back to {previous}");
+                return Ok(());
+            }
+            Err(e) => errors.push(format!("{}: {e}", admin.homeserver())),
+        }
+    }
+    anyhow::bail!("the admin API couldn't help either ({})", errors.join("; "))
+}
+
+/// The power levels of `room_id` as the homeserver has them now, the synced ones may be outdated
+async fn fresh_power_levels(
+    c: &Client,
+    room_id: &RoomId,
+) -> anyhow::Result<RoomPowerLevelsEventContent> {
+    let request = get_state_events_for_key::v3::Request::new(
+        room_id.to_owned(),
+        StateEventType::RoomPowerLevels,
+        String::new(),
+    );
+    let response = c.send(request, None).await?;
+    Ok(response
+        .content
+        .deserialize_as::<RoomPowerLevelsEventContent>()?)
+}
+
+/// Sends `power_levels` to `room_id` as the account of `c`, which may not have synced the room
+async fn send_power_levels(
+    c: &Client,
+    room_id: &RoomId,
+    power_levels: &RoomPowerLevelsEventContent,
+    limiter: &Limiter,
+) -> anyhow::Result<()> {
+    let request =
+        send_state_event::v3::Request::new(room_id.to_owned(), &EmptyStateKey, power_levels)?;
+    limiter
+        .run(c, || async { Ok(c.send(request.clone(), None).await?) })
+        .await?;
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn ensure_power_levels(
+    from_c: &Client,
+    to_c: &Client,
+    new_username: OwnedUserId,
+    rooms: &Vec<&OwnedRoomId>,
+    plan: &Plan,
+    admins: &Admins,
+    limiter: &Limiter,
+    checkpoint: &Checkpoint,
+    dryrun: bool,
+) -> anyhow::Result<()> {
+    let bar = progress::phase("Power levels", rooms.len());
+    try_join_all(rooms.iter().map(|room_id| {
+        let from_c = from_c.clone();
+        let self_id = from_c.user_id().unwrap().to_owned();
+        let user_id = new_username.clone();
+        let bar = &bar;
+        let span = progress::room_span("power level", &from_c, room_id);
+        async move {
+            let _step = progress::step(bar, &from_c, room_id);
+            if checkpoint.done(Step::PowerLevel, room_id) {
+                info!("Power level in {room_id} was adjusted in a previous run.");
+                return anyhow::Ok(());
+            }
+            let Some(joined) = from_c.get_room(room_id) else {
+                return anyhow::Ok(());
+            };
+
+            let Some(me) = sync::member(&joined, &self_id).await? else {
+                warn!("{self_id} isn't member of {room_id}. Skipping power_level ensuring.");
+                return anyhow::Ok(());
+            };
+
+            let Some(new_acc) = sync::member(&joined, &user_id).await? else {
+                warn!("{user_id} isn't member of {room_id}. Skipping power_level ensuring.");
+                return anyhow::Ok(());
+            };
+
+            let target_power_level = plan.target_power_level(room_id, me.power_level());
+
+            if target_power_level <= new_acc.power_level() {
+                info!("Power levels of {user_id} and {self_id} in {room_id} are fine.");
+                report::power_levels(room_id, me.power_level(), new_acc.power_level());
+                return anyhow::Ok(());
+            }
+
+            info!(
+                "Trying to adjust power_level of {user_id} in {room_id} to {target_power_level}."
+            );
+
+            if dryrun {
+                report::action(room_id, Action::PowerLevel);
+                report::power_levels(room_id, me.power_level(), target_power_level);
+                return anyhow::Ok(());
+            }
+
+            let level = Int::try_from(target_power_level)?;
+            if let Err(e) = limiter
+                .run(&from_c, || {
+                    joined.update_power_levels(vec![(&user_id, level)])
+                })
+                .await
+            {
+                error::abort_if_fatal(&e)?;
+                let e = MatrixError::of(&e);
+                let fallback = if e.reason() == Reason::NoPermission && !admins.is_empty() {
+                    make_room_admin(admins, &joined, to_c, &self_id, target_power_level, limiter)
+                        .await
+                        .map_err(|e| format!(", {e}"))
+                } else {
+                    Err(String::new())
+                };
+                if let Err(fallback) = fallback {
+                    warn!("Couldn't update power levels for {user_id} in {room_id}: {e}{fallback}");
+                    report::fail(
+                        room_id,
+                        e.reason(),
+                        format!("updating the power level failed: {e}{fallback}"),
+                    )?;
+                    report::power_levels(room_id, me.power_level(), new_acc.power_level());
+                    return anyhow::Ok(());
+                }
+                checkpoint.record(Step::PowerLevel, room_id);
+                journal::record_power_level(room_id, new_acc.power_level());
+                report::action(room_id, Action::PowerLevel);
+                report::power_levels(room_id, me.power_level(), target_power_level);
+            } else {
+                checkpoint.record(Step::PowerLevel, room_id);
+                journal::record_power_level(room_id, new_acc.power_level());
+                report::action(room_id, Action::PowerLevel);
+                report::power_levels(room_id, me.power_level(), target_power_level);
+            }
+
+            Ok(())
+        }
+        .instrument(span)
+    }))
+    .await?;
+    bar.finish();
+    Ok(())
+}
+
+/// Rooms among `rooms` where the new account's power level is below the one it should get,
+/// going by the room state `c` knows of.
+async fn power_level_gaps<'a>(
+    c: &Client,
+    old_user: &UserId,
+    new_user: &UserId,
+    rooms: &[&'a OwnedRoomId],
+    plan: &Plan,
+) -> anyhow::Result<Vec<&'a OwnedRoomId>> {
+    let mut gaps = Vec::new();
+
+    for room_id in rooms {
+        let Some(joined) = c.get_room(room_id) else {
+            continue;
+        };
+        let Some(me) = sync::member(&joined, old_user).await? else {
+            continue;
+        };
+        let below = match sync::member(&joined, new_user).await? {
+            Some(new_acc) => {
+                plan.target_power_level(room_id, me.power_level()) > new_acc.power_level()
+            }
+            None => true,
+        };
+        if below {
+            gaps.push(*room_id);
+        }
+    }
+
+    Ok(gaps)
+}
+
+/// Rooms among `rooms` the new account is banned from and, with `unban`, couldn't be unbanned.
+async fn banned_rooms(
+    from_c: &Client,
+    new_user: &OwnedUserId,
+    rooms: &Vec<&OwnedRoomId>,
+    unban: bool,
+    dryrun: bool,
+) -> anyhow::Result<Vec<OwnedRoomId>> {
+    let self_id = from_c.user_id().unwrap().to_owned();
+    let mut banned = Vec::new();
+
+    for room_id in rooms {
+        let Some(joined) = from_c.get_room(room_id) else {
+            continue;
+        };
+        let Some(member) = sync::member(&joined, new_user).await? else {
+            continue;
+        };
+        if *member.membership() != MembershipState::Ban {
+            continue;
+        }
+
+        if !unban {
+            warn!("{new_user} is banned from {room_id}. Skipping invite.");
+            report::skip(room_id, "banned");
+            banned.push(room_id.to_owned().clone());
+            continue;
+        }
+        if !joined.can_user_ban(&self_id).await? {
+            warn!(
+                "{new_user} is banned from {room_id} and {self_id} can't unban. Skipping invite."
+            );
+            report::skip(room_id, "banned, can't unban");
+            banned.push(room_id.to_owned().clone());
+            continue;
+        }
+
+        info!("Unbanning {new_user} in {room_id}");
+        if dryrun {
+            report::action(room_id, Action::Unbanned);
+            continue;
+        }
+        match joined.unban_user(new_user, Some("Account migration")).await {
+            Ok(_) => {
+                journal::record(room_id, Action::Unbanned);
+                report::action(room_id, Action::Unbanned);
+            }
+            Err(e) => {
+                error::abort_if_fatal(&e)?;
+                let e = MatrixError::of(&e);
+                warn!("Unbanning {new_user} in {room_id} failed: {e}. Skipping invite.");
+                report::fail(room_id, e.reason(), format!("unbanning failed: {e}"))?;
+                banned.push(room_id.to_owned().clone());
+            }
+        }
+    }
+
+    Ok(banned)
+}
+
+/// The power level members of `room` have unless given another one
+async fn users_default(room: &Room) -> anyhow::Result<i64> {
+    let Some(power_levels) = room
+        .get_state_event_static::<RoomPowerLevelsEventContent>()
+        .await?
+    else {
+        return Ok(0);
+    };
+    Ok(match power_levels.deserialize()? {
+        SyncOrStrippedState::Sync(SyncStateEvent::Original(power_levels)) => {
+            power_levels.content.users_default.into()
+        }
+        _ => 0,
+    })
+}
+
+/// Whether the server ACL of `room`, if any, lets `server` take part in it
+async fn acl_allows(room: &Room, server: &ServerName) -> anyhow::Result<bool> {
+    let Some(acl) = room
+        .get_state_event_static::<RoomServerAclEventContent>()
+        .await?
+    else {
+        return Ok(true);
+    };
+    Ok(match acl.deserialize()? {
+        SyncOrStrippedState::Sync(SyncStateEvent::Original(acl)) => acl.content.is_allowed(server),
+        _ => true,
+    })
+}
+
+/// Rooms among `rooms` whose server ACL denies the new account's homeserver, so inviting it
+/// would only end in a federation error.
+async fn acl_blocked_rooms(
+    from_c: &Client,
+    new_user: &OwnedUserId,
+    rooms: &Vec<&OwnedRoomId>,
+) -> anyhow::Result<Vec<OwnedRoomId>> {
+    let server = new_user.server_name();
+    let mut blocked = Vec::new();
+
+    for room_id in rooms {
+        let Some(joined) = from_c.get_room(room_id) else {
+            continue;
+        };
+        if !acl_allows(&joined, server).await? {
+            warn!("The server ACL of {room_id} denies {server}. Skipping invite.");
+            report::skip(
+                room_id,
+                format!("blocked by server ACL, ask a moderator to allow {server}"),
+            );
+            blocked.push(room_id.to_owned().clone());
+        }
+    }
+
+    Ok(blocked)
+}
+
+async fn accept_invites(
+    to_c: &Client,
+    rooms: &Vec<&OwnedRoomId>,
+    limiter: &Limiter,
+    checkpoint: &Checkpoint,
+    dryrun: bool,
+) -> anyhow::Result<Vec<OwnedRoomId>> {
+    let bar = progress::phase("Accepting", rooms.len());
+
+    // joins of rooms on the same server run one after the other, so a slow or rate limiting
+    // server doesn't take up all slots of the limiter
+    let mut by_server = BTreeMap::<_, Vec<_>>::new();
+    for room_id in rooms {
+        by_server
+            .entry(room_id.server_name().map(ToOwned::to_owned))
+            .or_default()
+            .push(room_id);
+    }
+
+    let pending = try_join_all(by_server.into_values().map(|rooms| {
+        let bar = &bar;
+        async move {
+            let mut pending = Vec::new();
+            for room_id in rooms {
+                let _step = progress::step(bar, to_c, room_id);
+                let invited = async {
+                    let Some(invited) = to_c.get_room(room_id) else {
+                        return anyhow::Ok(false);
+                    };
+                    info!(
+                        "Accepting invite for {}({})",
+                        invited.display_name().await?,
+                        invited.room_id()
+                    );
+                    if dryrun {
+                        report::action(room_id, Action::Accepted);
+                        return Ok(true);
+                    }
+                    if let Err(e) = limiter.run(to_c, || invited.join()).await {
+                        error::abort_if_fatal(&e)?;
+                        let e = MatrixError::of(&e);
+                        warn!("Accepting the invite to {room_id} failed: {e}");
+                        report::fail(room_id, e.reason(), format!("accepting failed: {e}"))?;
+                        // not pending anymore, there's no point in trying again
+                        return Ok(true);
+                    }
+                    checkpoint.record(Step::Accepted, room_id);
+                    journal::record(room_id, Action::Accepted);
+                    report::action(room_id, Action::Accepted);
+                    Ok(true)
+                }
+                .instrument(progress::room_span("accept", to_c, room_id))
+                .await?;
+                if !invited {
+                    pending.push(room_id.to_owned().clone());
+                }
+            }
+            anyhow::Ok(pending)
+        }
+    }))
+    .await?
+    .into_iter()
+    .flatten()
+    .collect();
+    // the accept phase repeats until everything is accepted, don't keep a bar for every round
+    bar.finish_and_clear();
+
+    Ok(pending)
+}
+
+async fn send_invites(
+    from_c: &Client,
+    rooms: &Vec<&OwnedRoomId>,
+    user_id: OwnedUserId,
+    limiter: &Limiter,
+    checkpoint: &Checkpoint,
+    dryrun: bool,
+) -> anyhow::Result<Vec<FailedInvite>> {
+    let bar = progress::phase("Inviting", rooms.len());
+    let failed = try_join_all(rooms.iter().map(|room_id| {
+        let from_c = from_c.clone();
+        let user_id = user_id.clone();
+        let bar = &bar;
+        let span = progress::room_span("invite", &from_c, room_id);
+        async move {
+            let _step = progress::step(bar, &from_c, room_id);
+            if checkpoint.done(Step::Invited, room_id) {
+                info!("Already invited to {room_id}");
+                return Ok(None);
+            }
+            let Some(joined) = from_c.get_room(room_id) else {
+                warn!("Can't invite user to {:}: not a member myself", room_id);
+                report::fail(
+                    room_id,
+                    Reason::NoPermission,
+                    "the old account isn't a member",
+                )?;
+                return Ok(Some(FailedInvite {
+                    room_id: room_id.to_owned().clone(),
+                    retry: None,
+                }));
+            };
+            info!(
+                "Inviting to {room_id} ({})",
+                joined.display_name().await.unwrap()
+            );
+
+            if !dryrun {
+                if let Err(e) = limiter
+                    .run(&from_c, || joined.invite_user_by_id(&user_id))
+                    .await
+                {
+                    error::abort_if_fatal(&e)?;
+                    let e = MatrixError::of(&e);
+                    let reason = e.reason();
+                    let mut retry = None;
+                    if reason.is_transient() {
+                        info!("Inviting to {room_id} failed ({e}), retrying later");
+                        retry = Some(Failure {
+                            reason,
+                            message: format!("inviting failed: {e}"),
+                        });
+                    } else if restricted_to(&joined).is_some() {
+                        info!(
+                            "Inviting to {room_id} failed ({e}), trying to join it directly later"
+                        );
+                    } else if joined.join_rule() == JoinRule::Knock {
+                        info!("Inviting to {room_id} failed ({e}), knocking instead");
+                    } else {
+                        warn!("Inviting to {:} failed: {e}", room_id);
+                        report::fail(room_id, reason, format!("inviting failed: {e}"))?;
+                    }
+                    return Ok(Some(FailedInvite {
+                        room_id: room_id.to_owned().clone(),
+                        retry,
+                    }));
+                }
+                checkpoint.record(Step::Invited, room_id);
+                journal::record(room_id, Action::Invited);
+            }
+            report::action(room_id, Action::Invited);
+            anyhow::Ok(None)
+        }
+        .instrument(span)
+    }))
+    .await?
+    .into_iter()
+    .flatten()
+    .collect();
+    bar.finish();
+    Ok(failed)
+}
+
+/// An invite that failed. Failures for a passing reason aren't reported yet, they're retried later
+/// in the run.
+struct FailedInvite {
+    room_id: OwnedRoomId,
+    retry: Option<Failure>,
+}
+
+/// Retries the invites that failed for a passing reason in rounds with a growing pause, accepting
+/// the ones that go through. Returns the rooms the new account still couldn't be invited to,
+/// reporting the failures given up on.
+async fn retry_invites(
+    args: &Args,
+    from_c: &Client,
+    to_c: &Client,
+    to_sync: &mut Syncer,
+    mut failed: Vec<FailedInvite>,
+    limiter: &Limiter,
+    checkpoint: &Checkpoint,
+) -> anyhow::Result<Vec<OwnedRoomId>> {
+    let to_user = to_c.user_id().unwrap().to_owned();
+    for round in 0..args.migrate().invite_retries {
+        let retry = failed
+            .iter()
+            .filter(|f| f.retry.is_some())
+            .map(|f| f.room_id.clone())
+            .collect::<Vec<_>>();
+        if retry.is_empty() || args.dryrun {
+            break;
+        }
+
+        let pause = INVITE_RETRY_BACKOFF.saturating_mul(1 << round);
+        info!(
+            "Retrying {} invites in {} ({}/{})",
+            retry.len(),
+            humantime::format_duration(pause),
+            round + 1,
+            args.migrate().invite_retries
+        );
+        tokio::time::sleep(pause).await;
+
+        let retry = retry.iter().collect::<Vec<_>>();
+        let still_failed =
+            send_invites(from_c, &retry, to_user.clone(), limiter, checkpoint, false).await?;
+        let mut awaiting = retry
+            .into_iter()
+            .filter(|r| !still_failed.iter().any(|f| f.room_id == **r))
+            .cloned()
+            .collect::<Vec<_>>();
+        failed.retain(|f| f.retry.is_none());
+        failed.extend(still_failed);
+
+        while !awaiting.is_empty() {
+            to_sync.next().await?;
+            awaiting = accept_invites(to_c, &awaiting.iter().collect(), limiter, checkpoint, false)
+                .await?;
+        }
+    }
+
+    let mut given_up = Vec::new();
+    for f in failed {
+        if let Some(failure) = f.retry {
+            warn!("Giving up inviting to {}: {}", f.room_id, failure.message);
+            report::fail(&f.room_id, failure.reason, failure.message)?;
+        }
+        given_up.push(f.room_id);
+    }
+    Ok(given_up)
+}
+
+/// The rooms whose members may join `room` without an invite, if its join rule is restricted
+fn restricted_to(room: &Room) -> Option<Vec<OwnedRoomId>> {
+    match room.join_rule() {
+        JoinRule::Restricted(restricted) | JoinRule::KnockRestricted(restricted) => Some(
+            restricted
+                .allow
+                .into_iter()
+                .filter_map(|rule| match rule {
+                    AllowRule::RoomMembership(membership) => Some(membership.room_id),
+                    _ => None,
+                })
+                .collect(),
+        ),
+        _ => None,
+    }
+}
+
+/// Joins the new account to the public rooms among `rooms`, through the servers of the old
+/// account's view of their members. Returns the rooms joined, the others go through an invite.
+async fn join_public(
+    from_c: &Client,
+    to_c: &Client,
+    rooms: &[&OwnedRoomId],
+    limiter: &Limiter,
+    checkpoint: &Checkpoint,
+    dryrun: bool,
+) -> anyhow::Result<Vec<OwnedRoomId>> {
+    let mut joined = Vec::new();
+    for room_id in rooms {
+        let Some(room) = from_c.get_room(room_id) else {
+            continue;
+        };
+        if room.join_rule() != JoinRule::Public {
+            continue;
+        }
+
+        info!("Joining public room {room_id} directly");
+        if dryrun {
+            report::action(room_id, Action::Joined);
+            joined.push((*room_id).clone());
+            continue;
+        }
+        let via = room.route().await?;
+        let target = OwnedRoomOrAliasId::from((*room_id).clone());
+        match limiter
+            .run(to_c, || to_c.join_room_by_id_or_alias(&target, &via))
+            .await
+        {
+            Ok(_) => {
+                checkpoint.record(Step::Accepted, room_id);
+                journal::record(room_id, Action::Joined);
+                report::action(room_id, Action::Joined);
+                joined.push((*room_id).clone());
+            }
+            Err(e) => {
+                error::abort_if_fatal(&e)?;
+                info!(
+                    "Joining {room_id} directly failed ({}), inviting instead",
+                    MatrixError::of(&e)
+                );
+            }
+        }
+    }
+    Ok(joined)
+}
+
+/// Joins the new account to the restricted rooms among `rooms` it may join as a member of one of
+/// the allowed rooms. Returns the rooms joined. With `report_failures`, the restricted rooms it
+/// can't join are reported as failed, their failed invites weren't.
+async fn join_restricted(
+    from_c: &Client,
+    to_c: &Client,
+    rooms: &[&OwnedRoomId],
+    limiter: &Limiter,
+    checkpoint: &Checkpoint,
+    dryrun: bool,
+    report_failures: bool,
+) -> anyhow::Result<Vec<OwnedRoomId>> {
+    let mut joined = Vec::new();
+    for room_id in rooms {
+        let Some(room) = from_c.get_room(room_id) else {
+            continue;
+        };
+        let Some(allowed) = restricted_to(&room) else {
+            continue;
+        };
+        let member_of = allowed.iter().find(|allowed_id| {
+            to_c.get_room(allowed_id)
+                .is_some_and(|r| r.state() == RoomState::Joined)
+        });
+        let Some(member_of) = member_of else {
+            if report_failures {
+                warn!("Only members of {allowed:?} can join {room_id} without an invite. Migrate one of them first.");
+                report::fail(
+                    room_id,
+                    Reason::NoPermission,
+                    format!("inviting failed and the new account isn't in any of the rooms allowed to join ({allowed:?})"),
+                )?;
+            }
+            continue;
+        };
+
+        info!("Joining {room_id} directly as member of {member_of}");
+        if dryrun {
+            report::action(room_id, Action::Joined);
+            joined.push(room_id.to_owned().clone());
+            continue;
+        }
+        let via = room.route().await?;
+        let target = OwnedRoomOrAliasId::from((*room_id).clone());
+        match limiter
+            .run(to_c, || to_c.join_room_by_id_or_alias(&target, &via))
+            .await
+        {
+            Ok(_) => {
+                checkpoint.record(Step::Accepted, room_id);
+                journal::record(room_id, Action::Joined);
+                report::action(room_id, Action::Joined);
+                joined.push(room_id.to_owned().clone());
+            }
+            Err(e) => {
+                error::abort_if_fatal(&e)?;
+                let e = MatrixError::of(&e);
+                warn!("Joining {room_id} directly failed: {e}");
+                if report_failures {
+                    report::fail(
+                        room_id,
+                        e.reason(),
+                        format!("inviting and joining directly failed: {e}"),
+                    )?;
+                }
+            }
+        }
+    }
+    Ok(joined)
+}
+
+/// Joins `user_id` to `rooms` through the admin API of its homeserver. Returns the rooms it
+/// has been joined to, the others have to go through an invite.
+async fn force_join(
+    admin: &SynapseAdmin,
+    from_c: &Client,
+    rooms: &[&OwnedRoomId],
+    user_id: &UserId,
+    limiter: &Limiter,
+    checkpoint: &Checkpoint,
+    dryrun: bool,
+) -> Vec<OwnedRoomId> {
+    let bar = progress::phase("Joining", rooms.len());
+    let joined = join_all(rooms.iter().map(|room_id| {
+        let bar = &bar;
+        let span = progress::room_span("join", from_c, room_id);
+        async move {
+            let _step = progress::step(bar, from_c, room_id);
+            if checkpoint.done(Step::Accepted, room_id) {
+                info!("Already joined {room_id} in a previous run");
+                return Some(room_id.to_owned().clone());
+            }
+            info!("Joining {user_id} to {room_id} through the admin API");
+            if dryrun {
+                report::action(room_id, Action::Joined);
+                return Some(room_id.to_owned().clone());
+            }
+
+            let result = {
+                let _permit = limiter.acquire(admin.homeserver()).await;
+                admin.join(room_id, user_id).await
+            };
+            match result {
+                Ok(()) => {
+                    checkpoint.record(Step::Accepted, room_id);
+                    journal::record(room_id, Action::Joined);
+                    report::action(room_id, Action::Joined);
+                    Some(room_id.to_owned().clone())
+                }
+                Err(e) => {
+                    info!("Can't join {room_id} through the admin API ({e}), inviting instead");
+                    None
+                }
+            }
+        }
+        .instrument(span)
+    }))
+    .await
+    .into_iter()
+    .flatten()
+    .collect();
+    bar.finish();
+    joined
+}
+
+/// The IDs of the `rooms` `filters` select, or of the ones among `picked` if the user picked
+/// the rooms already
+async fn select_picked(
+    filters: &FilterArgs,
+    rooms: Vec<Room>,
+    picked: Option<&BTreeSet<OwnedRoomId>>,
+) -> anyhow::Result<Vec<OwnedRoomId>> {
+    match picked {
+        Some(picked) => Ok(rooms
+            .iter()
+            .map(|r| r.room_id().to_owned())
+            .filter(|r| picked.contains(r))
+            .collect()),
+        None => filters.select(rooms).await,
+    }
+}
+
+async fn forward_invites(
+    from_c: &Client,
+    to_user: &OwnedUserId,
+    rooms: &Vec<OwnedRoomId>,
+    mode: InviteForwarding,
+    dryrun: bool,
+) -> anyhow::Result<Vec<OwnedRoomId>> {
+    let mut unforwarded = Vec::new();
+    for room_id in rooms {
+        let Some(invited) = from_c.get_room(room_id) else {
+            continue;
+        };
+        let display_name = invited.display_name().await?;
+
+        match mode {
+            InviteForwarding::Join => {
+                info!("Accepting pending invite of old account for {display_name}({room_id})");
+                if dryrun {
+                    continue;
+                }
+                if let Err(e) = invited.join().await {
+                    warn!("Joining {room_id} with the old account failed: {e}");
+                    unforwarded.push(room_id.to_owned());
+                }
+            }
+            InviteForwarding::Request => {
+                let Some(inviter) = invited.invite_details().await?.inviter else {
+                    warn!("Don't know who invited to {room_id}. Can't request a new invite.");
+                    unforwarded.push(room_id.to_owned());
+                    continue;
+                };
+                let inviter_id = inviter.user_id();
+
+                // look for an existing DM with the inviter to ask in
+                let Some(dm) = from_c
+                    .joined_rooms()
+                    .into_iter()
+                    .find(|r| r.direct_targets().contains(inviter_id))
+                else {
+                    warn!("No DM with {inviter_id} to request an invite to {room_id} in.");
+                    unforwarded.push(room_id.to_owned());
+                    continue;
+                };
+
+                info!("Asking {inviter_id} to invite {to_user} to {display_name}({room_id})");
+                if dryrun {
+                    continue;
+                }
+                let content = RoomMessageEventContent::text_plain(format!(
+                    "Hi! I'm moving to {to_user}. Could you invite that account to {display_name} ({room_id}) instead?"
+                ));
+                if let Err(e) = dm.send(content).await {
+                    warn!("Requesting an invite to {room_id} from {inviter_id} failed: {e}");
+                    unforwarded.push(room_id.to_owned());
+                }
+            }
+        }
+    }
+
+    Ok(unforwarded)
+}
+
+/// Rooms the logged in account has knocked on, with their join rule if known.
+///
+/// The sdk doesn't keep track of knocks, so this performs its own sync that
+/// leaves out everything but the join rules.
+async fn knocked_rooms(c: &Client) -> anyhow::Result<Vec<(OwnedRoomId, Option<JoinRule>)>> {
+    let mut room_filter = RoomFilter::empty();
+    room_filter.timeline = RoomEventFilter::ignore_all();
+    room_filter.ephemeral = RoomEventFilter::ignore_all();
+    room_filter.account_data = RoomEventFilter::ignore_all();
+    room_filter.state.types = Some(vec![StateEventType::RoomJoinRules.to_string()]);
+
+    let mut filter = FilterDefinition::ignore_all();
+    filter.room = room_filter;
+
+    let mut request = sync_events::v3::Request::new();
+    request.filter = Some(sync_events::v3::Filter::FilterDefinition(filter));
+    let response = c.send(request, None).await?;
+
+    Ok(response
+        .rooms
+        .knock
+        .into_iter()
+        .map(|(room_id, knocked)| {
+            let join_rule = knocked
+                .knock_state
+                .events
+                .iter()
+                .find_map(|e| match e.deserialize() {
+                    Ok(AnyStrippedStateEvent::RoomJoinRules(ev)) => Some(ev.content.join_rule),
+                    _ => None,
+                });
+            (room_id, join_rule)
+        })
+        .collect())
+}
+
+async fn migrate_knocks(
+    from_c: &Client,
+    to_c: &Client,
+    rooms: Vec<(OwnedRoomId, Option<JoinRule>)>,
+    dryrun: bool,
+) -> anyhow::Result<Vec<OwnedRoomId>> {
+    let old_user = from_c.user_id().unwrap().to_owned();
+    let mut manual = Vec::new();
+
+    for (room_id, join_rule) in rooms {
+        if to_c.get_room(&room_id).is_some() {
+            info!("New account already knows {room_id}. Skipping knock.");
+            continue;
+        }
+
+        if !matches!(
+            join_rule,
+            Some(JoinRule::Knock | JoinRule::KnockRestricted(_))
+        ) {
+            warn!("{room_id} doesn't allow knocking (anymore). Skipping knock.");
+            manual.push(room_id);
+            continue;
+        }
+
+        info!("Knocking on {room_id}");
+        if dryrun {
+            continue;
+        }
+
+        let via = room_id
+            .server_name()
+            .into_iter()
+            .chain([old_user.server_name()])
+            .map(ToOwned::to_owned)
+            .collect();
+        if let Err(e) = knock(to_c, &room_id, via, &old_user).await {
+            warn!("Knocking on {room_id} failed: {e}");
+            manual.push(room_id);
+        }
+    }
+
+    Ok(manual)
+}
+
+/// Knocks on `room_id` with the new account, through the servers `via`, with the migration of
+/// `old_user` as the reason
+async fn knock(
+    to_c: &Client,
+    room_id: &RoomId,
+    via: Vec<OwnedServerName>,
+    old_user: &UserId,
+) -> matrix_sdk::HttpResult<knock_room::v3::Response> {
+    let mut request = knock_room::v3::Request::new(room_id.to_owned().into());
+    request.reason = Some(format!("Account migration of {old_user}"));
+    request.server_name = via;
+    to_c.send(request, None).await
+}
+
+/// Knocks with the new account on the rooms among `failed` whose join rule allows knocking, as
+/// the invite to them failed for good. Returns the rooms knocked on, they're taken out of
+/// `failed`. Until a moderator lets the new account in, they're pending.
+async fn knock_failed_invites(
+    from_c: &Client,
+    to_c: &Client,
+    failed: &mut Vec<FailedInvite>,
+    limiter: &Limiter,
+    dryrun: bool,
+) -> anyhow::Result<Vec<OwnedRoomId>> {
+    let old_user = from_c.user_id().unwrap();
+    let mut knocked = Vec::new();
+    for f in failed.iter().filter(|f| f.retry.is_none()) {
+        let Some(room) = from_c.get_room(&f.room_id) else {
+            continue;
+        };
+        if !matches!(
+            room.join_rule(),
+            JoinRule::Knock | JoinRule::KnockRestricted(_)
+        ) {
+            continue;
+        }
+
+        info!("Knocking on {} with the new account", f.room_id);
+        if !dryrun {
+            let via = room.route().await?;
+            if let Err(e) = limiter
+                .run(to_c, || async {
+                    Ok(knock(to_c, &f.room_id, via.clone(), old_user).await?)
+                })
+                .await
+            {
+                error::abort_if_fatal(&e)?;
+                let e = MatrixError::of(&e);
+                warn!("Inviting to {} and knocking failed: {e}", f.room_id);
+                report::fail(
+                    &f.room_id,
+                    e.reason(),
+                    format!("inviting and knocking failed: {e}"),
+                )?;
+                continue;
+            }
+            journal::record(&f.room_id, Action::Knocked);
+        }
+        report::action(&f.room_id, Action::Knocked);
+        knocked.push(f.room_id.clone());
+    }
+    failed.retain(|f| !knocked.contains(&f.room_id));
+    Ok(knocked)
+}
+
+/// Localparts of the bots of common bridges
+const BRIDGE_BOTS: &[&str] = &[
+    "telegrambot",
+    "whatsappbot",
+    "signalbot",
+    "discordbot",
+    "_discord_bot",
+    "slackbot",
+    "facebookbot",
+    "instagrambot",
+    "googlechatbot",
+    "gmessagesbot",
+    "imessagebot",
+    "twitterbot",
+    "linkedinbot",
+    "heisenbridge",
+    "appservice-irc",
+    "ircbot",
+];
+
+/// Rooms among `rooms` that are managed by a bridge, detected either via bridge state events or
+/// a known bridge bot holding the highest power level.
+async fn bridged_rooms(c: &Client, rooms: &Vec<OwnedRoomId>) -> anyhow::Result<Vec<OwnedRoomId>> {
+    let mut bridged = Vec::new();
+    for room_id in rooms {
+        let Some(room) = c.get_room(room_id) else {
+            continue;
+        };
+
+        let mut has_bridge_state = false;
+        for event_type in ["m.bridge", "uk.half-shot.bridge"] {
+            if !room.get_state_events(event_type.into()).await?.is_empty() {
+                has_bridge_state = true;
+            }
+        }
+
+        // the power levels list the bot even though the members are lazy-loaded, only its
+        // membership is looked up
+        let mut bot = None;
+        if let Some(power_levels) = room
+            .get_state_event_static::<RoomPowerLevelsEventContent>()
+            .await?
+        {
+            let users = power_levels.deserialize()?.power_levels().users;
+            let max_power_level = users.values().max().copied();
+            for (user_id, power_level) in &users {
+                if Some(*power_level) != max_power_level
+                    || !BRIDGE_BOTS.contains(&user_id.localpart())
+                {
+                    continue;
+                }
+                let member = sync::member(&room, user_id).await?;
+                if member.is_some_and(|m| *m.membership() == MembershipState::Join) {
+                    bot = Some(user_id.clone());
+                    break;
+                }
+            }
+        }
+
+        if has_bridge_state || bot.is_some() {
+            info!(
+                "{}({room_id}) is bridged{}",
+                room.display_name().await?,
+                bot.map(|b| format!(" by {b}")).unwrap_or_default()
+            );
+            bridged.push(room_id.to_owned());
+        }
+    }
+    Ok(bridged)
+}
+
+/// The 1:1 DMs among `rooms`, with their counterpart.
+async fn direct_messages(
+    c: &Client,
+    rooms: &Vec<OwnedRoomId>,
+) -> anyhow::Result<Vec<(OwnedRoomId, OwnedUserId)>> {
+    let mut dms = Vec::new();
+    for room_id in rooms {
+        let Some(room) = c.get_room(room_id) else {
+            continue;
+        };
+        if !room.is_direct().await? {
+            continue;
+        }
+        let targets = room.direct_targets();
+        if targets.len() != 1 {
+            continue;
+        }
+        if let Some(target) = targets.into_iter().next() {
+            dms.push((room_id.to_owned(), target));
+        }
+    }
+    Ok(dms)
+}
+
+async fn recreate_dms(
+    from_c: &Client,
+    to_c: &Client,
+    dms: &Vec<(OwnedRoomId, OwnedUserId)>,
+    handoff_message: Option<&str>,
+    dryrun: bool,
+) -> anyhow::Result<Vec<OwnedUserId>> {
+    let old_user = from_c.user_id().unwrap().to_owned();
+    let handoff_message = handoff_message.map(|m| m.replace("{old_user}", old_user.as_str()));
+    let mut failed = Vec::new();
+
+    for (room_id, target) in dms {
+        if to_c
+            .joined_rooms()
+            .into_iter()
+            .chain(to_c.invited_rooms())
+            .any(|r| r.direct_targets().contains(target))
+        {
+            info!("New account already has a DM with {target}. Skipping {room_id}.");
+            continue;
+        }
+
+        info!("Re-creating DM {room_id} with {target}");
+        if dryrun {
+            report::action(room_id, Action::DmRecreated);
+            continue;
+        }
+
+        let dm = match to_c.create_dm(target).await {
+            Ok(dm) => dm,
+            Err(e) => {
+                error::abort_if_fatal(&e)?;
+                let e = MatrixError::of(&e);
+                warn!("Creating a DM with {target} failed: {e}");
+                report::fail(
+                    room_id,
+                    e.reason(),
+                    format!("re-creating the DM failed: {e}"),
+                )?;
+                failed.push(target.to_owned());
+                continue;
+            }
+        };
+        journal::record_dm(room_id, dm.room_id());
+        report::action(room_id, Action::DmRecreated);
+
+        if let Some(message) = &handoff_message {
+            if let Err(e) = dm.send(RoomMessageEventContent::text_plain(message)).await {
+                warn!("Couldn't post hand-off message to {target}: {e}");
+            }
+        }
+    }
+
+    Ok(failed)
+}
+
+/// Re-creates the rooms among `rooms` the old account administers from the new account: a new
+/// room with the same name, topic, avatar, join rule, encryption and power levels (the new
+/// account taking the old one's place), with the members of the old room invited. The old room
+/// gets a tombstone pointing to the new one. Returns the rooms taken care of, re-created or
+/// failed, the others go through the invite flow.
+async fn recreate_rooms(
+    args: &Args,
+    from_c: &Client,
+    to_c: &Client,
+    to_login: &Login,
+    rooms: &[OwnedRoomId],
+    limiter: &Limiter,
+) -> anyhow::Result<Vec<OwnedRoomId>> {
+    let dryrun = args.dryrun;
+    let old_user = from_c.user_id().unwrap().to_owned();
+    let new_user = to_c.user_id().unwrap().to_owned();
+    let mut done = Vec::new();
+
+    let batch_version = if args.migrate().import_history {
+        let version = import::batch_room_version(to_c, to_login)
+            .await
+            .unwrap_or_else(|e| {
+                warn!(
+                    "Couldn't look up the room versions of {}: {e}",
+                    to_c.homeserver()
+                );
+                None
+            });
+        if version.is_none() {
+            info!(
+                "{} can't import history with MSC2716 batch sends as the new account, quoting \
+                the messages instead",
+                to_c.homeserver()
+            );
+        }
+        version
+    } else {
+        None
+    };
+
+    for room_id in rooms {
+        let Some(room) = from_c.get_room(room_id) else {
+            continue;
+        };
+        if let Some(tombstone) = room.tombstone() {
+            info!(
+                "{room_id} is already replaced by {}. Not re-creating it.",
+                tombstone.replacement_room
+            );
+            report::skip(room_id, "already replaced");
+            done.push(room_id.clone());
+            continue;
+        }
+        if !room
+            .can_user_send_state(&old_user, StateEventType::RoomTombstone)
+            .await?
+        {
+            warn!("{old_user} can't replace {room_id}, inviting the new account instead");
+            continue;
+        }
+
+        info!("Re-creating {room_id} from {new_user}");
+        done.push(room_id.clone());
+        if dryrun {
+            report::action(room_id, Action::Recreated);
+            continue;
+        }
+
+        let mut request = create_room::v3::Request::new();
+        request.name = room.name();
+        request.topic = room.topic();
+        request.preset = Some(match room.join_rule() {
+            JoinRule::Public => RoomPreset::PublicChat,
+            _ => RoomPreset::PrivateChat,
+        });
+        if room.is_space() {
+            let mut creation_content = create_room::v3::CreationContent::new();
+            creation_content.room_type = Some(RoomType::Space);
+            request.creation_content = Some(Raw::new(&creation_content)?);
+        }
+        let members = room
+            .members(RoomMemberships::JOIN)
+            .await?
+            .into_iter()
+            .map(|m| m.user_id().to_owned())
+            .filter(|u| *u != old_user && *u != new_user)
+            .collect::<Vec<_>>();
+        // with the history imported first, the members aren't notified of every old message
+        if !args.migrate().import_history {
+            request.invite = members.clone();
+        }
+        // the history of encrypted rooms is quoted encrypted instead of batch sent in the clear
+        let encrypted = room.is_encrypted().await?;
+        let batch = !encrypted && batch_version.is_some();
+        if batch {
+            request.room_version = batch_version.clone();
+        }
+        let mut initial_state =
+            vec![
+                InitialStateEvent::new(RoomJoinRulesEventContent::new(room.join_rule()))
+                    .to_raw_any(),
+            ];
+        if let Some(url) = room.avatar_url() {
+            let mut avatar = RoomAvatarEventContent::new();
+            avatar.url = Some(url);
+            initial_state.push(InitialStateEvent::new(avatar).to_raw_any());
+        }
+        if encrypted {
+            initial_state.push(
+                InitialStateEvent::new(RoomEncryptionEventContent::with_recommended_defaults())
+                    .to_raw_any(),
+            );
+        }
+        request.initial_state = initial_state;
+        if let Some(power_levels) = room
+            .get_state_event_static::<RoomPowerLevelsEventContent>()
+            .await?
+        {
+            let mut power_levels =
+                RoomPowerLevelsEventContent::from(power_levels.deserialize()?.power_levels());
+            let old_level = power_levels.users.remove(&old_user).unwrap_or_default();
+            power_levels
+                .users
+                .insert(new_user.clone(), old_level.max(int!(100)));
+            request.power_level_content_override = Some(Raw::new(&power_levels)?.cast());
+        }
+
+        let created = match to_c.create_room(request).await {
+            Ok(created) => created,
+            Err(e) => {
+                error::abort_if_fatal(&e)?;
+                let e = MatrixError::of(&e);
+                warn!("Re-creating {room_id} failed: {e}");
+                report::fail(room_id, e.reason(), format!("re-creating failed: {e}"))?;
+                continue;
+            }
+        };
+        journal::record_recreated(room_id, created.room_id());
+        report::action(room_id, Action::Recreated);
+
+        let tombstone = RoomTombstoneEventContent::new(
+            format!("This room has moved, {old_user} is now {new_user}"),
+            created.room_id().to_owned(),
+        );
+        if let Err(e) = room.send_state_event(tombstone).await {
+            warn!(
+                "Couldn't point {room_id} to its new room {}: {e}",
+                created.room_id()
+            );
+        }
+
+        if args.migrate().import_history {
+            match import::import_history(
+                &room,
+                to_c,
+                &created,
+                to_login,
+                args.migrate().import_history_limit,
+                batch,
+                limiter,
+            )
+            .await
+            {
+                Ok(imported) => info!("Imported {imported} messages into {}", created.room_id()),
+                Err(e) => warn!(
+                    "Importing the history of {room_id} into {} failed: {e}",
+                    created.room_id()
+                ),
+            }
+            for member in &members {
+                if let Err(e) = limiter
+                    .run(to_c, || created.invite_user_by_id(member))
+                    .await
+                {
+                    error::abort_if_fatal(&e)?;
+                    warn!("Couldn't invite {member} to {}: {e}", created.room_id());
+                }
+            }
+        }
+    }
+
+    Ok(done)
+}
+
+/// Sets the display name the old account uses in each of `rooms` for the new account too, if
+/// it differs from the global display name of the old account
+async fn copy_room_display_names(
+    from_c: &Client,
+    to_c: &Client,
+    rooms: &Vec<&OwnedRoomId>,
+    limiter: &Limiter,
+    dryrun: bool,
+) -> anyhow::Result<()> {
+    let old_user = from_c.user_id().unwrap().to_owned();
+    let new_user = to_c.user_id().unwrap().to_owned();
+    let global_name = from_c.account().get_display_name().await?;
+
+    for room_id in rooms {
+        let (Some(old_room), Some(new_room)) = (from_c.get_room(room_id), to_c.get_room(room_id))
+        else {
+            continue;
+        };
+        if new_room.state() != RoomState::Joined {
+            continue;
+        }
+        let Some(old_member) = old_room.get_member_no_sync(&old_user).await? else {
+            continue;
+        };
+        let Some(name) = old_member.display_name() else {
+            continue;
+        };
+        if Some(name) == global_name.as_deref() {
+            continue;
+        }
+        let Some(new_member) = new_room.get_member_no_sync(&new_user).await? else {
+            continue;
+        };
+        if new_member.display_name() == Some(name) {
+            continue;
+        }
+
+        info!("Setting the display name of {new_user} in {room_id} to \"{name}\"");
+        if dryrun {
+            continue;
+        }
+        let mut content = RoomMemberEventContent::new(MembershipState::Join);
+        content.displayname = Some(name.to_owned());
+        content.avatar_url = new_member.avatar_url().map(ToOwned::to_owned);
+        if let Err(e) = limiter
+            .run(to_c, || {
+                new_room.send_state_event_for_key(&new_user, content.clone())
+            })
+            .await
+        {
+            warn!("Couldn't set the display name of {new_user} in {room_id}: {e}");
+        }
+    }
+    Ok(())
+}
+
+async fn republish_aliases(
+    from_c: &Client,
+    to_c: &Client,
+    rooms: &Vec<&OwnedRoomId>,
+    update_canonical_alias: bool,
+    dryrun: bool,
+) -> anyhow::Result<()> {
+    let new_server = to_c.user_id().unwrap().server_name().to_owned();
+
+    for room_id in rooms {
+        let Some(joined) = from_c.get_room(room_id) else {
+            continue;
+        };
+
+        // aliases the old homeserver has published for this room
+        let old_aliases = match from_c
+            .send(aliases::v3::Request::new(room_id.to_owned().clone()), None)
+            .await
+        {
+            Ok(response) => response.aliases,
+            Err(e) => {
+                warn!("Couldn't fetch aliases of {room_id}: {e}");
+                continue;
+            }
+        };
+
+        let mut new_aliases = Vec::new();
+        for old_alias in old_aliases {
+            let new_alias = RoomAliasId::parse(format!("#{}:{new_server}", old_alias.alias()))?;
+            info!("Publishing {new_alias} for {room_id} (was {old_alias})");
+            if !dryrun {
+                if let Err(e) = to_c
+                    .send(
+                        create_alias::v3::Request::new(
+                            new_alias.clone(),
+                            room_id.to_owned().clone(),
+                        ),
+                        None,
+                    )
+                    .await
+                {
+                    warn!("Couldn't publish {new_alias} for {room_id}: {e}");
+                    continue;
+                }
+            }
+            new_aliases.push((old_alias, new_alias));
+        }
+
+        if !update_canonical_alias || new_aliases.is_empty() {
+            continue;
+        }
+
+        let mut content = RoomCanonicalAliasEventContent::new();
+        content.alias = joined.canonical_alias();
+        content.alt_aliases = joined.alt_aliases();
+
+        for (old_alias, new_alias) in new_aliases {
+            if content.alias.as_ref() == Some(&old_alias) {
+                content.alias = Some(new_alias.clone());
+                content.alt_aliases.push(old_alias);
+            } else if !content.alt_aliases.contains(&new_alias) {
+                content.alt_aliases.push(new_alias);
+            }
+        }
+
+        info!(
+            "Updating canonical alias of {room_id} to {:?} (alt: {:?})",
+            content.alias, content.alt_aliases
+        );
+        if dryrun {
+            continue;
+        }
+        if let Err(e) = joined.send_state_event(content).await {
+            warn!("Couldn't update canonical alias of {room_id}: {e}");
+        }
+    }
+
+    Ok(())
+}
+
+async fn redirect_profile(
+    from_c: &Client,
+    to_c: &Client,
+    template: &str,
+    avatar: AvatarRedirect,
+    dryrun: bool,
+) -> anyhow::Result<()> {
+    let old_user = from_c.user_id().unwrap().to_owned();
+    let new_user = to_c.user_id().unwrap().to_owned();
+
+    let name = from_c
+        .account()
+        .get_display_name()
+        .await?
+        .unwrap_or_else(|| old_user.localpart().to_owned());
+    let display_name = template
+        .replace("{name}", &name)
+        .replace("{new_user}", new_user.as_str());
+
+    info!("Renaming {old_user} to \"{display_name}\"");
+    if !dryrun {
+        from_c
+            .account()
+            .set_display_name(Some(&display_name))
+            .await?;
+    }
+
+    let avatar_url = match avatar {
+        AvatarRedirect::Keep => return Ok(()),
+        AvatarRedirect::Clear => None,
+        AvatarRedirect::New => to_c.account().get_avatar_url().await?,
+    };
+
+    info!("Setting avatar of {old_user} to {avatar_url:?}");
+    if !dryrun {
+        from_c
+            .account()
+            .set_avatar_url(avatar_url.as_deref())
+            .await?;
+    }
+
+    Ok(())
+}
+
+async fn deactivate_account(
+    c: &Client,
+    http: &reqwest::Client,
+    password: Option<&str>,
+    erase: bool,
+    dryrun: bool,
+) -> anyhow::Result<()> {
+    let user_id = c.user_id().unwrap().to_owned();
+    info!(
+        "Deactivating {user_id}{}",
+        if erase { " and erasing its data" } else { "" }
+    );
+    if dryrun {
+        return Ok(());
+    }
+
+    // the sdk's deactivate doesn't know about `erase`, so do the request ourselves
+    let url = endpoint(
+        &c.homeserver(),
+        &["_matrix", "client", "v3", "account", "deactivate"],
+    )?;
+    let token = c.access_token().unwrap_or_default();
+
+    uia::send(
+        http,
+        &c.homeserver(),
+        Some(&user_id),
+        password,
+        &uia::Answers::default(),
+        &format!("deactivating {user_id}"),
+        |auth| {
+            http.post(url.clone())
+                .bearer_auth(&token)
+                .json(&json!({ "auth": auth, "erase": erase }))
+        },
+    )
+    .await?
+    .error_for_status()?;
+    info!("{user_id} has been deactivated");
+    Ok(())
+}
+
+/// Deactivates the old account through the admin API of its homeserver, which needs neither
+/// its password nor a confirmation in the browser. The homeserver removes it from the rooms it's
+/// still in.
+async fn deactivate_as_admin(
+    c: &Client,
+    admin: &SynapseAdmin,
+    erase: bool,
+    dryrun: bool,
+) -> anyhow::Result<()> {
+    let user_id = c.user_id().unwrap().to_owned();
+    let remaining = admin.joined_rooms(&user_id).await?.len();
+    info!(
+        "Deactivating {user_id}{} through the admin API, removing it from the {remaining} rooms \
+        it's still in",
+        if erase { " and erasing its data" } else { "" }
+    );
+    if dryrun {
+        return Ok(());
+    }
+    admin.deactivate(&user_id, erase).await?;
+    info!("{user_id} has been deactivated");
+    Ok(())
+}
+
+async fn leave_room(
+    args: &Args,
+    from_c: &Client,
+    to_c: &Client,
+    rooms: Vec<&OwnedRoomId>,
+    plan: &Plan,
+    checkpoint: &Checkpoint,
+) -> anyhow::Result<()> {
+    let dryrun = args.dryrun;
+    let new_user = to_c.user_id().unwrap().to_owned();
+    let leave_message = args
+        .migrate()
+        .leave_message
+        .as_ref()
+        .map(|m| m.replace("{new_user}", new_user.as_str()));
+
+    let bar = progress::phase("Leaving", rooms.len());
+    for room_id in rooms {
+        let _step = progress::step(&bar, from_c, room_id);
+        async {
+            // fetch room
+            let Some(joined) = to_c.get_room(room_id) else {
+                warn!("new user isn't member of {room_id}. Skipping leave.");
+                return anyhow::Ok(());
+            };
+
+            // check if old user is in room
+            let self_id = from_c.user_id().unwrap().to_owned();
+            let Some(me) = sync::member(&joined, &self_id).await? else {
+                warn!("old user isn't member of {room_id} anymore. Skipping leave.");
+                return anyhow::Ok(());
+            };
+
+            // check if new user is in room
+            let Some(new_acc) = sync::member(&joined, &new_user).await? else {
+                warn!("new user isn't member of {room_id}. Skipping leave.");
+                return anyhow::Ok(());
+            };
+
+            // check if new users power level is equal/greater of old user (or what the plan says)
+            let target = plan.target_power_level(room_id, me.power_level());
+            if target > new_acc.power_level() {
+                let gaps = audit::ability_gaps(&joined, target, new_acc.power_level()).await?;
+                let gaps = if gaps.is_empty() {
+                    String::new()
+                } else {
+                    format!(" and can't {}", gaps.join(", "))
+                };
+                if args.migrate().leave_policy != LeavePolicy::Force {
+                    warn!("New user {new_user} doesn't have an equal/higher power level than {self_id} in {room_id}{gaps}. Skipping leave.");
+                    return anyhow::Ok(());
+                }
+                warn!("New user {new_user} doesn't have an equal/higher power level than {self_id} in {room_id}{gaps}. Leaving anyway.");
+            }
+
+            // check that the new user can actually use the room
+            if let Some(problem) = audit::unusable(from_c, to_c, room_id).await? {
+                if args.migrate().leave_policy != LeavePolicy::Force {
+                    warn!("{room_id} isn't usable by {new_user} yet: {problem}. Skipping leave.");
+                    report::skip(room_id, format!("not left, {problem}"));
+                    return anyhow::Ok(());
+                }
+                warn!("{room_id} isn't usable by {new_user} yet: {problem}. Leaving anyway.");
+            }
+
+            let old_room = from_c.get_room(room_id).expect("Failed to fetch room");
+            if args.migrate().demote_old {
+                let default = users_default(&old_room).await?;
+                if me.power_level() > default {
+                    info!(
+                        "Dropping the power level of {self_id} in {room_id} from {} to {default}",
+                        me.power_level()
+                    );
+                    if !dryrun {
+                        journal::record_power_level_of(room_id, &self_id, me.power_level());
+                        let demoted = old_room
+                            .update_power_levels(vec![(&self_id, Int::try_from(default)?)])
+                            .await;
+                        if let Err(e) = demoted {
+                            error::abort_if_fatal(&e)?;
+                            warn!("Couldn't drop the power level of {self_id} in {room_id}: {e}. Leaving anyway.");
+                        }
+                    }
+                }
+            }
+
+            info!(
+                "Leaving room {}({})",
+                joined.display_name().await?,
+                joined.room_id()
+            );
+            if let Some(message) = &leave_message {
+                info!("Posting \"{message}\" in {room_id}");
+            }
+            if dryrun {
+                report::action(room_id, Action::Left);
+                return anyhow::Ok(());
+            } else {
+                if let Some(message) = &leave_message {
+                    if let Err(e) = old_room
+                        .send(RoomMessageEventContent::text_plain(message))
+                        .await
+                    {
+                        warn!("Couldn't post leave message in {room_id}: {e}");
+                    }
+                }
+                if let Err(e) = old_room.leave().await {
+                    error::abort_if_fatal(&e)?;
+                    let reason = MatrixError::of(&e).reason();
+                    warn!("Leaving {room_id} failed: {e}");
+                    report::fail(room_id, reason, format!("leaving failed: {e}"))?;
+                    return anyhow::Ok(());
+                }
+                checkpoint.record(Step::Left, room_id);
+                journal::record(room_id, Action::Left);
+                report::action(room_id, Action::Left);
+            }
+
+            // TODO: Perform more checks to ensure setting is_direct is desired
+            if joined.name().is_none() {
+                info!(
+                    "Setting room {}({}) to direct message",
+                    joined.display_name().await?,
+                    joined.room_id()
+                );
+
+                if !dryrun {
+                    if let Err(e) = joined.set_is_direct(true).await {
+                        error::abort_if_fatal(&e)?;
+                        let reason = MatrixError::of(&e).reason();
+                        warn!("Marking {room_id} as direct message failed: {e}");
+                        report::fail(room_id, reason, format!("marking as DM failed: {e}"))?;
+                    }
+                }
+            }
+            Ok(())
+        }
+        .instrument(progress::room_span("leave", from_c, room_id))
+        .await?;
+    }
+    bar.finish();
+
+    Ok(())
+}
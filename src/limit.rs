@@ -0,0 +1,241 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use matrix_sdk::{reqwest::Url, Client, HttpError};
+use tokio::{
+    sync::{Mutex, Semaphore, SemaphorePermit},
+    time::Instant,
+};
+use tracing::warn;
+
+use crate::{error::MatrixError, metrics};
+
+/// Whether new requests wait, set from the dashboard
+static PAUSED: AtomicBool = AtomicBool::new(false);
+
+/// Holds back new requests until unpaused. Requests already running finish.
+pub fn pause(paused: bool) {
+    PAUSED.store(paused, Ordering::Relaxed);
+}
+
+pub fn is_paused() -> bool {
+    PAUSED.load(Ordering::Relaxed)
+}
+
+/// How often a rate limited request is retried before giving up
+const RATE_LIMIT_RETRIES: usize = 10;
+
+/// How long to pause when the homeserver rate limits without saying for how long
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(5);
+
+/// How often and how patiently requests failing with a transient error, like a timeout or a
+/// 5xx response, are retried
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    pub retries: usize,
+    /// Pause before the first retry, doubled for every further one
+    pub backoff: Duration,
+}
+
+impl RetryPolicy {
+    fn delay(&self, attempt: usize) -> Duration {
+        self.backoff.saturating_mul(1 << attempt.min(16))
+    }
+
+    /// Runs `request`, retrying it on transient errors
+    pub async fn run<T, F, Fut>(&self, mut request: F) -> matrix_sdk::Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = matrix_sdk::Result<T>>,
+    {
+        let mut attempt = 0;
+        loop {
+            match request().await {
+                Err(e) if attempt < self.retries && is_transient(&e) => {
+                    self.wait(attempt, &e).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn wait(&self, attempt: usize, e: &matrix_sdk::Error) {
+        let delay = self.delay(attempt);
+        metrics::RETRIES.inc();
+        warn!(
+            "Request failed ({e}), retrying in {} ({}/{})",
+            humantime::format_duration(delay),
+            attempt + 1,
+            self.retries
+        );
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// Whether `e` is worth retrying: network errors, timeouts and server errors
+pub fn is_transient(e: &matrix_sdk::Error) -> bool {
+    match e {
+        matrix_sdk::Error::Http(HttpError::Reqwest(_)) => true,
+        e => e
+            .as_client_api_error()
+            .is_some_and(|e| e.status_code.is_server_error()),
+    }
+}
+
+/// Token bucket pacing the requests to one homeserver
+struct Bucket {
+    rate: f64,
+    tokens: f64,
+    updated: Instant,
+    paused_until: Instant,
+}
+
+impl Bucket {
+    fn new(rate: f64, burst: f64) -> Self {
+        let now = Instant::now();
+        Self {
+            rate,
+            tokens: burst,
+            updated: now,
+            paused_until: now,
+        }
+    }
+
+    /// Takes a token, returning how long to wait before using it. Tokens are handed out in
+    /// advance, so concurrent callers queue up instead of all waking at once.
+    fn take(&mut self, burst: f64) -> Duration {
+        let now = Instant::now();
+        let paused = self.paused_until.saturating_duration_since(now);
+        if self.rate <= 0.0 {
+            return paused;
+        }
+
+        let refill = now.duration_since(self.updated).as_secs_f64() * self.rate;
+        self.tokens = (self.tokens + refill).min(burst) - 1.0;
+        self.updated = now;
+        let wait = if self.tokens < 0.0 {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        } else {
+            Duration::ZERO
+        };
+        wait.max(paused)
+    }
+
+    fn pause(&mut self, duration: Duration) {
+        self.paused_until = self.paused_until.max(Instant::now() + duration);
+        // don't let a full bucket burst out right after the pause
+        self.tokens = self.tokens.min(0.0);
+    }
+}
+
+/// Bounds how many requests run at once and paces them with a token bucket per homeserver, so
+/// large accounts don't hammer the homeservers. Every request gets the same share of the rate,
+/// however far down the list of rooms it is.
+#[derive(Clone)]
+pub struct Limiter {
+    permits: Arc<Semaphore>,
+    requests_per_second: f64,
+    burst: f64,
+    rates: HashMap<Url, f64>,
+    buckets: Arc<Mutex<HashMap<Url, Bucket>>>,
+    retry: RetryPolicy,
+}
+
+impl Limiter {
+    pub fn new(
+        concurrency: usize,
+        requests_per_second: f64,
+        burst: usize,
+        retry: RetryPolicy,
+    ) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(concurrency.max(1))),
+            requests_per_second,
+            burst: burst.max(1) as f64,
+            rates: HashMap::new(),
+            buckets: Arc::default(),
+            retry,
+        }
+    }
+
+    /// Overrides the rate for the homeserver of `c`
+    pub fn with_rate(mut self, c: &Client, requests_per_second: f64) -> Self {
+        self.rates.insert(c.homeserver(), requests_per_second);
+        self
+    }
+
+    /// Waits for a token of `homeserver` and a free slot. The slot is freed again when the
+    /// returned permit is dropped.
+    pub async fn acquire(&self, homeserver: &Url) -> SemaphorePermit<'_> {
+        while is_paused() {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+        }
+        let wait = {
+            let mut buckets = self.buckets.lock().await;
+            buckets
+                .entry(homeserver.clone())
+                .or_insert_with(|| {
+                    let rate = self.rates.get(homeserver);
+                    Bucket::new(*rate.unwrap_or(&self.requests_per_second), self.burst)
+                })
+                .take(self.burst)
+        };
+        tokio::time::sleep(wait).await;
+
+        self.permits
+            .acquire()
+            .await
+            .expect("the semaphore is never closed")
+    }
+
+    /// Runs `request` to the homeserver of `c` in a slot, retrying it when the homeserver rate
+    /// limits it or it fails transiently. On rate limits every request to that homeserver is
+    /// paused for as long as it asks.
+    pub async fn run<T, F, Fut>(&self, c: &Client, mut request: F) -> matrix_sdk::Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = matrix_sdk::Result<T>>,
+    {
+        let homeserver = c.homeserver();
+        let mut retries = 0;
+        let mut attempt = 0;
+        loop {
+            let result = {
+                let _permit = self.acquire(&homeserver).await;
+                request().await
+            };
+            let retry_after = match &result {
+                Err(e) if retries < RATE_LIMIT_RETRIES => match MatrixError::of(e) {
+                    MatrixError::RateLimited { retry_after } => {
+                        retry_after.unwrap_or(DEFAULT_RETRY_AFTER)
+                    }
+                    _ if attempt < self.retry.retries && is_transient(e) => {
+                        self.retry.wait(attempt, e).await;
+                        attempt += 1;
+                        continue;
+                    }
+                    _ => return result,
+                },
+                _ => return result,
+            };
+
+            warn!(
+                "Rate limited by {homeserver}, pausing for {}",
+                humantime::format_duration(retry_after)
+            );
+            metrics::rate_limited(retry_after);
+            if let Some(bucket) = self.buckets.lock().await.get_mut(&homeserver) {
+                bucket.pause(retry_after);
+            }
+            retries += 1;
+        }
+    }
+}
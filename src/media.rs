@@ -0,0 +1,320 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use matrix_sdk::{
+    ruma::{
+        api::client::media::{create_content, get_content},
+        events::{GlobalAccountDataEventType, StateEventType},
+        serde::Raw,
+        MxcUri, OwnedMxcUri, OwnedRoomId, UserId,
+    },
+    Client,
+};
+use serde::Serialize;
+use serde_json::Value;
+use tracing::{info, warn};
+
+use crate::{
+    admin::{SynapseAdmin, UserMedia},
+    error::{self, MatrixError},
+    report,
+};
+
+/// Copies media from the old homeserver to the new one, uploading every mxc URI only once.
+pub struct MediaMigrator {
+    from_c: Client,
+    to_c: Client,
+    uploaded: HashMap<OwnedMxcUri, OwnedMxcUri>,
+}
+
+impl MediaMigrator {
+    pub fn new(from_c: &Client, to_c: &Client) -> Self {
+        Self {
+            from_c: from_c.clone(),
+            to_c: to_c.clone(),
+            uploaded: HashMap::new(),
+        }
+    }
+
+    /// Whether `uri` lives on the old account's homeserver and would vanish with it.
+    pub fn is_owned(&self, uri: &MxcUri) -> bool {
+        uri.server_name().ok() == self.from_c.user_id().map(|u| u.server_name())
+    }
+
+    /// Downloads `uri` with the old account and uploads it with the new one.
+    pub async fn reupload(&mut self, uri: &MxcUri) -> anyhow::Result<OwnedMxcUri> {
+        if let Some(new_uri) = self.uploaded.get(uri) {
+            return Ok(new_uri.clone());
+        }
+
+        let content = self
+            .from_c
+            .send(get_content::v3::Request::from_url(uri)?, None)
+            .await?;
+
+        let mut request = create_content::v3::Request::new(content.file);
+        request.content_type = content.content_type;
+        let new_uri = self.to_c.send(request, None).await?.content_uri;
+
+        info!("Re-uploaded {uri} as {new_uri}");
+        self.uploaded.insert(uri.to_owned(), new_uri.clone());
+        Ok(new_uri)
+    }
+}
+
+/// Re-uploads the old account's avatar and the avatars of `rooms` that live on the old
+/// homeserver, pointing the new account and the rooms at the copies.
+pub async fn reupload_media(
+    from_c: &Client,
+    to_c: &Client,
+    rooms: &Vec<&OwnedRoomId>,
+    dryrun: bool,
+) -> anyhow::Result<()> {
+    let mut media = MediaMigrator::new(from_c, to_c);
+    let new_user = to_c.user_id().unwrap().to_owned();
+
+    if let Some(avatar) = from_c.account().get_avatar_url().await? {
+        if !media.is_owned(&avatar) {
+            info!("Avatar {avatar} isn't hosted on the old homeserver. Skipping.");
+        } else if to_c.account().get_avatar_url().await?.is_some() {
+            info!("{new_user} already has an avatar. Skipping.");
+        } else {
+            info!("Copying avatar {avatar} to {new_user}");
+            if !dryrun {
+                let new_avatar = media.reupload(&avatar).await?;
+                to_c.account().set_avatar_url(Some(&new_avatar)).await?;
+            }
+        }
+    }
+
+    for room_id in rooms {
+        let Some(joined) = to_c.get_room(room_id) else {
+            continue;
+        };
+        let Some(avatar) = joined.avatar_url() else {
+            continue;
+        };
+        if !media.is_owned(&avatar) {
+            continue;
+        }
+        if !joined
+            .can_user_send_state(&new_user, StateEventType::RoomAvatar)
+            .await?
+        {
+            warn!("{new_user} can't change the avatar of {room_id}. Skipping re-upload.");
+            continue;
+        }
+
+        info!("Copying room avatar {avatar} of {room_id}");
+        if dryrun {
+            continue;
+        }
+        match media.reupload(&avatar).await {
+            Ok(new_avatar) => {
+                if let Err(e) = joined.set_avatar_url(&new_avatar, None).await {
+                    error::abort_if_fatal(&e)?;
+                    let reason = MatrixError::of(&e).reason();
+                    warn!("Setting the re-uploaded avatar of {room_id} failed: {e}");
+                    report::fail(room_id, reason, format!("setting the avatar failed: {e}"))?;
+                }
+            }
+            Err(e) => warn!("Couldn't re-upload avatar of {room_id}: {e}"),
+        }
+    }
+
+    Ok(())
+}
+
+/// Account data holding the user's own emote and sticker packs (MSC2545)
+const IMAGE_PACKS: [&str; 2] = ["im.ponies.user_emotes", "m.image_pack"];
+
+/// Account data listing the room packs the user enabled everywhere
+const IMAGE_PACK_ROOMS: [&str; 2] = ["im.ponies.emote_rooms", "m.image_pack.rooms"];
+
+/// Copies the old account's emote and sticker packs to the new one, re-uploading the images
+/// hosted on the old homeserver. Packs and images the new account already has are kept.
+pub async fn migrate_emotes(from_c: &Client, to_c: &Client, dryrun: bool) -> anyhow::Result<()> {
+    let mut media = MediaMigrator::new(from_c, to_c);
+
+    for event_type in IMAGE_PACKS.into_iter().chain(IMAGE_PACK_ROOMS) {
+        let event_type = GlobalAccountDataEventType::from(event_type);
+        let Some(old) = from_c
+            .account()
+            .fetch_account_data(event_type.clone())
+            .await?
+        else {
+            continue;
+        };
+        let mut content = old.deserialize_as::<Value>()?;
+
+        if IMAGE_PACKS.contains(&event_type.to_string().as_str()) {
+            let images = content["images"].as_object().map_or(0, |i| i.len());
+            info!("Copying {images} images of the {event_type} pack");
+            if dryrun {
+                continue;
+            }
+            if let Some(images) = content["images"].as_object_mut() {
+                for (shortcode, image) in images.iter_mut() {
+                    if let Err(e) = reupload_url(&mut media, &mut image["url"]).await {
+                        warn!("Couldn't re-upload the image of :{shortcode}: {e}");
+                    }
+                }
+            }
+            if content["pack"]["avatar_url"].is_string() {
+                if let Err(e) = reupload_url(&mut media, &mut content["pack"]["avatar_url"]).await {
+                    warn!("Couldn't re-upload the avatar of the {event_type} pack: {e}");
+                }
+            }
+        } else {
+            info!("Copying {event_type}");
+            if dryrun {
+                continue;
+            }
+        }
+
+        add_account_data(to_c, event_type, content).await?;
+    }
+    Ok(())
+}
+
+/// Adds `content` to the account data of `c` of `event_type`, keeping what's already there
+pub async fn add_account_data(
+    c: &Client,
+    event_type: GlobalAccountDataEventType,
+    mut content: Value,
+) -> anyhow::Result<()> {
+    if let Some(existing) = c.account().fetch_account_data(event_type.clone()).await? {
+        let mut existing = existing.deserialize_as::<Value>()?;
+        merge(&mut existing, content);
+        content = existing;
+    }
+    c.account()
+        .set_account_data_raw(event_type, Raw::new(&content)?.cast())
+        .await?;
+    Ok(())
+}
+
+/// Points `url` at a copy on the new homeserver, if it's an mxc URI of the old one
+pub async fn reupload_url(media: &mut MediaMigrator, url: &mut Value) -> anyhow::Result<()> {
+    let Some(uri) = url.as_str().map(<&MxcUri>::from) else {
+        return Ok(());
+    };
+    if media.is_owned(uri) {
+        *url = media.reupload(uri).await?.to_string().into();
+    }
+    Ok(())
+}
+
+/// Adds what `into` is missing from `from`, keeping its own values
+fn merge(into: &mut Value, from: Value) {
+    let (Value::Object(into), Value::Object(from)) = (into, from) else {
+        return;
+    };
+    for (key, value) in from {
+        match into.get_mut(&key) {
+            Some(existing) => merge(existing, value),
+            None => {
+                into.insert(key, value);
+            }
+        }
+    }
+}
+
+/// What became of a file the old account uploaded
+#[derive(Serialize, Debug)]
+pub struct ExportedMedia {
+    #[serde(flatten)]
+    pub media: UserMedia,
+    pub uri: OwnedMxcUri,
+    /// Where it was downloaded to
+    pub file: Option<PathBuf>,
+    /// Its copy on the new homeserver
+    pub new_uri: Option<OwnedMxcUri>,
+    pub error: Option<String>,
+}
+
+/// Downloads the files `user_id` uploaded to `dir`, if given, and re-uploads them with `to_c`,
+/// if given. Both happen through the admin API of the old homeserver, so media the old account
+/// can't see anymore is included too. Writes an index of the files to `dir`.
+pub async fn export_media(
+    admin: &SynapseAdmin,
+    user_id: &UserId,
+    media: Vec<UserMedia>,
+    dir: Option<&Path>,
+    to_c: Option<&Client>,
+) -> anyhow::Result<Vec<ExportedMedia>> {
+    if let Some(dir) = dir {
+        std::fs::create_dir_all(dir)?;
+    }
+    let server = user_id.server_name();
+    let mut exported = Vec::with_capacity(media.len());
+    for media in media {
+        let uri = OwnedMxcUri::from(format!("mxc://{server}/{}", media.media_id));
+        let mut entry = ExportedMedia {
+            media,
+            uri,
+            file: None,
+            new_uri: None,
+            error: None,
+        };
+        if dir.is_none() && to_c.is_none() {
+            exported.push(entry);
+            continue;
+        }
+        let (content, content_type) = match admin.download(server, &entry.media.media_id).await {
+            Ok(downloaded) => downloaded,
+            Err(e) => {
+                warn!("Couldn't download {}: {e}", entry.uri);
+                entry.error = Some(e.to_string());
+                exported.push(entry);
+                continue;
+            }
+        };
+
+        if let Some(dir) = dir {
+            let mut name = entry.media.media_id.clone();
+            if let Some(upload_name) = &entry.media.upload_name {
+                name += "-";
+                name += &upload_name.replace(|c: char| !c.is_alphanumeric() && c != '.', "_");
+            }
+            let path = dir.join(name);
+            match std::fs::write(&path, &content) {
+                Ok(()) => entry.file = Some(path),
+                Err(e) => {
+                    warn!("Couldn't write {}: {e}", path.display());
+                    entry.error = Some(e.to_string());
+                }
+            }
+        }
+
+        if let Some(to_c) = to_c {
+            let mut request = create_content::v3::Request::new(content);
+            request.content_type = content_type.or_else(|| entry.media.media_type.clone());
+            request.filename = entry.media.upload_name.clone();
+            match to_c.send(request, None).await {
+                Ok(response) => {
+                    info!("Re-uploaded {} as {}", entry.uri, response.content_uri);
+                    entry.new_uri = Some(response.content_uri);
+                }
+                Err(e) => {
+                    warn!("Couldn't re-upload {}: {e}", entry.uri);
+                    entry.error = Some(e.to_string());
+                }
+            }
+        }
+        exported.push(entry);
+    }
+
+    if let Some(dir) = dir {
+        let index = dir.join("media.json");
+        std::fs::write(&index, serde_json::to_vec_pretty(&exported)?)?;
+        info!(
+            "Wrote the index of {} files to {}",
+            exported.len(),
+            index.display()
+        );
+    }
+    Ok(exported)
+}
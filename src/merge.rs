@@ -0,0 +1,82 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use matrix_sdk::ruma::{OwnedRoomId, OwnedUserId};
+use tracing::{info, warn};
+
+use crate::{
+    context::{self, Context},
+    report, Args, MigrationReport, Migrator,
+};
+
+/// Migrates the accounts of `sources` one after the other into the same new account. Rooms
+/// several of them share are only migrated once, with the highest power level any of them has,
+/// and differing power levels are recorded as conflicts. The accounts share the report of
+/// `context`.
+pub async fn merge(sources: Vec<Args>, context: Arc<Context>) -> anyhow::Result<MigrationReport> {
+    let count = sources.len();
+    let mut members: BTreeMap<OwnedRoomId, Vec<(OwnedUserId, i64)>> = BTreeMap::new();
+    let mut last: Option<Migrator> = None;
+
+    for (i, mut args) in sources.into_iter().enumerate() {
+        // every account resumes on its own
+        let stem = args.state_file.file_stem().unwrap_or_default().to_owned();
+        args.state_file
+            .set_file_name(format!("{}-{}.json", stem.to_string_lossy(), i + 1));
+
+        let mut migrator = Migrator::login_in(args, context.clone()).await?;
+        let old_user = migrator.from_c.user_id().unwrap().to_owned();
+        info!("--- Migrating {old_user}, account {} of {count}", i + 1);
+
+        migrator.start().await?;
+        migrator.select_rooms().await?;
+        for room_id in &migrator.rooms {
+            let Some(room) = migrator.from_c.get_room(room_id) else {
+                continue;
+            };
+            let power_level = room
+                .get_member_no_sync(&old_user)
+                .await?
+                .map_or(0, |m| m.power_level());
+            members
+                .entry(room_id.clone())
+                .or_default()
+                .push((old_user.clone(), power_level));
+        }
+        migrator.invite().await?;
+        migrator.migrate_extras().await?;
+        migrator.leave().await?;
+        migrator.redirect_profile().await?;
+        migrator.deactivate().await?;
+
+        if let Some(previous) = last.replace(migrator) {
+            previous.logout().await?;
+        }
+    }
+
+    context::scope(context, async {
+        for (room_id, members) in members.iter().filter(|(_, m)| m.len() > 1) {
+            let accounts = members.iter().map(|(user, _)| user).collect::<Vec<_>>();
+            info!("{room_id} is shared by {accounts:?}, migrated once");
+            let highest = members.iter().map(|(_, level)| *level).max().unwrap_or(0);
+            if members.iter().any(|(_, level)| *level != highest) {
+                let levels = members
+                    .iter()
+                    .map(|(user, level)| format!("{user} has {level}"))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                warn!("Power levels differ in {room_id}: {levels}. The new account gets {highest}");
+                report::conflict(
+                    room_id,
+                    format!("power levels differ: {levels}, the new account gets {highest}"),
+                );
+            }
+        }
+        anyhow::Ok(())
+    })
+    .await?;
+
+    let Some(last) = last else {
+        anyhow::bail!("No accounts to merge");
+    };
+    last.finish().await
+}
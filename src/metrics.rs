@@ -0,0 +1,162 @@
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use tracing::info;
+
+use crate::{
+    http,
+    report::{self, Action, Reason},
+};
+
+/// A counter exposed to Prometheus
+pub struct Counter {
+    name: &'static str,
+    help: &'static str,
+    value: AtomicU64,
+}
+
+impl Counter {
+    const fn new(name: &'static str, help: &'static str) -> Self {
+        Self {
+            name,
+            help,
+            value: AtomicU64::new(0),
+        }
+    }
+
+    pub fn add(&self, n: u64) {
+        self.value.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+pub static INVITES: Counter = Counter::new(
+    "matrix_migrate_invites_total",
+    "Invites sent to the new account",
+);
+pub static JOINS: Counter = Counter::new(
+    "matrix_migrate_joins_total",
+    "Rooms joined by the new account",
+);
+pub static POWER_LEVELS: Counter = Counter::new(
+    "matrix_migrate_power_level_updates_total",
+    "Power level updates for the new account",
+);
+pub static LEAVES: Counter = Counter::new(
+    "matrix_migrate_leaves_total",
+    "Rooms left by the old account",
+);
+pub static RETRIES: Counter = Counter::new(
+    "matrix_migrate_http_retries_total",
+    "Requests retried after a transient error",
+);
+pub static RATE_LIMITS: Counter = Counter::new(
+    "matrix_migrate_rate_limits_total",
+    "Requests rate limited by a homeserver",
+);
+/// In milliseconds, exposed in seconds
+pub static RATE_LIMIT_WAIT: Counter = Counter::new(
+    "matrix_migrate_rate_limit_wait_seconds_total",
+    "Time paused because of rate limits",
+);
+
+const FAILURE_REASONS: [Reason; 4] = [
+    Reason::RateLimited,
+    Reason::NoPermission,
+    Reason::Federation,
+    Reason::Other,
+];
+static FAILURES: [AtomicU64; 4] = [const { AtomicU64::new(0) }; 4];
+
+pub fn action(action: Action) {
+    match action {
+        Action::Invited => INVITES.inc(),
+        Action::Joined | Action::Accepted => JOINS.inc(),
+        Action::PowerLevel => POWER_LEVELS.inc(),
+        Action::Left => LEAVES.inc(),
+        Action::Unbanned | Action::Knocked | Action::DmRecreated | Action::Recreated => {}
+    }
+}
+
+pub fn failure(reason: Reason) {
+    let i = FAILURE_REASONS.iter().position(|r| *r == reason).unwrap();
+    FAILURES[i].fetch_add(1, Ordering::Relaxed);
+}
+
+/// When the pause of the last rate limit ends
+static RATE_LIMITED_UNTIL: Mutex<Option<Instant>> = Mutex::new(None);
+
+pub fn rate_limited(wait: Duration) {
+    RATE_LIMITS.inc();
+    RATE_LIMIT_WAIT.add(wait.as_millis() as u64);
+    *RATE_LIMITED_UNTIL.lock().unwrap() = Some(Instant::now() + wait);
+}
+
+/// How much longer requests are paused because of a rate limit, if they are
+pub fn rate_limited_for() -> Option<Duration> {
+    RATE_LIMITED_UNTIL
+        .lock()
+        .unwrap()
+        .and_then(|until| until.checked_duration_since(Instant::now()))
+}
+
+/// All metrics in the Prometheus text format
+fn render() -> String {
+    let mut out = String::new();
+    for counter in [
+        &INVITES,
+        &JOINS,
+        &POWER_LEVELS,
+        &LEAVES,
+        &RETRIES,
+        &RATE_LIMITS,
+    ] {
+        out += &format!(
+            "# HELP {0} {1}\n# TYPE {0} counter\n{0} {2}\n",
+            counter.name,
+            counter.help,
+            counter.value.load(Ordering::Relaxed)
+        );
+    }
+    out += &format!(
+        "# HELP {0} {1}\n# TYPE {0} counter\n{0} {2:.3}\n",
+        RATE_LIMIT_WAIT.name,
+        RATE_LIMIT_WAIT.help,
+        RATE_LIMIT_WAIT.value.load(Ordering::Relaxed) as f64 / 1000.0
+    );
+
+    let name = "matrix_migrate_failures_total";
+    out += &format!("# HELP {name} Failed actions by reason\n# TYPE {name} counter\n");
+    for (reason, count) in FAILURE_REASONS.iter().zip(&FAILURES) {
+        let reason = report::name(reason);
+        out += &format!(
+            "{name}{{reason=\"{reason}\"}} {}\n",
+            count.load(Ordering::Relaxed)
+        );
+    }
+    out
+}
+
+/// Serves the metrics on `addr` for as long as the process runs
+pub async fn serve(addr: SocketAddr) -> anyhow::Result<()> {
+    // every request gets the metrics, whatever its path
+    http::serve(addr, |_| {
+        ("200 OK", "text/plain; version=0.0.4", render().into_bytes())
+    })
+    .await?;
+    info!("Serving metrics on http://{addr}/metrics");
+    Ok(())
+}
@@ -0,0 +1,145 @@
+use std::{collections::HashMap, ops::RangeInclusive, path::Path};
+
+use matrix_sdk::{
+    ruma::{OwnedRoomId, OwnedRoomOrAliasId, RoomId},
+    Client,
+};
+use serde::Deserialize;
+use tracing::info;
+
+/// Power levels Matrix can represent, the range of its integers
+pub(crate) const POWER_LEVELS: RangeInclusive<i64> = -0x1F_FFFF_FFFF_FFFF..=0x1F_FFFF_FFFF_FFFF;
+
+/// Per-room options overriding the global flags, read from a TOML file like
+///
+/// ```toml
+/// [rooms."#team:example.org"]
+/// power_level = 50
+/// leave = false
+///
+/// [rooms."!abcdef:example.org"]
+/// skip = true
+///
+/// [rooms."#old-project:example.org"]
+/// recreate = true
+/// ```
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Plan {
+    #[serde(default)]
+    rooms: HashMap<OwnedRoomOrAliasId, RoomPlan>,
+
+    #[serde(skip)]
+    resolved: HashMap<OwnedRoomId, RoomPlan>,
+
+    /// `--target-power-level`
+    #[serde(skip)]
+    power_level: Option<i64>,
+
+    /// `--max-power-level`
+    #[serde(skip)]
+    max_power_level: Option<i64>,
+}
+
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RoomPlan {
+    /// Leave the room alone
+    #[serde(default)]
+    pub skip: bool,
+
+    /// Whether the old account leaves the room, overriding `--leave-rooms`
+    pub leave: Option<bool>,
+
+    /// Power level to grant the new account instead of the old account's
+    pub power_level: Option<i64>,
+
+    /// Whether to re-create the DM instead of inviting, overriding `--recreate-dms`
+    pub dm: Option<bool>,
+
+    /// Whether to re-create the room from the new account instead of inviting, overriding
+    /// `--recreate`
+    pub recreate: Option<bool>,
+}
+
+impl Plan {
+    /// Sets the options of `room`, for building a plan in code instead of loading it
+    pub fn with_room(mut self, room: impl Into<OwnedRoomOrAliasId>, plan: RoomPlan) -> Self {
+        self.rooms.insert(room.into(), plan);
+        self
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let plan: Plan = toml::from_str(&std::fs::read_to_string(path)?)
+            .map_err(|e| anyhow::anyhow!("Invalid plan {}: {e}", path.display()))?;
+        for (room, room_plan) in &plan.rooms {
+            if let Some(power_level) = room_plan.power_level {
+                anyhow::ensure!(
+                    POWER_LEVELS.contains(&power_level),
+                    "Invalid plan {}: power level {power_level} of {room} is out of range",
+                    path.display()
+                );
+            }
+        }
+        info!("Loaded plan for {} rooms", plan.rooms.len());
+        Ok(plan)
+    }
+
+    /// Resolves the aliases used as keys. Has to be called before looking up rooms.
+    pub async fn resolve(&mut self, c: &Client) -> anyhow::Result<()> {
+        for (room, room_plan) in &self.rooms {
+            let room_id = match OwnedRoomId::try_from(room.clone()) {
+                Ok(room_id) => room_id,
+                Err(alias) => c.resolve_room_alias(&alias).await?.room_id,
+            };
+            self.resolved.insert(room_id, room_plan.clone());
+        }
+        Ok(())
+    }
+
+    pub fn room(&self, room_id: &RoomId) -> Option<&RoomPlan> {
+        self.resolved.get(room_id)
+    }
+
+    pub fn skips(&self, room_id: &RoomId) -> bool {
+        self.room(room_id).is_some_and(|p| p.skip)
+    }
+
+    pub fn leaves(&self, room_id: &RoomId, default: bool) -> bool {
+        self.room(room_id).and_then(|p| p.leave).unwrap_or(default)
+    }
+
+    /// Whether any room is set to be left regardless of `--leave-rooms`
+    pub fn has_leaves(&self) -> bool {
+        self.resolved.values().any(|p| p.leave == Some(true))
+    }
+
+    pub fn recreates_dm(&self, room_id: &RoomId, default: bool) -> bool {
+        self.room(room_id).and_then(|p| p.dm).unwrap_or(default)
+    }
+
+    pub fn recreates(&self, room_id: &RoomId, default: bool) -> bool {
+        self.room(room_id)
+            .and_then(|p| p.recreate)
+            .unwrap_or(default)
+    }
+
+    /// Grants the new account `power_level` instead of the old account's level in rooms the plan
+    /// has none for, and at most `max_power_level`
+    pub fn set_power_levels(&mut self, power_level: Option<i64>, max_power_level: Option<i64>) {
+        self.power_level = power_level;
+        self.max_power_level = max_power_level;
+    }
+
+    /// The power level the new account should end up with, given the old account's. The old
+    /// account can't grant more than it has itself, and no level goes above `max_power_level`.
+    pub fn target_power_level(&self, room_id: &RoomId, old_power_level: i64) -> i64 {
+        let target = match self.room(room_id).and_then(|p| p.power_level) {
+            Some(power_level) => power_level,
+            None => self
+                .power_level
+                .map_or(old_power_level, |p| p.min(old_power_level)),
+        };
+        self.max_power_level.map_or(target, |max| target.min(max))
+    }
+}
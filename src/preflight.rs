@@ -0,0 +1,65 @@
+use matrix_sdk::{
+    ruma::api::{client::discovery::get_supported_versions, MatrixVersion},
+    Client,
+};
+use tracing::info;
+
+/// Checks before changing anything that the accounts can be migrated at all, so the run doesn't
+/// stop halfway through
+pub async fn check(from_c: &Client, to_c: &Client, knocks: bool) -> anyhow::Result<()> {
+    let from_user = from_c.user_id().unwrap();
+    let to_user = to_c.user_id().unwrap();
+    if from_user == to_user {
+        anyhow::bail!(
+            "--from and --to are both {from_user}, an account can't be migrated to itself"
+        );
+    }
+
+    for (c, which) in [(from_c, "old"), (to_c, "new")] {
+        let versions = c
+            .send(get_supported_versions::Request::new(), None)
+            .await
+            .map_err(|e| {
+                anyhow::anyhow!(
+                    "The homeserver of the {which} account ({}) doesn't respond: {e}. Check the \
+                    homeserver URL and its reverse proxy",
+                    c.homeserver()
+                )
+            })?;
+        let known = versions.known_versions().collect::<Vec<_>>();
+        if known.is_empty() {
+            anyhow::bail!(
+                "The homeserver of the {which} account ({}) supports none of the Matrix versions \
+                {:?}. It's too old or not a Matrix homeserver",
+                c.homeserver(),
+                versions.versions
+            );
+        }
+        info!("Homeserver of the {which} account supports Matrix {known:?}");
+
+        if which == "new" && knocks {
+            let unstable_knocks = versions
+                .unstable_features
+                .get("xyz.amorgan.knock")
+                .is_some_and(|enabled| *enabled);
+            // knocking came with Matrix 1.1, every later version has it as well
+            if known.iter().all(|v| *v == MatrixVersion::V1_0) && !unstable_knocks {
+                anyhow::bail!(
+                    "--migrate-knocks needs knocking (Matrix 1.1), which the homeserver of the new \
+                    account ({}) doesn't support",
+                    c.homeserver()
+                );
+            }
+        }
+    }
+
+    let whoami = to_c.whoami().await?;
+    if whoami.is_guest {
+        anyhow::bail!(
+            "{to_user} is a guest account, which can't be invited into rooms. Register a full \
+            account to migrate to"
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,196 @@
+use std::{
+    collections::VecDeque,
+    io::{IsTerminal, Write},
+    ops::Deref,
+    sync::{LazyLock, Mutex},
+};
+
+use dialoguer::Confirm;
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use matrix_sdk::{ruma::RoomId, Client};
+use tracing::{info_span, warn, Span};
+use tracing_subscriber::EnvFilter;
+
+use crate::{
+    hook, tui,
+    webhook::{self, Event},
+};
+
+/// All progress bars, drawn below the log output. Hidden when stderr isn't a terminal.
+static BARS: LazyLock<MultiProgress> = LazyLock::new(MultiProgress::new);
+
+/// The progress bars of all phases so far, for the dashboard
+static PHASES: Mutex<Vec<ProgressBar>> = Mutex::new(Vec::new());
+
+/// Log lines kept instead of written to stderr while the dashboard is shown
+static CAPTURED: Mutex<Option<VecDeque<String>>> = Mutex::new(None);
+
+/// How many captured log lines are kept
+const CAPTURED_LINES: usize = 1000;
+
+/// How log lines are written
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    /// One JSON object per line, with the room and phase as fields
+    Json,
+}
+
+/// Writes to stderr with the progress bars hidden, so log lines are printed above them instead
+/// of through them.
+struct BarsWriter;
+
+impl Write for BarsWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if let Some(lines) = CAPTURED.lock().unwrap().as_mut() {
+            for line in String::from_utf8_lossy(buf).lines() {
+                if lines.len() == CAPTURED_LINES {
+                    lines.pop_front();
+                }
+                lines.push_back(line.to_owned());
+            }
+            return Ok(buf.len());
+        }
+        BARS.suspend(|| std::io::stderr().write(buf))
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        std::io::stderr().flush()
+    }
+}
+
+/// Sets up logging with `filters` in `RUST_LOG` syntax, also picking up the `log` records of
+/// dependencies. `color` is only used on terminals.
+pub fn init_logging(filters: &str, format: LogFormat, color: bool) -> anyhow::Result<()> {
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::try_new(filters)?)
+        .with_ansi(color && std::io::stderr().is_terminal())
+        .with_writer(|| BarsWriter);
+    match format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder.json().try_init(),
+    }
+    .map_err(|e| anyhow::anyhow!(e))
+}
+
+/// Stops drawing progress bars, for quiet runs
+pub fn hide() {
+    BARS.set_draw_target(ProgressDrawTarget::hidden());
+}
+
+/// Keeps log lines in memory instead of writing them, or writes the kept ones out when stopped
+pub fn capture_logs(capture: bool) {
+    let captured = std::mem::replace(&mut *CAPTURED.lock().unwrap(), capture.then(VecDeque::new));
+    for line in captured.into_iter().flatten() {
+        eprintln!("{line}");
+    }
+}
+
+/// The last `n` captured log lines
+pub fn last_log_lines(n: usize) -> Vec<String> {
+    let captured = CAPTURED.lock().unwrap();
+    let Some(lines) = captured.as_ref() else {
+        return Vec::new();
+    };
+    lines
+        .iter()
+        .skip(lines.len().saturating_sub(n))
+        .cloned()
+        .collect()
+}
+
+/// The progress bars of all phases so far
+pub fn phases() -> Vec<ProgressBar> {
+    PHASES.lock().unwrap().clone()
+}
+
+/// Asks whether to go on with a destructive step after printing `summary`. `yes` answers for the
+/// user, without a terminal to ask on the answer is no.
+pub fn confirm(summary: &str, question: &str, yes: bool) -> anyhow::Result<bool> {
+    if yes {
+        return Ok(true);
+    }
+    if !std::io::stdin().is_terminal() {
+        warn!("{question} Not without confirmation, pass --yes to confirm without a terminal");
+        return Ok(false);
+    }
+    tui::suspend(|| {
+        BARS.suspend(|| {
+            eprintln!("{summary}");
+            Ok(Confirm::new()
+                .with_prompt(question)
+                .default(false)
+                .interact()?)
+        })
+    })
+}
+
+/// A progress bar for a phase working through `len` rooms. The message is meant for the room
+/// currently worked on.
+pub fn phase(name: &str, len: usize) -> Phase {
+    let bar = BARS.add(ProgressBar::new(len as u64));
+    bar.set_style(
+        ProgressStyle::with_template("{prefix:>12} [{bar:30}] {pos}/{len} ETA {eta} {wide_msg}")
+            .expect("valid template")
+            .progress_chars("=> "),
+    );
+    bar.set_prefix(name.to_owned());
+    PHASES.lock().unwrap().push(bar.clone());
+    hook::call(hook::Event::PhaseStarted {
+        phase: name,
+        rooms: len as u64,
+    });
+    Phase(bar)
+}
+
+/// The progress bar of a phase
+pub struct Phase(ProgressBar);
+
+impl Phase {
+    /// Leaves the bar as it is and announces the phase as completed
+    pub fn finish(&self) {
+        self.0.finish();
+        webhook::send(Event::PhaseCompleted {
+            phase: &self.0.prefix(),
+            rooms: self.0.position(),
+        });
+        hook::call(hook::Event::PhaseFinished {
+            phase: &self.0.prefix(),
+            rooms: self.0.position(),
+        });
+    }
+}
+
+impl Deref for Phase {
+    type Target = ProgressBar;
+
+    fn deref(&self) -> &ProgressBar {
+        &self.0
+    }
+}
+
+/// Advances its progress bar when dropped, however the work on the room ends.
+pub struct Step(ProgressBar);
+
+impl Drop for Step {
+    fn drop(&mut self) {
+        self.0.inc(1);
+    }
+}
+
+/// Shows the room as the current one of `bar` until the returned step is dropped.
+pub fn step(bar: &ProgressBar, c: &Client, room_id: &RoomId) -> Step {
+    let name = c.get_room(room_id).and_then(|r| r.name());
+    bar.set_message(name.unwrap_or_else(|| room_id.to_string()));
+    Step(bar.clone())
+}
+
+/// Span for the work of `phase` on a room, so its log lines carry the room's ID and name
+pub fn room_span(phase: &str, c: &Client, room_id: &RoomId) -> Span {
+    let name = c
+        .get_room(room_id)
+        .and_then(|r| r.name())
+        .unwrap_or_default();
+    info_span!("room", phase, %room_id, name)
+}
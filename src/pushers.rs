@@ -0,0 +1,85 @@
+use matrix_sdk::{
+    ruma::{
+        api::client::{
+            account::get_3pids,
+            push::{get_pushers, set_pusher, PusherKind},
+        },
+        thirdparty::Medium,
+    },
+    Client,
+};
+use tracing::{info, warn};
+
+use crate::report;
+
+/// Looks at the pushers and third-party identifiers of the old account, which stay behind with
+/// it. Email notifications are set up again for addresses the new account has too, everything
+/// else ends up in the report as something to do by hand.
+pub async fn check_pushers(from_c: &Client, to_c: &Client, dryrun: bool) -> anyhow::Result<()> {
+    let old_threepids = from_c.send(get_3pids::v3::Request::new(), None).await?;
+    let new_threepids = to_c.send(get_3pids::v3::Request::new(), None).await?;
+    let has_new = |medium: &Medium, address: &str| {
+        new_threepids
+            .threepids
+            .iter()
+            .any(|t| t.medium == *medium && t.address == address)
+    };
+
+    for threepid in &old_threepids.threepids {
+        let medium = match threepid.medium {
+            Medium::Email => "email address",
+            Medium::Msisdn => "phone number",
+            _ => "address",
+        };
+        let address = &threepid.address;
+        if !has_new(&threepid.medium, address) {
+            report::manual(
+                format!("{medium} {address}"),
+                "Remove it from the old account, then add it to the new one in the settings of \
+                your client, for password resets and notifications",
+            );
+        }
+        report::manual(
+            format!("discovery of {address}"),
+            "If others find you by it through an identity server, turn off its discovery on \
+            the old account and turn it on for the new one in the settings of your client",
+        );
+    }
+
+    let old_pushers = from_c.send(get_pushers::v3::Request::new(), None).await?;
+    let new_pushers = to_c.send(get_pushers::v3::Request::new(), None).await?;
+    for pusher in old_pushers.pushers {
+        if new_pushers.pushers.iter().any(|p| p.ids == pusher.ids) {
+            continue;
+        }
+        match &pusher.kind {
+            PusherKind::Email(_) if has_new(&Medium::Email, &pusher.ids.pushkey) => {
+                info!("Sending email notifications to {}", pusher.ids.pushkey);
+                if dryrun {
+                    continue;
+                }
+                let address = pusher.ids.pushkey.clone();
+                if let Err(e) = to_c.send(set_pusher::v3::Request::post(pusher), None).await {
+                    warn!("Couldn't set up email notifications to {address}: {e}");
+                    report::manual(
+                        format!("email notifications to {address}"),
+                        "Turn them on for the new account in the settings of your client",
+                    );
+                }
+            }
+            PusherKind::Email(_) => report::manual(
+                format!("email notifications to {}", pusher.ids.pushkey),
+                "Add the address to the new account, then turn them on in the settings of \
+                your client",
+            ),
+            _ => report::manual(
+                format!(
+                    "push notifications of {} on {}",
+                    pusher.app_display_name, pusher.device_display_name
+                ),
+                "Sign in to the new account in the app on that device to get its notifications",
+            ),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,121 @@
+use matrix_sdk::{
+    reqwest::{self, Url},
+    ruma::ServerName,
+    Client,
+};
+use serde::Deserialize;
+use tracing::{debug, info};
+
+use crate::http::endpoint;
+
+/// How a homeserver implementation differs from what the run expects by default
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Quirks {
+    /// Name of the profile, for the logs
+    pub name: &'static str,
+    /// Requests per second the homeserver takes without rate limiting the invites
+    pub requests_per_second: Option<f64>,
+    /// Whether the homeserver serves sliding sync
+    pub sliding_sync: bool,
+    /// Whether the homeserver has Synapse's admin API
+    pub admin_api: bool,
+}
+
+/// Synapse, and whatever else isn't known to differ from it
+const DEFAULT: Quirks = Quirks {
+    name: "default",
+    requests_per_second: None,
+    sliding_sync: true,
+    admin_api: true,
+};
+
+/// matrix.org limits invites much more strictly than Synapse does by default
+const MATRIX_ORG: Quirks = Quirks {
+    name: "matrix.org",
+    requests_per_second: Some(0.2),
+    ..DEFAULT
+};
+
+/// Conduit and its forks have neither the sliding sync proxy nor Synapse's admin API
+const CONDUIT: Quirks = Quirks {
+    name: "Conduit",
+    requests_per_second: None,
+    sliding_sync: false,
+    admin_api: false,
+};
+
+const DENDRITE: Quirks = Quirks {
+    name: "Dendrite",
+    requests_per_second: None,
+    sliding_sync: false,
+    admin_api: false,
+};
+
+#[derive(Deserialize)]
+struct ServerVersion {
+    server: Software,
+}
+
+#[derive(Deserialize)]
+struct Software {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct Delegation {
+    #[serde(rename = "m.server")]
+    server: String,
+}
+
+/// Where the federation API of `server_name` is served: where its `/.well-known/matrix/server`
+/// delegates to, or else port 8448 of the server name itself
+async fn federation_url(http: &reqwest::Client, server_name: &ServerName) -> Option<Url> {
+    let well_known = format!("https://{server_name}/.well-known/matrix/server");
+    let delegation = match http.get(well_known).send().await {
+        Ok(response) => response.json::<Delegation>().await.ok(),
+        Err(_) => None,
+    };
+    let server = delegation.map_or_else(|| server_name.to_string(), |d| d.server);
+    let mut url = Url::parse(&format!("https://{server}")).ok()?;
+    if url.port().is_none() {
+        url.set_port(Some(8448)).ok()?;
+    }
+    Some(url)
+}
+
+/// The implementation the server at `base` reports on its federation API, if it's reachable
+async fn software_at(http: &reqwest::Client, base: &Url) -> Option<String> {
+    let url = endpoint(base, &["_matrix", "federation", "v1", "version"]).ok()?;
+    let response = http.get(url).send().await.ok()?.error_for_status().ok()?;
+    let version = response.json::<ServerVersion>().await.ok()?;
+    Some(version.server.name)
+}
+
+/// The implementation the homeserver of `c` reports, asking the client base URL first since most
+/// homeservers serve federation there too, then where federation is delegated to
+async fn software(http: &reqwest::Client, c: &Client) -> Option<String> {
+    if let Some(software) = software_at(http, &c.homeserver()).await {
+        return Some(software);
+    }
+    let federation = federation_url(http, c.user_id()?.server_name()).await?;
+    software_at(http, &federation).await
+}
+
+/// Picks the profile of the homeserver of `c`, by its server name or the implementation it
+/// reports
+pub async fn detect(http: &reqwest::Client, c: &Client) -> Quirks {
+    let server_name = c.user_id().unwrap().server_name();
+    if server_name == "matrix.org" {
+        return MATRIX_ORG;
+    }
+    let Some(software) = software(http, c).await else {
+        info!("Couldn't tell which implementation {server_name} runs, using the default profile");
+        return DEFAULT;
+    };
+    debug!("{server_name} runs {software}");
+    match software.to_lowercase().as_str() {
+        s if s.contains("conduit") || s.contains("continuwuity") => CONDUIT,
+        "dendrite" => DENDRITE,
+        _ => DEFAULT,
+    }
+}
@@ -0,0 +1,86 @@
+use matrix_sdk::{reqwest::StatusCode, Client};
+use serde::Deserialize;
+use serde_json::json;
+use tracing::info;
+
+use crate::{auth::Login, http::endpoint, uia};
+
+#[derive(Deserialize)]
+struct MatrixError {
+    errcode: String,
+    #[serde(default)]
+    error: String,
+}
+
+/// Registers the account of `login` with its password, unless it exists already. Registration
+/// tokens and email verification are answered from `answers` or prompted for, every other stage
+/// goes through the homeserver's fallback page.
+pub async fn register(
+    login: &Login,
+    answers: &uia::Answers<'_>,
+    dryrun: bool,
+) -> anyhow::Result<()> {
+    let Some(user_id) = &login.user else {
+        anyhow::bail!("--register-to needs --to");
+    };
+    let Some(password) = &login.password else {
+        anyhow::bail!(
+            "--register-to needs the password of the new account, --to-pw or --to-pw-file"
+        );
+    };
+    let http = login.http_client()?;
+    let server_name = login.homeserver.as_deref().unwrap_or(user_id.server_name());
+    let homeserver = Client::builder()
+        .http_client(http.clone())
+        .server_name(server_name)
+        .build()
+        .await?
+        .homeserver();
+
+    let available = http
+        .get(endpoint(
+            &homeserver,
+            &["_matrix", "client", "v3", "register", "available"],
+        )?)
+        .query(&[("username", user_id.localpart())])
+        .send()
+        .await?;
+    if available.status() != StatusCode::OK {
+        let e: MatrixError = available.json().await?;
+        if e.errcode == "M_USER_IN_USE" {
+            info!("{user_id} exists already, not registering it");
+            return Ok(());
+        }
+        anyhow::bail!("Can't register {user_id}: {} {}", e.errcode, e.error);
+    }
+
+    info!("Registering {user_id}");
+    if dryrun {
+        anyhow::bail!(
+            "{user_id} doesn't exist yet, it's registered when running without --dry-run"
+        );
+    }
+    let url = endpoint(&homeserver, &["_matrix", "client", "v3", "register"])?;
+    uia::send(
+        &http,
+        &homeserver,
+        None,
+        None,
+        answers,
+        &format!("registering {user_id}"),
+        |auth| {
+            http.post(url.clone()).json(&json!({
+                "auth": auth,
+                "username": user_id.localpart(),
+                "password": password,
+                "initial_device_display_name": login.device_name,
+                // the migration logs in by itself afterwards
+                "inhibit_login": true,
+            }))
+        },
+    )
+    .await?
+    .error_for_status()?;
+    info!("{user_id} has been registered");
+    Ok(())
+}
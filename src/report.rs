@@ -0,0 +1,701 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    path::Path,
+    process::ExitCode,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::SystemTime,
+};
+
+use matrix_sdk::{
+    ruma::{
+        api::client::room::create_room::{self, v3::RoomPreset},
+        events::room::message::RoomMessageEventContent,
+        OwnedRoomId, OwnedUserId, RoomId, UserId,
+    },
+    Client, RoomState,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    context,
+    export::escape,
+    metrics,
+    webhook::{self, Event},
+};
+use tracing::{info, warn};
+
+/// The report of a run, filled in by the migration steps as they go
+#[derive(Default)]
+pub struct State {
+    /// What happened to the rooms so far
+    rooms: Mutex<BTreeMap<OwnedRoomId, RoomReport>>,
+    /// What a failed room means for the rest of the run
+    on_error: Mutex<OnError>,
+    /// What's left for the user to do by hand, outside of the rooms
+    manual: Mutex<Vec<ManualStep>>,
+    /// Joined rooms not selected by the filters, which aren't part of the report
+    filtered: AtomicUsize,
+    /// The accounts migrated into the new one, several when merging accounts
+    sources: Mutex<Vec<OwnedUserId>>,
+}
+
+impl State {
+    /// The rooms as they are now, while the run goes on
+    pub fn snapshot(&self) -> Vec<RoomReport> {
+        self.rooms.lock().unwrap().values().cloned().collect()
+    }
+
+    /// Sets what a failed room means for the rest of the run
+    pub fn on_error(&self, policy: OnError) {
+        *self.on_error.lock().unwrap() = policy;
+    }
+}
+
+/// How the result of a run is printed
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Log lines only
+    #[default]
+    Text,
+    /// The report as JSON on stdout, logs stay on stderr
+    Json,
+}
+
+/// What to do when an action for a room fails
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OnError {
+    /// Record the failure and go on with the other rooms
+    #[default]
+    Continue,
+    /// Stop the whole run at the first failure
+    Abort,
+}
+
+/// An action taken (or, on dry runs, planned) for a room
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Unbanned,
+    Invited,
+    /// Joined without an invite, through the admin API or a restricted join rule
+    Joined,
+    Accepted,
+    /// Knocked with the new account after the invite failed, until a moderator lets it in
+    Knocked,
+    PowerLevel,
+    DmRecreated,
+    /// Re-created from the new account, the old room points to the new one
+    Recreated,
+    Left,
+}
+
+/// Why an action failed, for grouping the failures of a run
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Reason {
+    RateLimited,
+    NoPermission,
+    Federation,
+    Other,
+}
+
+impl Reason {
+    /// Whether trying again later might succeed
+    pub fn is_transient(self) -> bool {
+        matches!(self, Self::RateLimited | Self::Federation)
+    }
+}
+
+impl Display for Reason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::RateLimited => "rate limited",
+            Self::NoPermission => "no permission",
+            Self::Federation => "federation error",
+            Self::Other => "other",
+        })
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Failure {
+    pub reason: Reason,
+    pub message: String,
+}
+
+/// Something the migration couldn't carry over to the new account, and how to do it by hand
+#[derive(Serialize, Clone, Debug)]
+pub struct ManualStep {
+    pub what: String,
+    pub instructions: String,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    /// Migrated in this run
+    Migrated,
+    /// Nothing left to do from previous runs
+    AlreadyMigrated,
+    /// Would be migrated, on dry runs
+    Planned,
+    /// Invited, but not joined yet
+    Pending,
+    Skipped,
+    Failed,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct RoomReport {
+    pub room_id: OwnedRoomId,
+    pub name: Option<String>,
+    pub dm: bool,
+    pub status: Status,
+    pub actions: Vec<Action>,
+    /// Power level of the old account
+    pub power_level_before: Option<i64>,
+    /// Power level of the new account after the migration
+    pub power_level_after: Option<i64>,
+    /// Whether the old account left the room
+    pub left: bool,
+    pub skipped: Option<String>,
+    pub errors: Vec<Failure>,
+    /// Differences between the accounts merged into the new one, which share the room
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub conflicts: Vec<String>,
+}
+
+impl RoomReport {
+    /// What is planned for the room, in words
+    fn planned(&self) -> String {
+        if let Some(reason) = &self.skipped {
+            return format!("skip ({reason})");
+        }
+        if !self.errors.is_empty() {
+            let errors = self.errors.iter().map(|e| e.message.as_str());
+            return format!("fails: {}", errors.collect::<Vec<_>>().join("; "));
+        }
+        if self.actions.is_empty() {
+            return "nothing to do".to_owned();
+        }
+
+        self.actions
+            .iter()
+            .map(|a| match a {
+                Action::Unbanned => "unban".to_owned(),
+                Action::Invited => "invite".to_owned(),
+                Action::Joined => "join".to_owned(),
+                Action::Accepted => "accept".to_owned(),
+                Action::Knocked => "knock".to_owned(),
+                Action::PowerLevel => match self.power_level_after {
+                    Some(level) => format!("raise PL to {level}"),
+                    None => "raise PL".to_owned(),
+                },
+                Action::DmRecreated => "re-create DM".to_owned(),
+                Action::Recreated => "re-create".to_owned(),
+                Action::Left => "leave".to_owned(),
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// Where the room is at, in words
+    pub fn state(&self) -> String {
+        if let Some(error) = self.errors.last() {
+            return format!("failed: {}", error.message);
+        }
+        if let Some(reason) = &self.skipped {
+            return format!("skipped ({reason})");
+        }
+        match self.actions.last() {
+            Some(action) => name(action),
+            None => "waiting".to_owned(),
+        }
+    }
+
+    fn new(room_id: &RoomId) -> Self {
+        Self {
+            room_id: room_id.to_owned(),
+            name: None,
+            dm: false,
+            status: Status::Pending,
+            actions: Vec::new(),
+            power_level_before: None,
+            power_level_after: None,
+            left: false,
+            skipped: None,
+            errors: Vec::new(),
+            conflicts: Vec::new(),
+        }
+    }
+}
+
+#[derive(Serialize, Default, Debug)]
+pub struct Counters {
+    pub rooms: usize,
+    pub filtered: usize,
+    pub migrated: usize,
+    pub already_migrated: usize,
+    pub planned: usize,
+    pub pending: usize,
+    pub skipped: usize,
+    pub failed: usize,
+    pub invites: usize,
+    pub accepted: usize,
+    pub power_levels: usize,
+    pub left: usize,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Report {
+    /// The account migrated, unless several were merged into the new one
+    pub from: Option<OwnedUserId>,
+    /// The accounts merged into the new one
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub merged_from: Vec<OwnedUserId>,
+    pub to: Option<OwnedUserId>,
+    pub finished_at: String,
+    pub dry_run: bool,
+    pub counters: Counters,
+    pub rooms: Vec<RoomReport>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub manual: Vec<ManualStep>,
+}
+
+fn update(room_id: &RoomId, f: impl FnOnce(&mut RoomReport)) {
+    context::with(|c| {
+        let mut rooms = c.report.rooms.lock().unwrap();
+        f(rooms
+            .entry(room_id.to_owned())
+            .or_insert_with(|| RoomReport::new(room_id)))
+    });
+}
+
+/// Adds `rooms` to the report, even if nothing is done with them
+pub fn track<'a>(rooms: impl IntoIterator<Item = &'a OwnedRoomId>) {
+    for room_id in rooms {
+        update(room_id, |_| {});
+    }
+}
+
+pub fn action(room_id: &RoomId, action: Action) {
+    metrics::action(action);
+    update(room_id, |r| r.actions.push(action));
+}
+
+/// Records the failure of an action for a room. Fails with `--on-error abort`, to stop the run.
+pub fn fail(room_id: &RoomId, reason: Reason, message: impl Display) -> anyhow::Result<()> {
+    let message = message.to_string();
+    metrics::failure(reason);
+    webhook::send(Event::RoomFailed {
+        room_id,
+        reason,
+        message: &message,
+    });
+    update(room_id, |r| {
+        r.errors.push(Failure {
+            reason,
+            message: message.clone(),
+        })
+    });
+    let abort = context::with(|c| *c.report.on_error.lock().unwrap() == OnError::Abort);
+    if abort == Some(true) {
+        anyhow::bail!("{room_id}: {message}. Stopping the run (--on-error abort)");
+    }
+    Ok(())
+}
+
+/// Records `from` as an account migrated into the new one
+pub fn source(from: &UserId) {
+    context::with(|c| {
+        let mut sources = c.report.sources.lock().unwrap();
+        if !sources.iter().any(|s| s == from) {
+            sources.push(from.to_owned());
+        }
+    });
+}
+
+/// Records how many rooms the filters left out
+pub fn filtered(rooms: usize) {
+    context::with(|c| c.report.filtered.store(rooms, Ordering::Relaxed));
+}
+
+pub fn skip(room_id: &RoomId, reason: impl Into<String>) {
+    update(room_id, |r| r.skipped = Some(reason.into()));
+}
+
+/// Records a difference between the merged accounts sharing `room_id`
+pub fn conflict(room_id: &RoomId, message: impl Into<String>) {
+    update(room_id, |r| r.conflicts.push(message.into()));
+}
+
+/// Records something the user has to do by hand, as the migration can't
+pub fn manual(what: impl Into<String>, instructions: impl Into<String>) {
+    let step = ManualStep {
+        what: what.into(),
+        instructions: instructions.into(),
+    };
+    context::with(|c| c.report.manual.lock().unwrap().push(step));
+}
+
+/// Records the power levels of the old account and the one the new account ends up with
+pub fn power_levels(room_id: &RoomId, before: i64, after: i64) {
+    update(room_id, |r| {
+        r.power_level_before = Some(before);
+        r.power_level_after = Some(after);
+    });
+}
+
+/// Settles the status of every room and counts them up
+pub async fn finish(from_c: &Client, to_c: &Client, dry_run: bool) -> Report {
+    let (mut rooms, filtered, manual, sources) = context::with(|c| {
+        (
+            c.report.snapshot(),
+            c.report.filtered.load(Ordering::Relaxed),
+            c.report.manual.lock().unwrap().clone(),
+            c.report.sources.lock().unwrap().clone(),
+        )
+    })
+    .unwrap_or_default();
+
+    let mut counters = Counters {
+        filtered,
+        ..Default::default()
+    };
+    for room in &mut rooms {
+        if let Some(r) = from_c.get_room(&room.room_id) {
+            room.name = r.display_name().await.ok().map(|n| n.to_string());
+            room.dm = r.is_direct().await.unwrap_or(false);
+        }
+        room.left = room.actions.contains(&Action::Left);
+
+        // rooms joined through the admin API only show up with the next sync, re-created DMs
+        // live on in a new room
+        let joined = to_c
+            .get_room(&room.room_id)
+            .is_some_and(|r| r.state() == RoomState::Joined)
+            || room.actions.contains(&Action::Joined)
+            || room.actions.contains(&Action::DmRecreated)
+            || room.actions.contains(&Action::Recreated);
+        room.status = if !room.errors.is_empty() {
+            Status::Failed
+        } else if room.skipped.is_some() {
+            Status::Skipped
+        } else if room.actions.is_empty() {
+            if joined {
+                Status::AlreadyMigrated
+            } else {
+                Status::Pending
+            }
+        } else if dry_run {
+            Status::Planned
+        } else if joined {
+            Status::Migrated
+        } else {
+            Status::Pending
+        };
+
+        counters.rooms += 1;
+        match room.status {
+            Status::Migrated => counters.migrated += 1,
+            Status::AlreadyMigrated => counters.already_migrated += 1,
+            Status::Planned => counters.planned += 1,
+            Status::Pending => counters.pending += 1,
+            Status::Skipped => counters.skipped += 1,
+            Status::Failed => counters.failed += 1,
+        }
+        for action in &room.actions {
+            match action {
+                Action::Invited => counters.invites += 1,
+                Action::Accepted | Action::Joined => counters.accepted += 1,
+                Action::PowerLevel => counters.power_levels += 1,
+                Action::Left => counters.left += 1,
+                Action::Unbanned | Action::Knocked | Action::DmRecreated | Action::Recreated => {}
+            }
+        }
+    }
+
+    let (from, merged_from) = if sources.len() > 1 {
+        (None, sources)
+    } else {
+        (from_c.user_id().map(ToOwned::to_owned), Vec::new())
+    };
+    Report {
+        from,
+        merged_from,
+        to: to_c.user_id().map(ToOwned::to_owned),
+        finished_at: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+        dry_run,
+        counters,
+        rooms,
+        manual,
+    }
+}
+
+/// Exit code when the run was aborted, by an error or from the dashboard
+pub const EXIT_ABORTED: u8 = 1;
+
+/// Exit code when some rooms were skipped, but everything else was migrated
+pub const EXIT_SKIPPED: u8 = 2;
+
+/// Exit code when migrating some rooms failed
+pub const EXIT_FAILED: u8 = 3;
+
+impl Report {
+    /// How the run ended, for wrapper scripts. Aborted runs exit with [`EXIT_ABORTED`].
+    pub fn exit_code(&self) -> ExitCode {
+        if self.counters.failed > 0 {
+            ExitCode::from(EXIT_FAILED)
+        } else if self.counters.skipped > 0 {
+            ExitCode::from(EXIT_SKIPPED)
+        } else {
+            ExitCode::SUCCESS
+        }
+    }
+
+    /// Logs how many rooms were migrated, skipped and failed and why
+    pub fn log_summary(&self) {
+        let c = &self.counters;
+        let migrated = if self.dry_run {
+            format!("{} rooms to migrate", c.planned)
+        } else {
+            format!("{} rooms migrated", c.migrated)
+        };
+        info!(
+            "--- Summary: {migrated}, {} already migrated, {} pending, {} skipped, {} filtered out, {} failed",
+            c.already_migrated, c.pending, c.skipped, c.filtered, c.failed
+        );
+
+        let mut skipped = BTreeMap::<_, usize>::new();
+        let mut failed = BTreeMap::<_, Vec<&RoomId>>::new();
+        for room in &self.rooms {
+            if let Some(reason) = &room.skipped {
+                *skipped.entry(reason.as_str()).or_default() += 1;
+            }
+            for error in &room.errors {
+                failed.entry(error.reason).or_default().push(&room.room_id);
+            }
+        }
+        for (reason, count) in skipped {
+            info!("    skipped, {reason}: {count}");
+        }
+        for (reason, rooms) in failed {
+            warn!("    failed, {reason}: {} {rooms:?}", rooms.len());
+        }
+        for step in &self.manual {
+            warn!("    to do by hand, {}: {}", step.what, step.instructions);
+        }
+    }
+
+    /// The planned actions of every room as a table, for reviewing a dry run
+    pub fn plan_table(&self) -> String {
+        let rows = self
+            .rooms
+            .iter()
+            .map(|r| {
+                let name = r.name.clone().unwrap_or_default();
+                (truncate(&name, 40), r.room_id.to_string(), r.planned())
+            })
+            .collect::<Vec<_>>();
+
+        let name_width = rows.iter().map(|r| r.0.chars().count()).max().unwrap_or(0);
+        let id_width = rows.iter().map(|r| r.1.len()).max().unwrap_or(0);
+        let name_width = name_width.max("Room".len());
+        let id_width = id_width.max("ID".len());
+
+        let mut table = format!("{:name_width$}  {:id_width$}  Plan\n", "Room", "ID");
+        table += &format!("{}\n", "-".repeat(name_width + id_width + 10));
+        for (name, id, plan) in rows {
+            table += &format!("{name:name_width$}  {id:id_width$}  {plan}\n");
+        }
+        table
+    }
+
+    /// Writes one line per room to `path`, for triaging failures in a spreadsheet
+    pub fn write_csv(&self, path: &Path) -> anyhow::Result<()> {
+        let mut csv = "name,room_id,dm,actions,status,error\n".to_owned();
+        for room in &self.rooms {
+            let actions = room.actions.iter().map(name).collect::<Vec<_>>();
+            let errors = room
+                .errors
+                .iter()
+                .map(|e| e.message.as_str())
+                .chain(room.skipped.as_deref())
+                .collect::<Vec<_>>();
+            let fields = [
+                room.name.as_deref().unwrap_or_default(),
+                room.room_id.as_str(),
+                if room.dm { "yes" } else { "no" },
+                &actions.join(" "),
+                &name(&room.status),
+                &errors.join("; "),
+            ];
+            csv += &fields.map(csv_field).join(",");
+            csv += "\n";
+        }
+
+        std::fs::write(path, csv)
+            .map_err(|e| anyhow::anyhow!("Couldn't write the report {}: {e}", path.display()))?;
+        info!("Wrote the CSV report to {}", path.display());
+        Ok(())
+    }
+
+    /// Writes the report as JSON to `path`, for auditing the migration later on
+    pub fn write(&self, path: &Path) -> anyhow::Result<()> {
+        std::fs::write(path, serde_json::to_vec_pretty(self)?)
+            .map_err(|e| anyhow::anyhow!("Couldn't write the report {}: {e}", path.display()))?;
+        info!("Wrote the migration report to {}", path.display());
+        Ok(())
+    }
+}
+
+pub fn truncate(s: &str, max: usize) -> String {
+    if s.chars().count() <= max {
+        return s.to_owned();
+    }
+    let mut truncated = s.chars().take(max - 1).collect::<String>();
+    truncated.push('…');
+    truncated
+}
+
+/// Name of the room the report is posted to on the new account
+const REPORT_ROOM_NAME: &str = "Migration report";
+
+/// How many rooms of each status are listed in the message, to stay below the event size limit
+const MAX_LISTED_ROOMS: usize = 100;
+
+impl Report {
+    /// The report as a chat message, in plain text and HTML
+    fn message(&self) -> (String, String) {
+        let c = &self.counters;
+        let title = match (&self.from, &self.to) {
+            (Some(from), Some(to)) => format!("Migration of {from} to {to}"),
+            (None, Some(to)) if !self.merged_from.is_empty() => {
+                let from = self.merged_from.iter().map(|u| u.as_str());
+                format!(
+                    "Migration of {} to {to}",
+                    from.collect::<Vec<_>>().join(", ")
+                )
+            }
+            _ => "Migration".to_owned(),
+        };
+        let summary = format!(
+            "{} rooms migrated, {} already migrated, {} pending, {} skipped, {} failed",
+            c.migrated, c.already_migrated, c.pending, c.skipped, c.failed
+        );
+
+        let mut plain = format!("{title} ({})\n{summary}\n", self.finished_at);
+        let mut html = format!(
+            "<h3>{}</h3><p>{}<br>{}</p>",
+            escape(&title),
+            self.finished_at,
+            escape(&summary)
+        );
+
+        let sections = [
+            ("Failed", Status::Failed),
+            ("Pending", Status::Pending),
+            ("Skipped", Status::Skipped),
+            ("Migrated", Status::Migrated),
+        ];
+        for (heading, status) in sections {
+            let rooms = self.rooms.iter().filter(|r| r.status == status);
+            let rooms = rooms.collect::<Vec<_>>();
+            if rooms.is_empty() {
+                continue;
+            }
+            plain += &format!("\n{heading}:\n");
+            html += &format!("<p><b>{heading}</b></p><ul>");
+            for room in rooms.iter().take(MAX_LISTED_ROOMS) {
+                let name = room.name.as_deref().unwrap_or("");
+                let detail = match room.status {
+                    Status::Failed => room
+                        .errors
+                        .iter()
+                        .map(|e| e.message.as_str())
+                        .collect::<Vec<_>>()
+                        .join("; "),
+                    Status::Skipped => room.skipped.clone().unwrap_or_default(),
+                    _ => String::new(),
+                };
+                let detail = if detail.is_empty() {
+                    detail
+                } else {
+                    format!(": {detail}")
+                };
+                plain += &format!("- {name} ({}){detail}\n", room.room_id);
+                html += &format!(
+                    "<li>{} (<code>{}</code>){}</li>",
+                    escape(name),
+                    room.room_id,
+                    escape(&detail)
+                );
+            }
+            if rooms.len() > MAX_LISTED_ROOMS {
+                let more = format!("… and {} more", rooms.len() - MAX_LISTED_ROOMS);
+                plain += &format!("{more}\n");
+                html += &format!("<li>{more}</li>");
+            }
+            html += "</ul>";
+        }
+
+        if !self.manual.is_empty() {
+            plain += "\nTo do by hand:\n";
+            html += "<p><b>To do by hand</b></p><ul>";
+            for step in &self.manual {
+                plain += &format!("- {}: {}\n", step.what, step.instructions);
+                html += &format!(
+                    "<li>{}: {}</li>",
+                    escape(&step.what),
+                    escape(&step.instructions)
+                );
+            }
+            html += "</ul>";
+        }
+        (plain, html)
+    }
+
+    /// Posts the report to a private room of the account of `c`, created on the first run and
+    /// reused afterwards
+    pub async fn post(&self, c: &Client) -> anyhow::Result<()> {
+        let own_room = c.joined_rooms().into_iter().find(|r| {
+            r.name().as_deref() == Some(REPORT_ROOM_NAME) && r.joined_members_count() == 1
+        });
+        let room = match own_room {
+            Some(room) => room,
+            None => {
+                let mut request = create_room::v3::Request::new();
+                request.name = Some(REPORT_ROOM_NAME.to_owned());
+                request.preset = Some(RoomPreset::PrivateChat);
+                c.create_room(request).await?
+            }
+        };
+
+        let (plain, html) = self.message();
+        room.send(RoomMessageEventContent::text_html(plain, html))
+            .await?;
+        info!("Posted the report to {}", room.room_id());
+        Ok(())
+    }
+}
+
+/// The name of a unit variant as it appears in the JSON report
+pub fn name(value: &impl Serialize) -> String {
+    match serde_json::to_value(value) {
+        Ok(serde_json::Value::String(name)) => name,
+        _ => String::new(),
+    }
+}
+
+/// Quotes `s` if it contains anything with a meaning in CSV
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_owned()
+    }
+}
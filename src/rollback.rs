@@ -0,0 +1,249 @@
+use std::{collections::BTreeMap, process::ExitCode};
+
+use matrix_sdk::{
+    ruma::{events::StateEventType, Int, OwnedRoomId, RoomId, UserId},
+    Client, Room, RoomState,
+};
+use tracing::{info, warn, Instrument};
+
+use crate::{
+    journal::Entry,
+    limit::Limiter,
+    progress,
+    report::{Action, EXIT_FAILED},
+};
+
+/// Reverses the actions of the journal `entries`: the new account leaves the rooms it joined
+/// and the DMs and rooms it created, pending invites are retracted and changed power levels are
+/// set back. Rooms the old account left can't be rejoined automatically and are only listed.
+pub async fn rollback(
+    from_c: &Client,
+    to_c: &Client,
+    entries: &[Entry],
+    limiter: &Limiter,
+    dryrun: bool,
+) -> anyhow::Result<ExitCode> {
+    let mut rooms: BTreeMap<&OwnedRoomId, Vec<&Entry>> = BTreeMap::new();
+    for entry in entries {
+        rooms.entry(&entry.room_id).or_default().push(entry);
+    }
+    info!(
+        "--- Rolling back {} actions in {} rooms",
+        entries.len(),
+        rooms.len()
+    );
+
+    let bar = progress::phase("Rolling back", rooms.len());
+    let mut failed = Vec::new();
+    let mut left = Vec::new();
+    let mut recreated = Vec::new();
+    for (room_id, entries) in rooms {
+        let _step = progress::step(&bar, from_c, room_id);
+        let done = |action| entries.iter().any(|e| e.action == action);
+        let result = async {
+            // before the new account's level is lowered, as it may have to restore the level of
+            // the old account, which left
+            for entry in entries.iter().filter(|e| e.user.is_some()) {
+                if let (Some(user), Some(previous)) = (&entry.user, entry.previous_power_level) {
+                    revert_old_power_level(from_c, to_c, room_id, user, previous, limiter, dryrun)
+                        .await?;
+                }
+            }
+            // the first entry has the power level from before the migration
+            if let Some(previous) = entries
+                .iter()
+                .filter(|e| e.user.is_none())
+                .find_map(|e| e.previous_power_level)
+            {
+                revert_power_level(from_c, to_c, room_id, previous, limiter, dryrun).await?;
+            }
+            if done(Action::Invited)
+                || done(Action::Knocked)
+                || done(Action::Accepted)
+                || done(Action::Joined)
+            {
+                remove_new_account(from_c, to_c, room_id, limiter, dryrun).await?;
+            }
+            for dm in entries.iter().filter_map(|e| e.created_room.as_ref()) {
+                remove_new_account(from_c, to_c, dm, limiter, dryrun).await?;
+            }
+            anyhow::Ok(())
+        }
+        .instrument(progress::room_span("rollback", from_c, room_id))
+        .await;
+
+        if let Err(e) = result {
+            warn!("Rolling back {room_id} failed: {e}");
+            failed.push(room_id);
+        }
+        if done(Action::Left) {
+            left.push(room_id);
+        }
+        if done(Action::Recreated) {
+            recreated.push(room_id);
+        }
+    }
+    bar.finish();
+
+    if !left.is_empty() {
+        warn!(
+            "The old account left {:?} during the migration. It has to be invited back manually",
+            left
+        );
+    }
+    if !recreated.is_empty() {
+        warn!(
+            "{:?} were re-created during the migration. The new account left the new rooms, but \
+            the old ones keep pointing to them",
+            recreated
+        );
+    }
+    if failed.is_empty() {
+        info!("--- Rolled back");
+        Ok(ExitCode::SUCCESS)
+    } else {
+        warn!(
+            "Failed to roll back {:?}. See logs above for the reasons why",
+            failed
+        );
+        Ok(ExitCode::from(EXIT_FAILED))
+    }
+}
+
+/// Lowers the new account's power level in `room_id` back to `previous`. The new account lowers
+/// it itself, which it may even at the old account's level, and only where it can't the old
+/// account does.
+async fn revert_power_level(
+    from_c: &Client,
+    to_c: &Client,
+    room_id: &RoomId,
+    previous: i64,
+    limiter: &Limiter,
+    dryrun: bool,
+) -> anyhow::Result<()> {
+    let new_user = to_c.user_id().unwrap();
+    let previous = Int::try_from(previous)?;
+    let (c, room) = match sender(to_c, room_id, new_user).await? {
+        Some(room) => (to_c, room),
+        None => match sender(from_c, room_id, from_c.user_id().unwrap()).await? {
+            Some(room) => (from_c, room),
+            None => {
+                warn!(
+                    "Neither account may change the power levels of {room_id}, can't lower the \
+                    power level of {new_user}"
+                );
+                return Ok(());
+            }
+        },
+    };
+    info!("Lowering the power level of {new_user} in {room_id} back to {previous}");
+    if dryrun {
+        return Ok(());
+    }
+    limiter
+        .run(c, || room.update_power_levels(vec![(new_user, previous)]))
+        .await?;
+    Ok(())
+}
+
+/// Sets the power level of `user`, another account than the new one, in `room_id` back to
+/// `previous`, unless the migration already did: lowers it where the admin API made it admin,
+/// or raises it where `--demote-old` dropped it. The old account does it while it's still in the
+/// room, otherwise the new account.
+async fn revert_old_power_level(
+    from_c: &Client,
+    to_c: &Client,
+    room_id: &RoomId,
+    user: &UserId,
+    previous: i64,
+    limiter: &Limiter,
+    dryrun: bool,
+) -> anyhow::Result<()> {
+    let previous = Int::try_from(previous)?;
+    let (c, room) = match sender(from_c, room_id, from_c.user_id().unwrap()).await? {
+        Some(room) => (from_c, room),
+        None => match sender(to_c, room_id, to_c.user_id().unwrap()).await? {
+            Some(room) => (to_c, room),
+            None => {
+                warn!(
+                    "Neither account may change the power levels of {room_id}, can't set the \
+                    power level of {user} back to {previous}"
+                );
+                return Ok(());
+            }
+        },
+    };
+    let current = room
+        .get_member_no_sync(user)
+        .await?
+        .map(|m| m.power_level());
+    if current == Some(previous.into()) {
+        return Ok(());
+    }
+    info!("Setting the power level of {user} in {room_id} back to {previous}");
+    if dryrun {
+        return Ok(());
+    }
+    limiter
+        .run(c, || room.update_power_levels(vec![(user, previous)]))
+        .await?;
+    Ok(())
+}
+
+/// `room_id` if `user_id` of `c` is joined to it and may change its power levels
+async fn sender(c: &Client, room_id: &RoomId, user_id: &UserId) -> anyhow::Result<Option<Room>> {
+    let Some(room) = c
+        .get_room(room_id)
+        .filter(|r| r.state() == RoomState::Joined)
+    else {
+        return Ok(None);
+    };
+    let allowed = room
+        .can_user_send_state(user_id, StateEventType::RoomPowerLevels)
+        .await?;
+    Ok(allowed.then_some(room))
+}
+
+/// Takes the new account out of `room_id`: leaves it if joined, otherwise the old account
+/// retracts the invite, or the new account rejects it where the old one can't.
+async fn remove_new_account(
+    from_c: &Client,
+    to_c: &Client,
+    room_id: &RoomId,
+    limiter: &Limiter,
+    dryrun: bool,
+) -> anyhow::Result<()> {
+    let new_user = to_c.user_id().unwrap();
+    let Some(room) = to_c.get_room(room_id) else {
+        info!("{new_user} isn't in {room_id} anymore");
+        return Ok(());
+    };
+    match room.state() {
+        RoomState::Joined => {
+            info!("Leaving {room_id} with {new_user}");
+            if !dryrun {
+                limiter.run(to_c, || room.leave()).await?;
+            }
+        }
+        RoomState::Invited => {
+            info!("Retracting the invite of {new_user} to {room_id}");
+            if dryrun {
+                return Ok(());
+            }
+            let retracted = match from_c.get_room(room_id) {
+                Some(from_room) => limiter
+                    .run(from_c, || {
+                        from_room.kick_user(new_user, Some("Account migration rolled back"))
+                    })
+                    .await
+                    .is_ok(),
+                None => false,
+            };
+            if !retracted {
+                limiter.run(to_c, || room.leave()).await?;
+            }
+        }
+        RoomState::Left => info!("{new_user} already left {room_id}"),
+    }
+    Ok(())
+}
@@ -0,0 +1,382 @@
+use std::collections::HashMap;
+
+use matrix_sdk::{
+    deserialized_responses::SyncOrStrippedState,
+    ruma::{
+        api::client::state::get_state_events_for_key,
+        events::{
+            room::{
+                join_rules::{AllowRule, JoinRule, RoomJoinRulesEventContent},
+                tombstone::RoomTombstoneEventContent,
+            },
+            space::{child::SpaceChildEventContent, parent::SpaceParentEventContent},
+            StateEventType, SyncStateEvent,
+        },
+        OwnedRoomId, RoomId,
+    },
+    Client, Room, RoomState,
+};
+use tracing::{info, warn};
+
+use crate::{audit, limit::Limiter, report};
+
+/// The rooms re-created by the new account, by the ID of the room they replace
+struct Replacements {
+    c: Client,
+    known: HashMap<OwnedRoomId, OwnedRoomId>,
+}
+
+impl Replacements {
+    fn new(from_c: &Client) -> Self {
+        Self {
+            c: from_c.clone(),
+            known: HashMap::new(),
+        }
+    }
+
+    /// The room that replaces `room_id` according to its tombstone, or `room_id` itself. Asks
+    /// the homeserver, as the tombstones sent during this run aren't synced yet.
+    async fn get(&mut self, room_id: &RoomId) -> OwnedRoomId {
+        if let Some(replacement) = self.known.get(room_id) {
+            return replacement.clone();
+        }
+        let request = get_state_events_for_key::v3::Request::new(
+            room_id.to_owned(),
+            StateEventType::RoomTombstone,
+            String::new(),
+        );
+        let replacement = match self.c.send(request, None).await {
+            Ok(response) => response
+                .content
+                .deserialize_as::<RoomTombstoneEventContent>()
+                .map_or_else(|_| room_id.to_owned(), |t| t.replacement_room),
+            Err(_) => room_id.to_owned(),
+        };
+        self.known.insert(room_id.to_owned(), replacement.clone());
+        replacement
+    }
+}
+
+/// Hands the spaces among `rooms` the old account administers over to the new account: a
+/// re-created space gets the children of the old one, and children only members of the old
+/// space may join let members of the new one join too. Afterwards, checks that the new account
+/// can manage every room of the space the old one could, and reports what it can't.
+pub async fn hand_over_spaces(
+    from_c: &Client,
+    to_c: &Client,
+    rooms: &[OwnedRoomId],
+    limiter: &Limiter,
+    dryrun: bool,
+) -> anyhow::Result<()> {
+    let old_user = from_c.user_id().unwrap().to_owned();
+    let mut replacements = Replacements::new(from_c);
+
+    for space_id in rooms {
+        let Some(space) = from_c.get_room(space_id) else {
+            continue;
+        };
+        if !space.is_space()
+            || !space
+                .can_user_send_state(&old_user, StateEventType::SpaceChild)
+                .await?
+        {
+            continue;
+        }
+        let children = children(&space).await?;
+        let new_space_id = replacements.get(space_id).await;
+        info!(
+            "Handing over the space {space_id} and its {} rooms",
+            children.len()
+        );
+
+        if new_space_id != *space_id {
+            link_children(
+                to_c,
+                &new_space_id,
+                &children,
+                &mut replacements,
+                limiter,
+                dryrun,
+            )
+            .await?;
+        }
+        for child_id in children.keys() {
+            let new_child_id = replacements.get(child_id).await;
+            if let Err(e) = update_child(
+                from_c,
+                to_c,
+                child_id,
+                &new_child_id,
+                &mut replacements,
+                limiter,
+                dryrun,
+            )
+            .await
+            {
+                warn!("Couldn't point {new_child_id} to the new space {new_space_id}: {e}");
+            }
+        }
+
+        if !dryrun {
+            let hierarchy = std::iter::once(space_id.clone()).chain(children.into_keys());
+            for room_id in hierarchy {
+                verify(from_c, to_c, &room_id, space_id, &mut replacements).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The rooms `space` lists as its children, with how it lists them
+async fn children(space: &Room) -> anyhow::Result<HashMap<OwnedRoomId, SpaceChildEventContent>> {
+    let mut children = HashMap::new();
+    for event in space
+        .get_state_events_static::<SpaceChildEventContent>()
+        .await?
+    {
+        if let SyncOrStrippedState::Sync(SyncStateEvent::Original(child)) = event.deserialize()? {
+            // children without servers to join through are removed ones
+            if !child.content.via.is_empty() {
+                children.insert(child.state_key, child.content);
+            }
+        }
+    }
+    Ok(children)
+}
+
+/// Lists `children` of the old space in the space re-created as `new_space_id`
+async fn link_children(
+    to_c: &Client,
+    new_space_id: &RoomId,
+    children: &HashMap<OwnedRoomId, SpaceChildEventContent>,
+    replacements: &mut Replacements,
+    limiter: &Limiter,
+    dryrun: bool,
+) -> anyhow::Result<()> {
+    let Some(new_space) = to_c.get_room(new_space_id) else {
+        warn!("{new_space_id} isn't known to the new account yet. Not adding its rooms.");
+        return Ok(());
+    };
+    let new_server = to_c.user_id().unwrap().server_name().to_owned();
+    for (child_id, content) in children {
+        let new_child_id = replacements.get(child_id).await;
+        if new_space
+            .get_state_event_static_for_key::<SpaceChildEventContent, _>(&new_child_id)
+            .await?
+            .is_some()
+        {
+            continue;
+        }
+        let mut content = content.clone();
+        if new_child_id != *child_id {
+            content.via = vec![new_server.clone()];
+        }
+        info!("Adding {new_child_id} to the space {new_space_id}");
+        if dryrun {
+            continue;
+        }
+        if let Err(e) = limiter
+            .run(to_c, || {
+                new_space.send_state_event_for_key(&new_child_id, content.clone())
+            })
+            .await
+        {
+            warn!("Couldn't add {new_child_id} to the space {new_space_id}: {e}");
+        }
+    }
+    Ok(())
+}
+
+/// Points the room `new_child_id`, which is `child_id` or its replacement, at the rooms
+/// replacing its parent spaces and the rooms its join rule refers to
+async fn update_child(
+    from_c: &Client,
+    to_c: &Client,
+    child_id: &RoomId,
+    new_child_id: &RoomId,
+    replacements: &mut Replacements,
+    limiter: &Limiter,
+    dryrun: bool,
+) -> anyhow::Result<()> {
+    let recreated = new_child_id != child_id;
+    let joined = to_c
+        .get_room(new_child_id)
+        .filter(|r| r.state() == RoomState::Joined);
+    let Some(room) = joined.or_else(|| from_c.get_room(child_id)) else {
+        return Ok(());
+    };
+
+    let mut join_rule = room.join_rule();
+    if let JoinRule::Restricted(restricted) | JoinRule::KnockRestricted(restricted) = &mut join_rule
+    {
+        let mut allow = Vec::new();
+        for rule in &restricted.allow {
+            let AllowRule::RoomMembership(membership) = rule else {
+                allow.push(rule.clone());
+                continue;
+            };
+            let replacement = replacements.get(&membership.room_id).await;
+            // members of a re-created child live in the new rooms, the others may be in either
+            if !recreated {
+                allow.push(rule.clone());
+            }
+            let new_rule = AllowRule::room_membership(replacement);
+            if !allow.contains(&new_rule) {
+                allow.push(new_rule);
+            }
+        }
+        if allow != restricted.allow {
+            info!("Allowing members of the new spaces to join {new_child_id}");
+            restricted.allow = allow;
+            if !dryrun {
+                let room = sender(
+                    from_c,
+                    to_c,
+                    child_id,
+                    new_child_id,
+                    StateEventType::RoomJoinRules,
+                )
+                .await?
+                .ok_or_else(|| anyhow::anyhow!("neither account may change its join rule"))?;
+                let content = RoomJoinRulesEventContent::new(join_rule.clone());
+                limiter
+                    .run(&room.client(), || room.send_state_event(content.clone()))
+                    .await?;
+            }
+        }
+    }
+
+    for event in room
+        .get_state_events_static::<SpaceParentEventContent>()
+        .await?
+    {
+        let SyncOrStrippedState::Sync(SyncStateEvent::Original(parent)) = event.deserialize()?
+        else {
+            continue;
+        };
+        let new_parent_id = replacements.get(&parent.state_key).await;
+        if new_parent_id == parent.state_key
+            || room
+                .get_state_event_static_for_key::<SpaceParentEventContent, _>(&new_parent_id)
+                .await?
+                .is_some()
+        {
+            continue;
+        }
+        info!("Setting {new_parent_id} as parent of {new_child_id}");
+        if dryrun {
+            continue;
+        }
+        let new_server = to_c.user_id().unwrap().server_name().to_owned();
+        let mut content = SpaceParentEventContent::new(vec![new_server]);
+        content.canonical = parent.content.canonical;
+        let room = sender(
+            from_c,
+            to_c,
+            child_id,
+            new_child_id,
+            StateEventType::SpaceParent,
+        )
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("neither account may change its parent spaces"))?;
+        limiter
+            .run(&room.client(), || {
+                room.send_state_event_for_key(&new_parent_id, content.clone())
+            })
+            .await?;
+    }
+    Ok(())
+}
+
+/// The room through which the new account, or else the old one, may send `event_type` to
+/// `new_child_id`, the room `child_id` or its replacement
+async fn sender(
+    from_c: &Client,
+    to_c: &Client,
+    child_id: &RoomId,
+    new_child_id: &RoomId,
+    event_type: StateEventType,
+) -> anyhow::Result<Option<Room>> {
+    if let Some(room) = to_c.get_room(new_child_id) {
+        if room.state() == RoomState::Joined
+            && room
+                .can_user_send_state(to_c.user_id().unwrap(), event_type.clone())
+                .await?
+        {
+            return Ok(Some(room));
+        }
+    }
+    if new_child_id != child_id {
+        return Ok(None);
+    }
+    let Some(room) = from_c.get_room(child_id) else {
+        return Ok(None);
+    };
+    let can_send = room.state() == RoomState::Joined
+        && room
+            .can_user_send_state(from_c.user_id().unwrap(), event_type)
+            .await?;
+    Ok(can_send.then_some(room))
+}
+
+/// Checks that the new account can do everything in `room_id`, or the room replacing it, that
+/// the old one can. Records what it can't as something to do by hand.
+async fn verify(
+    from_c: &Client,
+    to_c: &Client,
+    room_id: &RoomId,
+    space_id: &RoomId,
+    replacements: &mut Replacements,
+) -> anyhow::Result<()> {
+    let Some(old_room) = from_c.get_room(room_id) else {
+        return Ok(());
+    };
+    if old_room.state() != RoomState::Joined {
+        return Ok(());
+    }
+    let Some(old_member) = old_room
+        .get_member_no_sync(from_c.user_id().unwrap())
+        .await?
+    else {
+        return Ok(());
+    };
+
+    let new_room_id = replacements.get(room_id).await;
+    let new_user = to_c.user_id().unwrap();
+    let new_room = to_c
+        .get_room(&new_room_id)
+        .filter(|r| r.state() == RoomState::Joined);
+    let new_member = match &new_room {
+        Some(room) => room.get_member_no_sync(new_user).await?,
+        None => None,
+    };
+    let (Some(new_room), Some(new_member)) = (new_room, new_member) else {
+        warn!("{new_user} isn't member of {new_room_id} in the space {space_id}");
+        report::manual(
+            format!("{new_room_id} in the space {space_id}"),
+            "The new account isn't member of it yet. Accept its invite or join it",
+        );
+        return Ok(());
+    };
+
+    let gaps = audit::ability_gaps(
+        &new_room,
+        old_member.power_level(),
+        new_member.power_level(),
+    )
+    .await?;
+    if !gaps.is_empty() {
+        warn!(
+            "{new_user} can't {} in {new_room_id} of the space {space_id}",
+            gaps.join(", ")
+        );
+        report::manual(
+            format!("{new_room_id} in the space {space_id}"),
+            format!(
+                "The new account can't {}. Have an admin of the room raise its power level",
+                gaps.join(", ")
+            ),
+        );
+    }
+    Ok(())
+}
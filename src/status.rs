@@ -0,0 +1,71 @@
+use std::path::Path;
+
+use matrix_sdk::ruma::OwnedUserId;
+use serde::Serialize;
+
+use crate::{
+    checkpoint::{Checkpoint, Step},
+    journal,
+};
+
+/// How far the last migration got, read from its state file and journal
+#[derive(Serialize)]
+pub struct Status {
+    pub from: OwnedUserId,
+    pub to: OwnedUserId,
+    pub invited: usize,
+    pub accepted: usize,
+    pub power_levels: usize,
+    pub left: usize,
+    /// Actions recorded in the journal, which `rollback` would reverse
+    pub journaled: usize,
+    pub last_action_at: Option<String>,
+}
+
+/// Reads the progress of the last migration from `state_file` and `journal_path`, without
+/// logging in
+pub fn status(state_file: &Path, journal_path: &Path) -> anyhow::Result<Status> {
+    let checkpoint = Checkpoint::read(state_file)?;
+    let Some((from, to)) = checkpoint.accounts() else {
+        anyhow::bail!("The state file {} has no migration", state_file.display());
+    };
+    let entries = if journal_path.exists() {
+        journal::read(journal_path, &from, &to)?
+    } else {
+        Vec::new()
+    };
+
+    Ok(Status {
+        invited: checkpoint.count(Step::Invited),
+        accepted: checkpoint.count(Step::Accepted),
+        power_levels: checkpoint.count(Step::PowerLevel),
+        left: checkpoint.count(Step::Left),
+        journaled: entries.len(),
+        last_action_at: entries.last().map(|e| e.at.clone()),
+        from,
+        to,
+    })
+}
+
+impl Status {
+    /// The status as a human readable summary
+    pub fn summary(&self) -> String {
+        let rows = [
+            ("Migrating:", format!("{} to {}", self.from, self.to)),
+            ("Invited:", self.invited.to_string()),
+            ("Accepted:", self.accepted.to_string()),
+            ("Power levels adjusted:", self.power_levels.to_string()),
+            ("Left:", self.left.to_string()),
+            ("Journaled actions:", self.journaled.to_string()),
+            (
+                "Last action at:",
+                self.last_action_at
+                    .clone()
+                    .unwrap_or_else(|| "-".to_owned()),
+            ),
+        ];
+        rows.iter()
+            .map(|(label, value)| format!("{label:<24}{value}\n"))
+            .collect()
+    }
+}
@@ -0,0 +1,300 @@
+use std::time::Duration;
+
+use futures::{pin_mut, StreamExt};
+use matrix_sdk::{
+    config::SyncSettings,
+    ruma::{
+        api::client::{
+            error::ErrorKind,
+            filter::{FilterDefinition, LazyLoadOptions, RoomEventFilter},
+            state::get_state_events_for_key,
+            sync::sync_events::{self, v4::AccountDataConfig},
+        },
+        events::{
+            room::{
+                member::{MembershipState, RoomMemberEventContent},
+                power_levels::RoomPowerLevelsEventContent,
+            },
+            StateEventType,
+        },
+        uint, OwnedRoomId, UserId,
+    },
+    sliding_sync::{SlidingSync, SlidingSyncList, SlidingSyncListLoadingState, SlidingSyncMode},
+    Client, Room,
+};
+use tracing::info;
+
+use crate::limit::RetryPolicy;
+
+/// Name of the sliding sync list covering all rooms
+const ALL_ROOMS: &str = "all_rooms";
+
+/// Key of the last sync token in the store
+const SYNC_TOKEN_KEY: &[u8] = b"matrix-migrate-sync-token";
+
+/// Keeps the rooms of a client up to date, either with the regular `/sync` or with sliding
+/// sync, which only fetches the state the migration looks at and is much faster on large
+/// accounts.
+pub struct Syncer {
+    c: Client,
+    timeout: Duration,
+    retry: RetryPolicy,
+    token: Option<String>,
+    /// Whether the state is kept in a persistent store, so syncing can continue where the last
+    /// run stopped
+    cached: bool,
+    sliding: Option<SlidingSync>,
+    loaded: bool,
+    /// The only rooms synced, if not all of them
+    rooms: Option<Vec<OwnedRoomId>>,
+}
+
+impl Syncer {
+    pub async fn new(
+        c: &Client,
+        sliding: bool,
+        cached: bool,
+        timeout: Duration,
+        retry: RetryPolicy,
+    ) -> anyhow::Result<Self> {
+        let sliding = if sliding {
+            let mut account_data = AccountDataConfig::default();
+            account_data.enabled = Some(true);
+
+            let list = SlidingSyncList::builder(ALL_ROOMS)
+                .sync_mode(SlidingSyncMode::new_growing(100))
+                .timeline_limit(1)
+                .required_state(
+                    [
+                        StateEventType::RoomCreate,
+                        StateEventType::RoomName,
+                        StateEventType::RoomAvatar,
+                        StateEventType::RoomCanonicalAlias,
+                        StateEventType::RoomJoinRules,
+                        StateEventType::RoomEncryption,
+                        StateEventType::RoomPowerLevels,
+                        StateEventType::RoomServerAcl,
+                        StateEventType::RoomTopic,
+                    ]
+                    .into_iter()
+                    .map(|t| (t, String::new()))
+                    .chain([
+                        (StateEventType::RoomMember, "$ME".to_owned()),
+                        // the space steps need every child and parent, keyed by room ID
+                        (StateEventType::SpaceChild, "*".to_owned()),
+                        (StateEventType::SpaceParent, "*".to_owned()),
+                    ])
+                    .collect(),
+                );
+
+            let builder = c.sliding_sync("matrix-migrate")?;
+            let builder = if cached {
+                builder.add_cached_list(list).await?
+            } else {
+                builder.add_list(list)
+            };
+            Some(
+                builder
+                    .with_account_data_extension(account_data)
+                    .poll_timeout(timeout)
+                    .build()
+                    .await?,
+            )
+        } else {
+            None
+        };
+
+        let token = if cached {
+            c.store()
+                .get_custom_value(SYNC_TOKEN_KEY)
+                .await?
+                .map(String::from_utf8)
+                .transpose()?
+        } else {
+            None
+        };
+        if token.is_some() {
+            info!("Continuing the sync of the previous run");
+        }
+
+        Ok(Self {
+            c: c.clone(),
+            timeout,
+            retry,
+            token,
+            cached,
+            sliding,
+            loaded: false,
+            rooms: None,
+        })
+    }
+
+    /// Only syncs `rooms`, e.g. the rooms the filters may select. Sliding sync is left out, as
+    /// its room list covers all rooms.
+    pub fn with_rooms(mut self, rooms: Vec<OwnedRoomId>) -> Self {
+        self.sliding = None;
+        self.rooms = Some(rooms);
+        self
+    }
+
+    /// Waits for the next updates. The first call returns once all rooms are loaded.
+    pub async fn next(&mut self) -> anyhow::Result<()> {
+        if self.sliding.is_none() {
+            return self.sync_once().await;
+        }
+
+        let loaded = self.loaded;
+        loop {
+            self.sliding_round().await?;
+            if loaded || self.loaded {
+                return Ok(());
+            }
+        }
+    }
+
+    /// While the first sync is still loading rooms, waits for the next batch of them and
+    /// returns the rooms it brought in. Returns `None` once all rooms are loaded. The regular
+    /// `/sync` loads all rooms in a single batch.
+    pub async fn next_batch(&mut self) -> anyhow::Result<Option<Vec<OwnedRoomId>>> {
+        if self.loaded {
+            return Ok(None);
+        }
+        if self.sliding.is_none() {
+            self.sync_once().await?;
+            self.loaded = true;
+            return Ok(Some(
+                self.c
+                    .joined_rooms()
+                    .iter()
+                    .map(|r| r.room_id().to_owned())
+                    .collect(),
+            ));
+        }
+
+        self.sliding_round().await.map(Some)
+    }
+
+    async fn sync_once(&mut self) -> anyhow::Result<()> {
+        let mut filter = lazy_filter();
+        filter.room.rooms = self.rooms.clone();
+        let mut settings = SyncSettings::default()
+            .timeout(self.timeout)
+            .filter(sync_events::v3::Filter::FilterDefinition(filter));
+        if let Some(token) = &self.token {
+            settings = settings.token(token);
+        }
+        let response = self
+            .retry
+            .run(|| self.c.sync_once(settings.clone()))
+            .await?;
+        if self.cached {
+            self.c
+                .store()
+                .set_custom_value(SYNC_TOKEN_KEY, response.next_batch.clone().into_bytes())
+                .await?;
+        }
+        self.token = Some(response.next_batch);
+        Ok(())
+    }
+
+    /// Runs a single round of sliding sync, returning the rooms it updated
+    async fn sliding_round(&mut self) -> anyhow::Result<Vec<OwnedRoomId>> {
+        let sliding = self
+            .sliding
+            .as_ref()
+            .expect("only called with sliding sync");
+        let stream = sliding.sync();
+        pin_mut!(stream);
+        let summary = stream
+            .next()
+            .await
+            .ok_or_else(|| anyhow::anyhow!("Sliding sync stopped"))??;
+        if self.loaded {
+            return Ok(summary.rooms);
+        }
+
+        let state = sliding
+            .on_list(ALL_ROOMS, |list| {
+                let state = list.state();
+                let rooms = list.maximum_number_of_rooms();
+                async move { (state, rooms) }
+            })
+            .await;
+        if let Some((SlidingSyncListLoadingState::FullyLoaded, rooms)) = state {
+            info!("Loaded {} rooms through sliding sync", rooms.unwrap_or(0));
+            self.loaded = true;
+        }
+        Ok(summary.rooms)
+    }
+}
+
+/// Lazy-loads members and drops the timeline apart from the latest event, so large rooms
+/// don't make the initial `/sync` download tens of thousands of events.
+fn lazy_filter() -> FilterDefinition {
+    let lazy_load = LazyLoadOptions::Enabled {
+        include_redundant_members: false,
+    };
+
+    let mut filter = FilterDefinition::default();
+    filter.room.state.lazy_load_options = lazy_load;
+    filter.room.timeline.lazy_load_options = lazy_load;
+    filter.room.timeline.limit = Some(uint!(1));
+    filter.room.ephemeral = RoomEventFilter::ignore_all();
+    filter
+}
+
+/// What the migration looks at of a member of a room
+pub struct Member {
+    membership: MembershipState,
+    power_level: i64,
+}
+
+impl Member {
+    pub fn membership(&self) -> &MembershipState {
+        &self.membership
+    }
+
+    pub fn power_level(&self) -> i64 {
+        self.power_level
+    }
+}
+
+/// The member `user_id` of `room`. The lazy-loading sync leaves out most members, so unlike
+/// [`Room::get_member`], which then fetches all members of the room, only the member event of
+/// `user_id` is requested if it isn't known yet.
+pub async fn member(room: &Room, user_id: &UserId) -> anyhow::Result<Option<Member>> {
+    if let Some(member) = room.get_member_no_sync(user_id).await? {
+        return Ok(Some(Member {
+            membership: member.membership().clone(),
+            power_level: member.power_level(),
+        }));
+    }
+
+    let request = get_state_events_for_key::v3::Request::new(
+        room.room_id().to_owned(),
+        StateEventType::RoomMember,
+        user_id.to_string(),
+    );
+    let content = match room.client().send(request, None).await {
+        Ok(response) => response
+            .content
+            .deserialize_as::<RoomMemberEventContent>()?,
+        Err(e) if e.client_api_error_kind() == Some(&ErrorKind::NotFound) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let power_level = match room
+        .get_state_event_static::<RoomPowerLevelsEventContent>()
+        .await?
+    {
+        Some(power_levels) => power_levels
+            .deserialize()?
+            .power_levels()
+            .for_user(user_id)
+            .into(),
+        None => 0,
+    };
+    Ok(Some(Member {
+        membership: content.membership,
+        power_level,
+    }))
+}
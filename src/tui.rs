@@ -0,0 +1,266 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use matrix_sdk::ruma::OwnedUserId;
+use ratatui::{
+    backend::CrosstermBackend,
+    crossterm::{
+        event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
+        execute,
+        terminal::{self, EnterAlternateScreen, LeaveAlternateScreen},
+    },
+    layout::{Constraint, Layout},
+    style::{Color, Modifier, Style},
+    text::{Line, Span},
+    widgets::{Block, Borders, Gauge, List, ListItem, Paragraph},
+    Frame, Terminal,
+};
+
+use crate::{
+    context::{self, Context},
+    limit, metrics, progress,
+};
+
+/// The dashboard shown during a run
+pub struct Dashboard {
+    /// Whether the dashboard is shown
+    running: Arc<AtomicBool>,
+    /// Whether the dashboard gave the terminal back for a moment, e.g. for a confirmation
+    suspended: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
+/// How often the dashboard is redrawn, and how long a key press takes to be noticed
+const REFRESH: Duration = Duration::from_millis(250);
+
+/// How many log lines are shown below the rooms
+const LOG_LINES: u16 = 8;
+
+/// Takes over the terminal with a dashboard of the run of `context`, migrating `from` to `to`,
+/// showing the progress of the phases, the state of every room, rate limits and the latest log
+/// lines. `p` pauses new requests, `q` aborts the run.
+pub fn start(from: OwnedUserId, to: OwnedUserId, context: &Arc<Context>) -> anyhow::Result<()> {
+    enter()?;
+    progress::hide();
+    progress::capture_logs(true);
+    let running = Arc::new(AtomicBool::new(true));
+    let suspended = Arc::new(AtomicBool::new(false));
+    let thread = {
+        let (running, suspended, context) = (running.clone(), suspended.clone(), context.clone());
+        std::thread::spawn(move || {
+            if let Err(e) = run(&from, &to, &running, &suspended, &context) {
+                leave();
+                progress::capture_logs(false);
+                eprintln!("The dashboard failed: {e}");
+            }
+        })
+    };
+    *context.dashboard.lock().unwrap() = Some(Dashboard {
+        running,
+        suspended,
+        thread,
+    });
+    Ok(())
+}
+
+/// Gives the terminal back and writes out the log lines kept meanwhile
+pub fn stop(context: &Context) {
+    let Some(dashboard) = context.dashboard.lock().unwrap().take() else {
+        return;
+    };
+    dashboard.running.store(false, Ordering::Relaxed);
+    let _ = dashboard.thread.join();
+    leave();
+    progress::capture_logs(false);
+}
+
+/// Gives the terminal back while `f` runs, e.g. to ask for a confirmation
+pub fn suspend<T>(f: impl FnOnce() -> T) -> T {
+    let suspended = context::with(|c| {
+        let dashboard = c.dashboard.lock().unwrap();
+        dashboard.as_ref().map(|d| d.suspended.clone())
+    });
+    let Some(suspended) = suspended.flatten() else {
+        return f();
+    };
+    suspended.store(true, Ordering::Relaxed);
+    // let the drawing thread notice before taking the terminal from it
+    std::thread::sleep(REFRESH * 2);
+    leave();
+    let result = f();
+    let _ = enter();
+    suspended.store(false, Ordering::Relaxed);
+    result
+}
+
+fn enter() -> std::io::Result<()> {
+    terminal::enable_raw_mode()?;
+    execute!(std::io::stderr(), EnterAlternateScreen)
+}
+
+fn leave() {
+    let _ = terminal::disable_raw_mode();
+    let _ = execute!(std::io::stderr(), LeaveAlternateScreen);
+}
+
+fn run(
+    from: &OwnedUserId,
+    to: &OwnedUserId,
+    running: &AtomicBool,
+    suspended: &AtomicBool,
+    context: &Context,
+) -> std::io::Result<()> {
+    let mut terminal = Terminal::new(CrosstermBackend::new(std::io::stderr()))?;
+    let mut scroll = 0;
+    let mut was_suspended = false;
+    while running.load(Ordering::Relaxed) {
+        if suspended.load(Ordering::Relaxed) {
+            was_suspended = true;
+            std::thread::sleep(REFRESH);
+            continue;
+        }
+        if was_suspended {
+            terminal.clear()?;
+            was_suspended = false;
+        }
+
+        terminal.draw(|frame| draw(frame, from, to, scroll, context))?;
+        if !event::poll(REFRESH)? {
+            continue;
+        }
+        let Event::Key(key) = event::read()? else {
+            continue;
+        };
+        if key.kind != KeyEventKind::Press {
+            continue;
+        }
+        match key.code {
+            KeyCode::Char('p') | KeyCode::Char(' ') => limit::pause(!limit::is_paused()),
+            KeyCode::Char('q') => context.abort(),
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => context.abort(),
+            KeyCode::Down | KeyCode::Char('j') => scroll += 1,
+            KeyCode::Up | KeyCode::Char('k') => scroll = scroll.saturating_sub(1),
+            KeyCode::PageDown => scroll += 10,
+            KeyCode::PageUp => scroll = scroll.saturating_sub(10),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn draw(
+    frame: &mut Frame<'_>,
+    from: &OwnedUserId,
+    to: &OwnedUserId,
+    scroll: usize,
+    context: &Context,
+) {
+    let phases = progress::phases();
+    let [header, gauges, rooms, logs] = Layout::vertical([
+        Constraint::Length(2),
+        Constraint::Length(phases.len() as u16),
+        Constraint::Min(3),
+        Constraint::Length(LOG_LINES + 2),
+    ])
+    .areas(frame.area());
+
+    let status = if limit::is_paused() {
+        Span::styled("PAUSED", Style::new().fg(Color::Yellow))
+    } else if let Some(wait) = metrics::rate_limited_for() {
+        let wait = Duration::from_secs(wait.as_secs() + 1);
+        Span::styled(
+            format!("rate limited for {}", humantime::format_duration(wait)),
+            Style::new().fg(Color::Yellow),
+        )
+    } else {
+        Span::styled("running", Style::new().fg(Color::Green))
+    };
+    let help = format!(
+        "  rate limits: {}  retries: {}  [p] pause  [q] abort  [↑/↓] scroll",
+        metrics::RATE_LIMITS.get(),
+        metrics::RETRIES.get()
+    );
+    frame.render_widget(
+        Paragraph::new(vec![
+            Line::from(vec![Span::styled(
+                format!("Migrating {from} to {to}"),
+                Style::new().add_modifier(Modifier::BOLD),
+            )]),
+            Line::from(vec![status, Span::raw(help)]),
+        ]),
+        header,
+    );
+
+    let rows = Layout::vertical(vec![Constraint::Length(1); phases.len()]).split(gauges);
+    for (bar, area) in phases.iter().zip(rows.iter()) {
+        let len = bar.length().unwrap_or(0);
+        let ratio = if len == 0 {
+            1.0
+        } else {
+            bar.position() as f64 / len as f64
+        };
+        let label = format!(
+            "{:>12} {}/{} {}",
+            bar.prefix(),
+            bar.position(),
+            len,
+            bar.message()
+        );
+        frame.render_widget(
+            Gauge::default()
+                .gauge_style(Style::new().fg(Color::Blue))
+                .ratio(ratio.min(1.0))
+                .label(label),
+            *area,
+        );
+    }
+
+    let snapshot = context.report.snapshot();
+    let visible = rooms.height.saturating_sub(2) as usize;
+    let scroll = scroll.min(snapshot.len().saturating_sub(visible));
+    let items = snapshot
+        .iter()
+        .skip(scroll)
+        .take(visible)
+        .map(|room| {
+            let state = room.state();
+            let color = if !room.errors.is_empty() {
+                Color::Red
+            } else if room.skipped.is_some() {
+                Color::Yellow
+            } else if room.actions.is_empty() {
+                Color::Gray
+            } else {
+                Color::Green
+            };
+            let name = room.name.clone().unwrap_or_default();
+            ListItem::new(Line::from(vec![
+                Span::styled(format!("{state:<24} "), Style::new().fg(color)),
+                Span::raw(format!("{} {name}", room.room_id)),
+            ]))
+        })
+        .collect::<Vec<_>>();
+    frame.render_widget(
+        List::new(items).block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title(format!(" Rooms ({}) ", snapshot.len())),
+        ),
+        rooms,
+    );
+
+    let lines = progress::last_log_lines(LOG_LINES as usize)
+        .into_iter()
+        .map(Line::from)
+        .collect::<Vec<_>>();
+    frame.render_widget(
+        Paragraph::new(lines).block(Block::default().borders(Borders::ALL).title(" Log ")),
+        logs,
+    );
+}
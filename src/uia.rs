@@ -0,0 +1,169 @@
+use std::io::IsTerminal;
+
+use dialoguer::Input;
+use matrix_sdk::{
+    reqwest::{self, StatusCode, Url},
+    ruma::{
+        api::client::uiaa::{
+            AuthData, AuthType, Dummy, EmailIdentity, FallbackAcknowledgement, Password,
+            RegistrationToken, ThirdpartyIdCredentials, UiaaInfo, UserIdentifier,
+        },
+        ClientSecret, OwnedSessionId, UserId,
+    },
+};
+use serde::Deserialize;
+use serde_json::json;
+use tracing::info;
+
+use crate::http::endpoint;
+
+/// Answers to stages besides the password, for registering an account
+#[derive(Default)]
+pub struct Answers<'a> {
+    pub registration_token: Option<&'a str>,
+    /// Address to verify for the email stage
+    pub email: Option<&'a str>,
+}
+
+#[derive(Deserialize)]
+struct EmailSession {
+    sid: OwnedSessionId,
+}
+
+/// Sends the request built by `request`, completing the user-interactive authentication the
+/// homeserver asks for. Password, registration token, email and dummy stages are answered
+/// directly, every other stage (SSO, captchas, terms, ...) through the homeserver's fallback
+/// page. Stages needing input are only completed on a terminal, apart from the password and the
+/// registration token if they're given. `action` describes the request in messages.
+///
+/// Returns the first response that isn't a UIA challenge.
+pub async fn send(
+    http: &reqwest::Client,
+    homeserver: &Url,
+    user_id: Option<&UserId>,
+    password: Option<&str>,
+    answers: &Answers<'_>,
+    action: &str,
+    request: impl Fn(Option<&AuthData>) -> reqwest::RequestBuilder,
+) -> anyhow::Result<reqwest::Response> {
+    let interactive = std::io::stdin().is_terminal();
+
+    let mut auth: Option<AuthData> = None;
+    loop {
+        let response = request(auth.as_ref()).send().await?;
+        if response.status() != StatusCode::UNAUTHORIZED {
+            return Ok(response);
+        }
+
+        let uiaa: UiaaInfo = response.json().await?;
+        if let Some(e) = &uiaa.auth_error {
+            anyhow::bail!("Authentication for {action} failed: {}", e.message);
+        }
+        if auth.is_some() && uiaa.completed.is_empty() {
+            anyhow::bail!("Authentication for {action} wasn't accepted");
+        }
+
+        let supports = |stage: &AuthType| match stage {
+            AuthType::Password => user_id.is_some() && (password.is_some() || interactive),
+            AuthType::RegistrationToken => answers.registration_token.is_some() || interactive,
+            AuthType::EmailIdentity => answers.email.is_some() && interactive,
+            AuthType::Dummy | AuthType::Sso => true,
+            _ => interactive,
+        };
+        let Some(flow) = uiaa.flows.iter().find(|f| f.stages.iter().all(supports)) else {
+            anyhow::bail!("No supported authentication flow for {action}");
+        };
+        let Some(stage) = flow.stages.iter().find(|s| !uiaa.completed.contains(s)) else {
+            anyhow::bail!("Server didn't accept the completed authentication for {action}");
+        };
+
+        info!("Completing {stage} for {action}");
+        let session = uiaa.session.clone();
+        auth = Some(match stage {
+            AuthType::Password => {
+                let user_id = user_id.unwrap();
+                let password = match password {
+                    Some(password) => password.to_owned(),
+                    None => dialoguer::Password::new()
+                        .with_prompt(format!("Password of {user_id}"))
+                        .interact()?,
+                };
+                let mut password = Password::new(
+                    UserIdentifier::UserIdOrLocalpart(user_id.to_string()),
+                    password,
+                );
+                password.session = session;
+                AuthData::Password(password)
+            }
+            AuthType::RegistrationToken => {
+                let token = match answers.registration_token {
+                    Some(token) => token.to_owned(),
+                    None => Input::new()
+                        .with_prompt(format!("Registration token for {action}"))
+                        .interact_text()?,
+                };
+                let mut token = RegistrationToken::new(token);
+                token.session = session;
+                AuthData::RegistrationToken(token)
+            }
+            AuthType::EmailIdentity => {
+                let email = answers.email.unwrap();
+                let client_secret = ClientSecret::new();
+                let email_session = http
+                    .post(endpoint(
+                        homeserver,
+                        &[
+                            "_matrix",
+                            "client",
+                            "v3",
+                            "register",
+                            "email",
+                            "requestToken",
+                        ],
+                    )?)
+                    .json(&json!({
+                        "client_secret": client_secret,
+                        "email": email,
+                        "send_attempt": 1,
+                    }))
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json::<EmailSession>()
+                    .await?;
+                println!("Open the link in the email sent to {email}, then press enter");
+                std::io::stdin().read_line(&mut String::new())?;
+                // the homeserver sent the email itself, no identity server is involved
+                let credentials = ThirdpartyIdCredentials::new(
+                    email_session.sid,
+                    client_secret.into(),
+                    String::new(),
+                    String::new(),
+                );
+                // the stage has no constructor, build it from its JSON form
+                let identity: EmailIdentity = serde_json::from_value(json!({
+                    "type": "m.login.email.identity",
+                    "threepid_creds": credentials,
+                    "session": session,
+                }))?;
+                AuthData::EmailIdentity(identity)
+            }
+            AuthType::Dummy => {
+                let mut dummy = Dummy::new();
+                dummy.session = session;
+                AuthData::Dummy(dummy)
+            }
+            _ => {
+                let session = session.unwrap_or_default();
+                println!(
+                    "Confirm {action} in your browser, then press enter: {}",
+                    homeserver.join(&format!(
+                        "/_matrix/client/v3/auth/{stage}/fallback/web?session={session}"
+                    ))?
+                );
+                std::io::stdin().read_line(&mut String::new())?;
+                AuthData::FallbackAcknowledgement(FallbackAcknowledgement::new(session))
+            }
+        });
+    }
+}
@@ -0,0 +1,132 @@
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use tracing::info;
+
+use crate::{context::Context, export::escape, http, limit, metrics, progress, report};
+
+/// How often the page reloads itself, in seconds
+const REFRESH_SECONDS: u32 = 5;
+
+/// The status page: progress of the phases, failures and every room with its state
+fn render(context: &Context) -> String {
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\">\
+        <meta http-equiv=\"refresh\" content=\"{REFRESH_SECONDS}\">\
+        <title>matrix-migrate</title></head>\n<body>\n<h1>Migration</h1>\n"
+    );
+
+    let status = if limit::is_paused() {
+        "paused".to_owned()
+    } else if let Some(wait) = metrics::rate_limited_for() {
+        format!(
+            "rate limited for {}",
+            humantime::format_duration(Duration::from_secs(wait.as_secs() + 1))
+        )
+    } else {
+        "running".to_owned()
+    };
+    html += &format!(
+        "<p>{status}, {} rate limits, {} retries. <a href=\"/report.json\">Download the report</a></p>\n",
+        metrics::RATE_LIMITS.get(),
+        metrics::RETRIES.get()
+    );
+
+    html += "<h2>Progress</h2>\n<table>\n";
+    for bar in progress::phases() {
+        let len = bar.length().unwrap_or(0);
+        html += &format!(
+            "<tr><td>{}</td><td><progress value=\"{}\" max=\"{len}\"></progress></td>\
+            <td>{}/{len}</td><td>{}</td></tr>\n",
+            escape(&bar.prefix()),
+            bar.position(),
+            bar.position(),
+            escape(&bar.message())
+        );
+    }
+    html += "</table>\n";
+
+    let rooms = context.report.snapshot();
+    let failed = rooms.iter().filter(|r| !r.errors.is_empty());
+    html += "<h2>Failures</h2>\n<ul>\n";
+    for room in failed {
+        for error in &room.errors {
+            html += &format!(
+                "<li>{} ({}): {}</li>\n",
+                escape(room.name.as_deref().unwrap_or(room.room_id.as_str())),
+                report::name(&error.reason),
+                escape(&error.message)
+            );
+        }
+    }
+    html += "</ul>\n";
+
+    html += &format!(
+        "<h2>Rooms ({})</h2>\n<table>\n<tr><th>Room</th><th>Name</th><th>State</th></tr>\n",
+        rooms.len()
+    );
+    for room in &rooms {
+        html += &format!(
+            "<tr><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+            escape(room.room_id.as_str()),
+            escape(room.name.as_deref().unwrap_or_default()),
+            escape(&room.state())
+        );
+    }
+    html += "</table>\n</body>\n</html>\n";
+    html
+}
+
+/// The response to a request for `path`. The report is only served once this run wrote it,
+/// not the one of a previous run.
+fn respond(
+    path: &str,
+    report_file: &Path,
+    started: SystemTime,
+    context: &Context,
+) -> http::Response {
+    let written = std::fs::metadata(report_file)
+        .and_then(|m| m.modified())
+        .is_ok_and(|modified| modified >= started);
+    match path {
+        "/" | "/index.html" => (
+            "200 OK",
+            "text/html; charset=utf-8",
+            render(context).into_bytes(),
+        ),
+        "/report.json" => match std::fs::read(report_file) {
+            Ok(report) if written => ("200 OK", "application/json", report),
+            _ => (
+                "404 Not Found",
+                "text/plain",
+                b"The report is written once the migration finished".to_vec(),
+            ),
+        },
+        "/rooms.json" => (
+            "200 OK",
+            "application/json",
+            serde_json::to_vec_pretty(&context.report.snapshot()).unwrap_or_default(),
+        ),
+        _ => ("404 Not Found", "text/plain", b"Not found".to_vec()),
+    }
+}
+
+/// Serves a status page of the run of `context` on `addr` for as long as the process runs, with
+/// the report at `report_file` to download once it's written
+pub async fn serve(
+    addr: SocketAddr,
+    report_file: PathBuf,
+    context: Arc<Context>,
+) -> anyhow::Result<()> {
+    let started = SystemTime::now();
+    http::serve(addr, move |path| {
+        respond(path, &report_file, started, &context)
+    })
+    .await?;
+    info!("Serving the status page on http://{addr}/");
+    Ok(())
+}
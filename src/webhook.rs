@@ -0,0 +1,99 @@
+use std::time::Duration;
+
+use matrix_sdk::{
+    reqwest::{self, Url},
+    ruma::{OwnedUserId, RoomId},
+};
+use serde::Serialize;
+use serde_json::Value;
+use tokio::{sync::mpsc, task::JoinHandle};
+use tracing::warn;
+
+use crate::{
+    context,
+    report::{Counters, Reason},
+};
+
+/// How long to wait for the last events to be posted at the end of a run
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The webhook of a run
+pub struct Webhook {
+    /// Queue of the events still to be posted
+    queue: mpsc::UnboundedSender<Value>,
+    /// The task posting them one after the other
+    poster: JoinHandle<()>,
+}
+
+/// Events posted to the webhook
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    RunStarted {
+        from: Option<OwnedUserId>,
+        to: Option<OwnedUserId>,
+        dry_run: bool,
+    },
+    PhaseCompleted {
+        phase: &'a str,
+        rooms: u64,
+    },
+    RoomFailed {
+        room_id: &'a RoomId,
+        reason: Reason,
+        message: &'a str,
+    },
+    RunFinished {
+        counters: &'a Counters,
+    },
+}
+
+/// Starts posting the events of the current run to `url`
+pub fn init(http: reqwest::Client, url: Url) {
+    let (queue, mut events) = mpsc::unbounded_channel::<Value>();
+    let poster = tokio::spawn(async move {
+        while let Some(event) = events.recv().await {
+            let posted = http
+                .post(url.clone())
+                .json(&event)
+                .send()
+                .await
+                .and_then(|r| r.error_for_status());
+            if let Err(e) = posted {
+                warn!("Posting to the webhook failed: {e}");
+            }
+        }
+    });
+    context::with(|c| *c.webhook.lock().unwrap() = Some(Webhook { queue, poster }));
+}
+
+/// Queues `event` for the webhook, without waiting for it to be posted
+pub fn send(event: Event<'_>) {
+    context::with(|c| {
+        let webhook = c.webhook.lock().unwrap();
+        let Some(webhook) = webhook.as_ref() else {
+            return;
+        };
+        match serde_json::to_value(event) {
+            Ok(event) => {
+                let _ = webhook.queue.send(event);
+            }
+            Err(e) => warn!("Couldn't serialize the webhook event: {e}"),
+        }
+    });
+}
+
+/// Waits until all queued events are posted. Events sent afterwards are dropped.
+pub async fn flush() {
+    let Some(webhook) = context::with(|c| c.webhook.lock().unwrap().take()).flatten() else {
+        return;
+    };
+    // the poster stops once the closed queue is drained
+    drop(webhook.queue);
+    if tokio::time::timeout(FLUSH_TIMEOUT, webhook.poster)
+        .await
+        .is_err()
+    {
+        warn!("Gave up posting the last events to the webhook");
+    }
+}
@@ -0,0 +1,60 @@
+use matrix_sdk::{
+    ruma::{events::GlobalAccountDataEventType, MxcUri},
+    Client,
+};
+use serde_json::Value;
+use tracing::{info, warn};
+
+use crate::media::{self, MediaMigrator};
+
+/// Copies the old account's own widgets, like its sticker picker and integration managers, to
+/// the new one. Images of the widgets hosted on the old homeserver are re-uploaded. Widgets the
+/// new account already has are kept.
+pub async fn migrate_widgets(from_c: &Client, to_c: &Client, dryrun: bool) -> anyhow::Result<()> {
+    let event_type = GlobalAccountDataEventType::from("m.widgets");
+    let Some(widgets) = from_c
+        .account()
+        .fetch_account_data(event_type.clone())
+        .await?
+    else {
+        return Ok(());
+    };
+    let mut widgets = widgets.deserialize_as::<Value>()?;
+    let Some(widget_ids) = widgets
+        .as_object()
+        .map(|w| w.keys().cloned().collect::<Vec<_>>())
+    else {
+        return Ok(());
+    };
+    info!("Copying widgets {}", widget_ids.join(", "));
+    if dryrun {
+        return Ok(());
+    }
+
+    let old_user = from_c.user_id().unwrap().as_str();
+    let new_user = to_c.user_id().unwrap().as_str();
+    let mut media = MediaMigrator::new(from_c, to_c);
+    for (id, widget) in widgets.as_object_mut().into_iter().flatten() {
+        if widget["sender"] == old_user {
+            widget["sender"] = new_user.into();
+        }
+        let mut urls = Vec::new();
+        mxc_urls(widget, &mut urls);
+        for url in urls {
+            if let Err(e) = media::reupload_url(&mut media, url).await {
+                warn!("Couldn't re-upload an image of the widget {id}: {e}");
+            }
+        }
+    }
+    media::add_account_data(to_c, event_type, widgets).await
+}
+
+/// Collects the strings in `value` that are mxc URIs
+fn mxc_urls<'a>(value: &'a mut Value, urls: &mut Vec<&'a mut Value>) {
+    match value {
+        Value::String(s) if <&MxcUri>::from(s.as_str()).is_valid() => urls.push(value),
+        Value::Array(values) => values.iter_mut().for_each(|v| mxc_urls(v, urls)),
+        Value::Object(fields) => fields.values_mut().for_each(|v| mxc_urls(v, urls)),
+        _ => {}
+    }
+}